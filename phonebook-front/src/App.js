@@ -7,6 +7,32 @@ const SERVER_HOST = process.env.REACT_APP_SERVER_HOST ? process.env.REACT_APP_SE
 const base_url = `http://${SERVER_HOST}:${SERVER_PORT}`;
 // TODO: Reject duplicate names from being added, this can be done if the server returns an error
 
+// The form only has a single number field, the server keeps a list of labelled numbers per person
+const toRequest = (entry) => ({
+  name: entry.name,
+  numbers: [{ label: "mobile", number: entry.number, primary: true }],
+});
+// Names come back in parts, see `Name::display_name` on the server
+const displayName = (name) =>
+  [name.prefix, name.given, name.middle, name.family, name.suffix].filter(Boolean).join(" ");
+const formatNumbers = (numbers) =>
+  numbers
+    .map((n) => `${n.number}${n.extension ? ` ext. ${n.extension}` : ""} (${n.label})`)
+    .join(", ");
+const formatAddress = (a) =>
+  [a.street, a.city, a.region, a.postal_code, a.country].filter(Boolean).join(", ");
+// Like `?q=` on the server, except that the name has to start with the search
+const matchesSearch = (entry, search) => {
+  const found = (text) => text.toLowerCase().includes(search);
+  return (
+    displayName(entry.name).toLowerCase().startsWith(search) ||
+    entry.numbers.some((n) => found(n.number)) ||
+    (entry.emails || []).some((e) => found(e.address)) ||
+    (entry.addresses || []).some((a) => found(formatAddress(a))) ||
+    (entry.urls || []).some((w) => found(w.url))
+  );
+};
+
 export default function App() {
   // Hook for search bar
   const [searchName, setSearchName] = useState("");
@@ -14,6 +40,8 @@ export default function App() {
   const [book, setBook] = useState([]);
   // Controlled component for our form element
   const [newEntry, setEntry] = useState({ name: "", number: "" });
+  // Bumped per entry on upload, the photo URL stays the same so the browser would keep showing the old one
+  const [photoVersions, setPhotoVersions] = useState({});
   console.count(`Rendering App component`);
   // Use either useState's lazy init function OR useEffect hook to avoid a infinite loop of setBook and axios network request
   // https://stackoverflow.com/questions/62050966/how-to-fetch-data-without-useeffect-hooks-in-react-function-component
@@ -29,12 +57,12 @@ export default function App() {
         onClick={(_e) => {
           if (
             window.confirm(
-              "Do you really want to delete " + entry.name + " from phonebook?"
+              "Do you really want to delete " + displayName(entry.name) + " from phonebook?"
             )
           ) {
             axios.delete(`${base_url}/book/${entry.id}`).then((response) => {
               if (response.status === 204) {
-                console.log(`${entry.name} deleted from Phonebook`);
+                console.log(`${displayName(entry.name)} deleted from Phonebook`);
                 setBook(book.filter((live) => live.id !== entry.id));
               }
             });
@@ -44,12 +72,48 @@ export default function App() {
         Delete Entry
       </button>
     );
+    // Thumbnails are cut square by the server, entries without a photo simply show none
+    const Photo = () => (
+      <img
+        src={`${base_url}/book/${entry.id}/photo/thumbnail?v=${photoVersions[entry.id] || 0}`}
+        alt=""
+        width="64"
+        height="64"
+        style={{ borderRadius: "50%", verticalAlign: "middle", marginRight: "8px" }}
+        onError={(e) => {
+          e.target.style.display = "none";
+        }}
+      />
+    );
+    // JPEG or PNG up to 5 MiB, anything else comes back as a BAD_REQUEST
+    const PhotoInput = () => (
+      <input
+        type="file"
+        accept="image/jpeg,image/png"
+        onChange={(e) => {
+          const form = new FormData();
+          form.append("photo", e.target.files[0]);
+          axios
+            .post(`${base_url}/book/${entry.id}/photo`, form)
+            .then((_response) =>
+              setPhotoVersions({ ...photoVersions, [entry.id]: (photoVersions[entry.id] || 0) + 1 })
+            )
+            .catch((error) => window.alert(`Photo rejected: ${error.response ? error.response.data : error}`));
+        }}
+      />
+    );
     // (Fixed in previous commit) Warning here: When adding a new name via the react app (not downloaded from server), we don't se the id field
     // as a result those entries added by the react app will have an undefined {entry.id}. To fix this we post to the
     // server in addPhonebookEntry first and then fetch results from the server before rendering
     return (
       <li key={entry.id}>
-        Name : {entry.name}<br/>Number : {entry.number} <DeleteButton />
+        <Photo />
+        Name : {displayName(entry.name)}<br/>Numbers : {formatNumbers(entry.numbers)}
+        {entry.emails && <><br/>Emails : {entry.emails.map((e) => `${e.address} (${e.label})`).join(", ")}</>}
+        {entry.addresses && <><br/>Addresses : {entry.addresses.map((a) => `${formatAddress(a)} (${a.label})`).join("; ")}</>}
+        {entry.urls && <><br/>Websites : {entry.urls.map((w) => <span key={w.url}> <a href={w.url}>{w.url}</a></span>)}</>}
+        {entry.tags && <><br/>Tags : {entry.tags.join(", ")}</>} 
+        {entry.fields && Object.entries(entry.fields).map(([name, value]) => <span key={name}><br/>{name} : {String(value)}</span>)} <DeleteButton /> <PhotoInput />
       </li>
     );
   };
@@ -64,7 +128,7 @@ export default function App() {
     let duplicate_id = -1;
     if (
       book.find((person) => {
-        if (person.name === newEntry.name) {
+        if (displayName(person.name) === newEntry.name) {
           duplicate_id = person.id;
           return true;
         } else {
@@ -78,11 +142,11 @@ export default function App() {
       if (confirm && duplicate_id !== -1) {
         axios
           // This will cause a BAD_REQUEST response from server because we are already checking for name duplicates
-          .put(`${base_url}/book/${duplicate_id}`, newEntry)
+          .put(`${base_url}/book/${duplicate_id}`, toRequest(newEntry))
           .then((response) => console.log(`${duplicate_id} updated to `, newEntry));
       }
     }
-    axios.post(`${base_url}/book`, newEntry).then((response) => {
+    axios.post(`${base_url}/book`, toRequest(newEntry)).then((response) => {
       axios.get(`${base_url}/book`).then((response) => {
         // Set from GET request rather than just concating becz we depend on the server to issue a id
         setBook(response.data.phonebook);
@@ -124,9 +188,7 @@ export default function App() {
         {searchName === ""
           ? book.map((each) => <PhonebookEntry key={each.id} entry={each} />)
           : book
-              .filter((each) =>
-                each.name.toLowerCase().startsWith(searchName.toLowerCase())
-              )
+              .filter((each) => matchesSearch(each, searchName.toLowerCase()))
               .map((each) => <PhonebookEntry key={each.id} entry={each} />)}
       </ul>
       <form onSubmit={addPhonebookEntry}>
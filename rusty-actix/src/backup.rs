@@ -0,0 +1,226 @@
+//! Rotating timestamped backups of the data file.
+//! Before the data file gets overwritten a copy is kept in the backups directory as
+//! `<stem>-<UTC timestamp>.json`, keeping only the newest `keep` copies.
+//! Routine saves back up at most once per `interval`, so a burst of edits can't rotate away the state from before it.
+use crate::{read_json, Err, JsonFile};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Number of backups kept when nothing else is configured
+pub const DEFAULT_BACKUP_COUNT: usize = 10;
+/// Least time between two routine backups when nothing else is configured
+pub const DEFAULT_BACKUP_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Clone)]
+pub struct Backups {
+    dir: PathBuf,
+    keep: usize,
+    interval: Duration,
+}
+
+/// A backup as reported by `Backups::list`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BackupInfo {
+    pub name: String,
+    pub size: u64,
+}
+
+/// The default backups directory for a data file: `files/mock.json` -> `files/backups`
+pub fn backup_dir_for(path: &Path) -> PathBuf {
+    path.with_file_name("backups")
+}
+
+impl Backups {
+    /// Keep up to `keep` backups in `dir`, a `keep` of 0 disables backups altogether
+    pub fn new(dir: impl Into<PathBuf>, keep: usize) -> Self {
+        Self {
+            dir: dir.into(),
+            keep,
+            interval: DEFAULT_BACKUP_INTERVAL,
+        }
+    }
+
+    /// Let `snapshot_if_due` back up at most once per `interval`, 0 backs up every time
+    pub fn every(self, interval: Duration) -> Self {
+        Self { interval, ..self }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Copy the current contents of `path` into the backups directory and drop the oldest backups.
+    /// Returns the path of the new backup, or `None` if there was nothing to back up.
+    pub fn snapshot(&self, path: &Path) -> Result<Option<PathBuf>> {
+        if self.keep == 0 || !path.exists() {
+            return Ok(None);
+        }
+        let stem = path.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+        let backup = self.next_path(&stem)?;
+        {
+            let _lock = crate::lock::shared(path)?;
+            std::fs::copy(path, &backup)
+                .map_err(Err::Io)
+                .with_context(|| format!("Failed to back up `{}` to `{}`", path.display(), backup.display()))?;
+        }
+        log::info!("Backed up `{}` to `{}`", path.display(), backup.display());
+        self.rotate()?;
+        Ok(Some(backup))
+    }
+
+    /// Save `json_file` as a backup named after `stem`, for books that aren't kept in a JSON file of their own.
+    /// Returns the path of the new backup, or `None` if backups are disabled.
+    pub fn save(&self, stem: &str, json_file: &JsonFile) -> Result<Option<PathBuf>> {
+        if self.keep == 0 {
+            return Ok(None);
+        }
+        let backup = self.next_path(stem)?;
+        crate::write_json(&backup, json_file)?;
+        log::info!("Backed up `{stem}` to `{}`", backup.display());
+        self.rotate()?;
+        Ok(Some(backup))
+    }
+
+    /// `snapshot`, unless the newest backup is younger than the interval.
+    /// Used for routine saves, explicit ones such as a restore always call `snapshot`.
+    pub fn snapshot_if_due(&self, path: &Path) -> Result<Option<PathBuf>> {
+        if let Some(newest) = self.list()?.first() {
+            let modified = std::fs::metadata(self.dir.join(&newest.name)).and_then(|m| m.modified());
+            // A clock gone backwards or an unreadable time counts as due
+            let age = modified.ok().and_then(|m| SystemTime::now().duration_since(m).ok());
+            if age.is_some_and(|age| age < self.interval) {
+                log::debug!(
+                    "Skipped backing up `{}`, `{}` is recent enough",
+                    path.display(),
+                    newest.name
+                );
+                return Ok(None);
+            }
+        }
+        self.snapshot(path)
+    }
+
+    /// All backups, newest first
+    pub fn list(&self) -> Result<Vec<BackupInfo>> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => {
+                return Err(Err::Io(err)).with_context(|| format!("Failed to list backups in `{}`", self.dir.display()))
+            }
+        };
+        let mut backups = vec![];
+        for entry in entries {
+            let entry = entry.map_err(Err::Io)?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.ends_with(".json") {
+                continue;
+            }
+            let size = entry.metadata().map_err(Err::Io)?.len();
+            backups.push(BackupInfo { name, size });
+        }
+        // Compare without the extension so that `…Z-1.json` sorts after `…Z.json`
+        backups.sort_unstable_by(|a, b| b.name.trim_end_matches(".json").cmp(a.name.trim_end_matches(".json")));
+        Ok(backups)
+    }
+
+    /// Load the backup called `name`, as reported by `list`
+    pub fn load(&self, name: &str) -> Result<JsonFile> {
+        // Only plain file names, never paths that could point outside the backups directory
+        if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+            return Err(Err::PhonebookEntry("Invalid backup name".into()))
+                .with_context(|| format!("`{name}` is not a valid backup name"));
+        }
+        let path = self.dir.join(name);
+        if !path.is_file() {
+            return Err(Err::PhonebookEntry("Backup does not exist".into()))
+                .with_context(|| format!("No backup called `{name}`"));
+        }
+        read_json(&path)
+    }
+
+    /// A backup path that is not taken yet, creating the backups directory if need be
+    fn next_path(&self, stem: &str) -> Result<PathBuf> {
+        std::fs::create_dir_all(&self.dir)
+            .map_err(Err::Io)
+            .with_context(|| format!("Failed to create backups directory `{}`", self.dir.display()))?;
+        let timestamp = chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ");
+        // Timestamps sort chronologically, the counter only kicks in for saves within the same millisecond
+        let mut backup = self.dir.join(format!("{stem}-{timestamp}.json"));
+        let mut n = 1;
+        while backup.exists() {
+            backup = self.dir.join(format!("{stem}-{timestamp}-{n}.json"));
+            n += 1;
+        }
+        Ok(backup)
+    }
+
+    fn rotate(&self) -> Result<()> {
+        for stale in self.list()?.into_iter().skip(self.keep) {
+            let path = self.dir.join(&stale.name);
+            std::fs::remove_file(&path)
+                .map_err(Err::Io)
+                .with_context(|| format!("Failed to remove old backup `{}`", path.display()))?;
+            // Left behind if the backup was ever loaded
+            let _ = std::fs::remove_file(crate::lock::lock_path_for(&path));
+            log::debug!("Removed old backup `{}`", path.display());
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_backups_rotate() -> Result<()> {
+    use crate::Person;
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    let path = dir.join("book.json");
+    let backups = Backups::new(backup_dir_for(&path), 2);
+    assert_eq!(None, backups.snapshot(&path)?);
+
+    let mut json_file = JsonFile::default();
+    for name in ["Ada Lovelace", "Dan Abramov", "Harry Potter"] {
+        json_file.add_to_phonebook(person!(name, "4413"))?;
+        crate::write_json(&path, &json_file)?;
+        backups.snapshot(&path)?;
+    }
+    let list = backups.list()?;
+    assert_eq!(2, list.len());
+    // Newest first
+    assert_eq!(3, backups.load(&list[0].name)?.persons().len());
+    assert!(backups.load("../book.json").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_routine_backups_are_throttled() -> Result<()> {
+    use crate::Person;
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    let path = dir.join("book.json");
+    let backups = Backups::new(backup_dir_for(&path), 2).every(Duration::from_secs(3600));
+    let mut json_file = JsonFile::default();
+    json_file.add_to_phonebook(person!("Ada Lovelace", "4413"))?;
+    crate::write_json(&path, &json_file)?;
+    let first = backups.snapshot_if_due(&path)?;
+    assert!(first.is_some());
+
+    // A burst of saves keeps the backup from before it rather than rotating it away
+    for name in ["Dan Abramov", "Harry Potter", "Mary Poppins"] {
+        json_file.add_to_phonebook(person!(name, "4413"))?;
+        crate::write_json(&path, &json_file)?;
+        assert_eq!(None, backups.snapshot_if_due(&path)?);
+    }
+    assert_eq!(1, backups.list()?.len());
+    assert_eq!(1, backups.load(&backups.list()?[0].name)?.persons().len());
+
+    // Explicit backups are never skipped, and without an interval neither are routine ones
+    assert!(backups.snapshot(&path)?.is_some());
+    assert!(backups.clone().every(Duration::ZERO).snapshot_if_due(&path)?.is_some());
+    assert_eq!(2, backups.list()?.len());
+    assert!(!first.unwrap().exists());
+    Ok(())
+}
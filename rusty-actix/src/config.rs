@@ -0,0 +1,363 @@
+//! Server configuration.
+//! Settings are layered, each layer overriding the previous one:
+//! built-in defaults, then a TOML file, then environment variables, then command line flags.
+//! Everything is validated once at startup so a typo fails loudly instead of half working.
+use crate::format::{Encoding, Format};
+use crate::Err;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// The config file picked up when neither `--config` nor `PHONEBOOK_CONFIG` is given, if it exists
+pub const DEFAULT_CONFIG_PATH: &str = "phonebook.toml";
+
+/// Which `PhonebookStore` the server runs on
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+    Json,
+    Sqlite,
+    Memory,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The JSON phonebook, also the import source of the sqlite backend
+    pub data_path: PathBuf,
+    /// The sqlite database, only used with `store = "sqlite"`
+    pub db_path: PathBuf,
+    /// Where named phonebooks other than the default one are kept, one file per book
+    pub books_dir: PathBuf,
+    pub host: String,
+    pub port: u16,
+    /// Where the react app is served from
+    pub static_dir: PathBuf,
+    /// Allowed CORS origins, an empty list allows any origin
+    pub cors_origins: Vec<String>,
+    /// An `env_logger` filter such as `info` or `warn,phonebook=debug`
+    pub log_level: String,
+    pub store: StoreKind,
+    /// Number of backups kept of the data file, 0 disables backups
+    pub backup_count: usize,
+    /// Least time between two backups taken on routine saves, 0 backs up on every save
+    pub backup_interval_minutes: u64,
+    /// Take a data file whose checksum no longer matches for a hand edit rather than for corruption,
+    /// as long as its contents are valid
+    pub accept_edits: bool,
+    /// Debounce window of the background writer
+    pub write_window_ms: u64,
+    /// How long reads and writes wait for another process to release a phonebook file, 0 fails right away
+    pub lock_timeout_ms: u64,
+    /// How many days deleted entries stay in the trash before a purge removes them
+    pub trash_retention_days: u32,
+    /// How books are stored, pretty-printed JSON by default
+    pub format: Encoding,
+    /// zstd compress stored books
+    pub compress: bool,
+    /// Encrypt JSON books with the key in this file, 32 raw bytes or 64 hex digits
+    pub key_file: Option<PathBuf>,
+    /// Encrypt JSON books with a key derived from this passphrase.
+    /// Only taken from the environment or the command line, never from the config file.
+    #[serde(skip)]
+    pub passphrase: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            data_path: PathBuf::from("files/mock.json"),
+            db_path: PathBuf::from("files/phonebook.db"),
+            books_dir: PathBuf::from("files/books"),
+            host: "0.0.0.0".into(),
+            port: 80,
+            static_dir: PathBuf::from("./react-front"),
+            cors_origins: vec![],
+            log_level: "info".into(),
+            store: StoreKind::Json,
+            backup_count: crate::backup::DEFAULT_BACKUP_COUNT,
+            backup_interval_minutes: crate::backup::DEFAULT_BACKUP_INTERVAL.as_secs() / 60,
+            accept_edits: false,
+            write_window_ms: crate::writer::DEFAULT_WRITE_WINDOW.as_millis() as u64,
+            lock_timeout_ms: crate::lock::DEFAULT_LOCK_TIMEOUT.as_millis() as u64,
+            trash_retention_days: crate::DEFAULT_TRASH_RETENTION_DAYS,
+            format: Encoding::Json,
+            compress: false,
+            key_file: None,
+            passphrase: None,
+        }
+    }
+}
+
+/// Command line flags, each of which can also be given through its environment variable
+#[derive(clap::Parser, Debug, Default)]
+#[command(name = "actixbook", about = "A phonebook server")]
+pub struct Cli {
+    /// Run a maintenance command instead of the server
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// TOML config file
+    #[arg(long, env = "PHONEBOOK_CONFIG")]
+    pub config: Option<PathBuf>,
+    #[arg(long, env = "PHONEBOOK_DATA_PATH")]
+    pub data_path: Option<PathBuf>,
+    #[arg(long, env = "PHONEBOOK_DB_PATH")]
+    pub db_path: Option<PathBuf>,
+    #[arg(long, env = "PHONEBOOK_BOOKS_DIR")]
+    pub books_dir: Option<PathBuf>,
+    #[arg(long, env = "PHONEBOOK_HOST")]
+    pub host: Option<String>,
+    #[arg(long, env = "PORT")]
+    pub port: Option<u16>,
+    #[arg(long, env = "PHONEBOOK_STATIC_DIR")]
+    pub static_dir: Option<PathBuf>,
+    /// Allowed CORS origin, repeat the flag or separate with commas
+    #[arg(long = "cors-origin", env = "PHONEBOOK_CORS_ORIGINS", value_delimiter = ',')]
+    pub cors_origins: Option<Vec<String>>,
+    #[arg(long, env = "PHONEBOOK_LOG_LEVEL")]
+    pub log_level: Option<String>,
+    #[arg(long, value_enum, env = "PHONEBOOK_STORE")]
+    pub store: Option<StoreKind>,
+    #[arg(long, env = "PHONEBOOK_BACKUP_COUNT")]
+    pub backup_count: Option<usize>,
+    #[arg(long, env = "PHONEBOOK_BACKUP_INTERVAL_MINUTES")]
+    pub backup_interval_minutes: Option<u64>,
+    #[arg(long, env = "PHONEBOOK_ACCEPT_EDITS")]
+    pub accept_edits: Option<bool>,
+    #[arg(long, env = "PHONEBOOK_WRITE_WINDOW_MS")]
+    pub write_window_ms: Option<u64>,
+    #[arg(long, env = "PHONEBOOK_LOCK_TIMEOUT_MS")]
+    pub lock_timeout_ms: Option<u64>,
+    #[arg(long, env = "PHONEBOOK_TRASH_RETENTION_DAYS")]
+    pub trash_retention_days: Option<u32>,
+    #[arg(long, value_enum, env = "PHONEBOOK_FORMAT")]
+    pub format: Option<Encoding>,
+    #[arg(long, env = "PHONEBOOK_COMPRESS")]
+    pub compress: Option<bool>,
+    #[arg(long, env = "PHONEBOOK_KEY_FILE")]
+    pub key_file: Option<PathBuf>,
+    /// Prefer the environment variable, flags show up in the process list
+    #[arg(long, env = "PHONEBOOK_PASSPHRASE", hide_env_values = true)]
+    pub passphrase: Option<String>,
+}
+
+/// Maintenance commands, these run against a file and exit without starting the server
+#[derive(clap::Subcommand, Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Upgrade a phonebook file to the current format version
+    Migrate {
+        file: PathBuf,
+        /// Only report what would change
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Encrypt a phonebook file in place with the configured key file or passphrase
+    Encrypt { file: PathBuf },
+    /// Decrypt a phonebook file in place with the configured key file or passphrase
+    Decrypt { file: PathBuf },
+    /// Rewrite a phonebook file in place in another storage format
+    Convert {
+        file: PathBuf,
+        #[arg(long, value_enum)]
+        to: Encoding,
+        /// zstd compress the result
+        #[arg(long)]
+        compress: bool,
+    },
+}
+
+impl Config {
+    /// Layer `cli` (flags and environment) over the config file it points to
+    pub fn from_cli(cli: &Cli) -> Result<Self> {
+        let base = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Self::from_file(Path::new(DEFAULT_CONFIG_PATH))?,
+            None => Self::default(),
+        };
+        let config = base.merge(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(Err::Io)
+            .with_context(|| format!("Failed to read config file `{}`", path.display()))?;
+        toml::from_str(&text)
+            .map_err(|e| Err::Config(e.to_string()))
+            .with_context(|| format!("Failed to parse config file `{}`", path.display()))
+    }
+
+    fn merge(self, cli: &Cli) -> Self {
+        Self {
+            data_path: cli.data_path.clone().unwrap_or(self.data_path),
+            db_path: cli.db_path.clone().unwrap_or(self.db_path),
+            books_dir: cli.books_dir.clone().unwrap_or(self.books_dir),
+            host: cli.host.clone().unwrap_or(self.host),
+            port: cli.port.unwrap_or(self.port),
+            static_dir: cli.static_dir.clone().unwrap_or(self.static_dir),
+            cors_origins: cli.cors_origins.clone().unwrap_or(self.cors_origins),
+            log_level: cli.log_level.clone().unwrap_or(self.log_level),
+            store: cli.store.unwrap_or(self.store),
+            backup_count: cli.backup_count.unwrap_or(self.backup_count),
+            backup_interval_minutes: cli.backup_interval_minutes.unwrap_or(self.backup_interval_minutes),
+            accept_edits: cli.accept_edits.unwrap_or(self.accept_edits),
+            write_window_ms: cli.write_window_ms.unwrap_or(self.write_window_ms),
+            lock_timeout_ms: cli.lock_timeout_ms.unwrap_or(self.lock_timeout_ms),
+            trash_retention_days: cli.trash_retention_days.unwrap_or(self.trash_retention_days),
+            format: cli.format.unwrap_or(self.format),
+            compress: cli.compress.unwrap_or(self.compress),
+            key_file: cli.key_file.clone().or(self.key_file),
+            passphrase: cli.passphrase.clone().or(self.passphrase),
+        }
+    }
+
+    /// Check every setting, reporting all problems at once
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = vec![];
+        if self.host.trim().is_empty() || self.host.contains(char::is_whitespace) {
+            problems.push(format!("host `{}` is not a valid address", self.host));
+        }
+        for (what, path) in [("data_path", &self.data_path), ("db_path", &self.db_path)] {
+            // Missing directories are created on first run, but they can't be created over a file
+            if let Some(dir) = path.parent().filter(|d| d.exists() && !d.is_dir()) {
+                problems.push(format!(
+                    "{what} `{}`: `{}` is not a directory",
+                    path.display(),
+                    dir.display()
+                ));
+            }
+            if path.is_dir() {
+                problems.push(format!("{what} `{}` is a directory", path.display()));
+            }
+        }
+        if self.books_dir.exists() && !self.books_dir.is_dir() {
+            problems.push(format!("books_dir `{}` is not a directory", self.books_dir.display()));
+        }
+        for origin in &self.cors_origins {
+            let valid = ["http://", "https://"].iter().any(
+                |scheme| matches!(origin.strip_prefix(scheme), Some(rest) if !rest.is_empty() && !rest.contains('/')),
+            );
+            if !valid {
+                problems.push(format!("cors origin `{origin}` should look like `https://example.com`"));
+            }
+        }
+        for directive in self.log_level.split(',').filter(|d| !d.is_empty()) {
+            let level = directive.rsplit('=').next().unwrap_or_default();
+            let is_level = ["off", "error", "warn", "info", "debug", "trace"].contains(&level.to_lowercase().as_str());
+            // A bare module name (`phonebook`) is a valid filter too
+            if !is_level && directive.contains('=') {
+                problems.push(format!("log_level `{directive}`: unknown level `{level}`"));
+            }
+        }
+        if self.backup_interval_minutes > 10_080 {
+            problems.push(format!(
+                "backup_interval_minutes `{}` should be at most 10080",
+                self.backup_interval_minutes
+            ));
+        }
+        if self.write_window_ms == 0 || self.write_window_ms > 60_000 {
+            problems.push(format!(
+                "write_window_ms `{}` should be between 1 and 60000",
+                self.write_window_ms
+            ));
+        }
+        if self.lock_timeout_ms > 600_000 {
+            problems.push(format!(
+                "lock_timeout_ms `{}` should be at most 600000",
+                self.lock_timeout_ms
+            ));
+        }
+        if self.trash_retention_days > 36_500 {
+            problems.push(format!(
+                "trash_retention_days `{}` should be at most 36500",
+                self.trash_retention_days
+            ));
+        }
+        if self.key_file.is_some() && self.passphrase.is_some() {
+            problems.push("set either key_file or a passphrase, not both".to_owned());
+        }
+        if let Some(key_file) = self.key_file.as_ref().filter(|p| !p.is_file()) {
+            problems.push(format!("key_file `{}` is not a file", key_file.display()));
+        }
+        if self.store == StoreKind::Sqlite && (self.key_file.is_some() || self.passphrase.is_some()) {
+            problems.push("encryption at rest is only supported by the json store".to_owned());
+        }
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(Err::Config(problems.join("; "))).with_context(|| "Invalid configuration")
+    }
+
+    pub fn bind_address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    pub fn backup_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.backup_interval_minutes * 60)
+    }
+
+    pub fn write_window(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.write_window_ms)
+    }
+
+    pub fn lock_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.lock_timeout_ms)
+    }
+
+    pub fn trash_retention(&self) -> chrono::TimeDelta {
+        chrono::TimeDelta::days(self.trash_retention_days.into())
+    }
+
+    pub fn storage_format(&self) -> Format {
+        Format {
+            encoding: self.format,
+            compress: self.compress,
+        }
+    }
+}
+
+#[test]
+fn test_config_layers() -> Result<()> {
+    use clap::Parser;
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    let file = dir.join("phonebook.toml");
+    std::fs::write(&file, "port = 8080\nstore = \"sqlite\"\nstatic_dir = \".\"\n")?;
+
+    let cli = Cli::try_parse_from(["actixbook", "--config", file.to_str().unwrap(), "--port", "9090"])?;
+    let config = Config::from_cli(&cli)?;
+    // The flag wins over the file, the file wins over the defaults
+    assert_eq!(9090, config.port);
+    assert_eq!(StoreKind::Sqlite, config.store);
+    assert_eq!(Config::default().data_path, config.data_path);
+    let cli = Cli::try_parse_from(["actixbook", "migrate", "old.json", "--dry-run"])?;
+    assert_eq!(
+        Some(Command::Migrate {
+            file: "old.json".into(),
+            dry_run: true
+        }),
+        cli.command
+    );
+
+    let broken = Config {
+        port: 1,
+        cors_origins: vec!["example.com".into()],
+        log_level: "loud".into(),
+        write_window_ms: 0,
+        ..Config::default()
+    };
+    let err = format!("{:?}", broken.validate().unwrap_err());
+    for field in ["cors origin", "write_window_ms"] {
+        assert!(err.contains(field), "{field} missing from {err}");
+    }
+    // Without the react app the API is still worth serving
+    let headless = Config {
+        static_dir: dir.join("missing"),
+        ..Config::default()
+    };
+    assert!(headless.validate().is_ok());
+    std::fs::write(&file, "prot = 8080\n")?;
+    assert!(Config::from_file(&file).is_err());
+    Ok(())
+}
@@ -0,0 +1,213 @@
+//! Contact details of a `Person` besides phone numbers: emails, postal addresses and websites.
+//! Every detail is labelled and optional, a person may have any number of each. `Person::normalize`
+//! trims and validates them on input, so whatever is stored has already passed these checks.
+use crate::Err;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum Label {
+    Home,
+    Work,
+    #[default]
+    Other,
+}
+
+impl Label {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Label::Home => "home",
+            Label::Work => "work",
+            Label::Other => "other",
+        }
+    }
+}
+
+impl std::str::FromStr for Label {
+    type Err = Err;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "home" => Ok(Label::Home),
+            "work" => Ok(Label::Work),
+            "other" => Ok(Label::Other),
+            _ => Err(Err::PhonebookEntry(format!("Unknown label `{s}`"))),
+        }
+    }
+}
+
+impl Display for Label {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, PartialOrd)]
+pub struct Email {
+    #[serde(default)]
+    pub label: Label,
+    pub address: String,
+}
+
+impl Email {
+    /// A single `@` between a non-empty local part and a dotted domain, and no whitespace anywhere.
+    /// Deliberately loose, the only real check is sending a mail to it.
+    pub fn normalize(&mut self) -> Result<()> {
+        self.address = self.address.trim().to_owned();
+        let valid = match self.address.split_once('@') {
+            Some((local, domain)) => {
+                !local.is_empty()
+                    && !domain.contains('@')
+                    && domain.split('.').count() > 1
+                    && domain.split('.').all(|part| !part.is_empty())
+                    && !self.address.contains(char::is_whitespace)
+            }
+            None => false,
+        };
+        if valid {
+            return Ok(());
+        }
+        Err(Err::PhonebookEntry("Invalid email".into()))
+            .with_context(|| format!("`{}` is not an email address", self.address))
+    }
+}
+
+impl Display for Email {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.label, self.address)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, PartialOrd)]
+pub struct Address {
+    #[serde(default)]
+    pub label: Label,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub street: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub city: Option<String>,
+    /// State, province or county
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub postal_code: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+}
+
+impl Address {
+    /// Trim every part and drop the blank ones, at least one part has to be left
+    pub fn normalize(&mut self) -> Result<()> {
+        let mut blank = true;
+        for part in self.parts_mut() {
+            *part = part.take().map(|p| p.trim().to_owned()).filter(|p| !p.is_empty());
+            blank &= part.is_none();
+        }
+        if !blank {
+            return Ok(());
+        }
+        Err(Err::PhonebookEntry("Empty address".into())).with_context(|| {
+            format!(
+                "A {} address needs at least one of street, city, region, postal code or country",
+                self.label
+            )
+        })
+    }
+
+    fn parts_mut(&mut self) -> [&mut Option<String>; 5] {
+        [
+            &mut self.street,
+            &mut self.city,
+            &mut self.region,
+            &mut self.postal_code,
+            &mut self.country,
+        ]
+    }
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parts = [&self.street, &self.city, &self.region, &self.postal_code, &self.country];
+        let parts: Vec<&str> = parts.into_iter().flatten().map(String::as_str).collect();
+        write!(f, "{}: {}", self.label, parts.join(", "))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, PartialOrd)]
+pub struct Website {
+    #[serde(default)]
+    pub label: Label,
+    pub url: String,
+}
+
+impl Website {
+    /// Only absolute http and https URLs, normalized the way browsers would
+    pub fn normalize(&mut self) -> Result<()> {
+        let url = url::Url::parse(self.url.trim())
+            .ok()
+            .filter(|url| matches!(url.scheme(), "http" | "https") && url.has_host())
+            .ok_or_else(|| Err::PhonebookEntry("Invalid url".into()))
+            .with_context(|| format!("`{}` is not an http or https URL", self.url))?;
+        self.url = url.into();
+        Ok(())
+    }
+}
+
+impl Display for Website {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.label, self.url)
+    }
+}
+
+#[test]
+fn test_details_are_validated() -> Result<()> {
+    let mut email = Email {
+        address: " ada@example.com ".into(),
+        ..Default::default()
+    };
+    email.normalize()?;
+    assert_eq!("ada@example.com", email.address);
+    for bad in [
+        "ada",
+        "ada@",
+        "@example.com",
+        "ada@example",
+        "ada@@example.com",
+        "a da@example.com",
+        "ada@example..com",
+    ] {
+        let mut email = Email {
+            address: bad.into(),
+            ..Default::default()
+        };
+        assert!(email.normalize().is_err(), "{bad} passed");
+    }
+
+    let mut address = Address {
+        city: Some(" London ".into()),
+        street: Some("  ".into()),
+        ..Default::default()
+    };
+    address.normalize()?;
+    assert_eq!(
+        (None, Some("London")),
+        (address.street.as_deref(), address.city.as_deref())
+    );
+    assert!(Address::default().normalize().is_err());
+
+    let mut website = Website {
+        url: "HTTPS://Example.com".into(),
+        ..Default::default()
+    };
+    website.normalize()?;
+    assert_eq!("https://example.com/", website.url);
+    for bad in ["example.com", "ftp://example.com", "javascript:alert(1)"] {
+        let mut website = Website {
+            url: bad.into(),
+            ..Default::default()
+        };
+        assert!(website.normalize().is_err(), "{bad} passed");
+    }
+    Ok(())
+}
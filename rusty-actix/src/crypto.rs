@@ -0,0 +1,267 @@
+//! Optional encryption at rest of the phonebook file, its backups and its journal.
+//! With a key configured, `write_json` seals the serialized phonebook with XChaCha20-Poly1305 and
+//! `read_json` opens it again, anything tampered with fails to open instead of being loaded.
+//! The key is either 32 bytes read from a key file or derived with Argon2id from a passphrase and a per file salt.
+//! Plain files are still read while a key is set, so an existing phonebook gets encrypted on its next save.
+use crate::Err;
+use anyhow::{Context, Result};
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::XChaCha20Poly1305;
+use parking_lot::{Mutex, RwLock};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Every encrypted file starts with these bytes, plain JSON never does
+pub const MAGIC: &[u8; 8] = b"PBOOKENC";
+const FORMAT_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+/// Magic, format version, key source, salt and nonce
+const HEADER_LEN: usize = MAGIC.len() + 2 + SALT_LEN + NONCE_LEN;
+
+// Set once at startup, read by `read_json`, `write_json` and the journal
+static KEY: RwLock<Option<Arc<Key>>> = RwLock::new(None);
+
+/// Encrypt everything written from now on with `key`, or stop encrypting with `None`
+pub fn set_key(key: Option<Key>) {
+    *KEY.write() = key.map(Arc::new);
+}
+
+/// The key set with `set_key`
+pub fn key() -> Option<Arc<Key>> {
+    KEY.read().clone()
+}
+
+/// Whether `bytes` were produced by `Key::seal`
+pub fn is_encrypted(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub enum Key {
+    /// Used as is, the salt is ignored
+    File([u8; 32]),
+    /// Derived per salt, every save reuses `salt` so the costly derivation only runs once per salt
+    Passphrase {
+        passphrase: String,
+        salt: [u8; SALT_LEN],
+        derived: Mutex<HashMap<[u8; SALT_LEN], [u8; 32]>>,
+    },
+}
+
+// Never print key material
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Key::File(_) => f.write_str("Key::File(..)"),
+            Key::Passphrase { .. } => f.write_str("Key::Passphrase(..)"),
+        }
+    }
+}
+
+impl Key {
+    /// Read a key file holding either 32 raw bytes or 64 hex digits
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path)
+            .map_err(Err::Io)
+            .with_context(|| format!("Failed to read key file `{}`", path.display()))?;
+        let hex_key = std::str::from_utf8(&bytes)
+            .ok()
+            .and_then(|s| hex::decode(s.trim()).ok());
+        let key = match hex_key {
+            Some(key) if key.len() == 32 => key,
+            _ => bytes,
+        };
+        let key = <[u8; 32]>::try_from(key.as_slice())
+            .map_err(|_| Err::Config("Invalid key file".into()))
+            .with_context(|| format!("Key file `{}` should hold 32 bytes or 64 hex digits", path.display()))?;
+        Ok(Self::File(key))
+    }
+
+    pub fn from_passphrase(passphrase: &str) -> Result<Self> {
+        if passphrase.is_empty() {
+            return Err(Err::Config("Empty passphrase".into())).with_context(|| "The passphrase must not be empty");
+        }
+        Ok(Self::Passphrase {
+            passphrase: passphrase.to_owned(),
+            salt: rand::random(),
+            derived: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The key configured by a key file or a passphrase, a key file wins if both are given
+    pub fn load(key_file: Option<&Path>, passphrase: Option<&str>) -> Result<Option<Self>> {
+        match (key_file, passphrase) {
+            (Some(path), _) => Self::from_file(path).map(Some),
+            (None, Some(passphrase)) => Self::from_passphrase(passphrase).map(Some),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Encrypt `plain`, the result starts with `MAGIC`
+    pub fn seal(&self, plain: &[u8]) -> Result<Vec<u8>> {
+        let (source, salt) = match self {
+            Key::File(_) => (0, [0; SALT_LEN]),
+            Key::Passphrase { salt, .. } => (1, *salt),
+        };
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let mut sealed = Vec::with_capacity(HEADER_LEN + plain.len() + 16);
+        sealed.extend_from_slice(MAGIC);
+        sealed.extend_from_slice(&[FORMAT_VERSION, source]);
+        sealed.extend_from_slice(&salt);
+        sealed.extend_from_slice(&nonce);
+        // The header is authenticated too, so it can't be swapped between files
+        let ciphertext = self
+            .cipher(&salt)?
+            .encrypt(
+                nonce.as_ref().into(),
+                Payload {
+                    msg: plain,
+                    aad: &sealed,
+                },
+            )
+            .map_err(|_| Err::Config("Encryption failed".into()))?;
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Decrypt what `seal` produced
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < HEADER_LEN || !is_encrypted(sealed) {
+            return Err(Err::PhonebookEntry("Not encrypted".into())).with_context(|| "Data is not encrypted");
+        }
+        let (header, ciphertext) = sealed.split_at(HEADER_LEN);
+        let (version, source) = (header[MAGIC.len()], header[MAGIC.len() + 1]);
+        if version != FORMAT_VERSION {
+            return Err(Err::PhonebookEntry("Unsupported encryption".into()))
+                .with_context(|| format!("Unsupported encryption format version {version}"));
+        }
+        let expected = match self {
+            Key::File(_) => 0,
+            Key::Passphrase { .. } => 1,
+        };
+        if source != expected {
+            let what = if source == 0 { "a key file" } else { "a passphrase" };
+            return Err(Err::Config("Wrong kind of key".into()))
+                .with_context(|| format!("Data was encrypted with {what}"));
+        }
+        let salt: [u8; SALT_LEN] = header[MAGIC.len() + 2..][..SALT_LEN]
+            .try_into()
+            .expect("Sliced to length");
+        let nonce = &header[HEADER_LEN - NONCE_LEN..];
+        self.cipher(&salt)?
+            .decrypt(
+                nonce.into(),
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map_err(|_| Err::Config("Decryption failed".into()))
+            .with_context(|| "Failed to decrypt, the key is wrong or the data was tampered with")
+    }
+
+    fn cipher(&self, salt: &[u8; SALT_LEN]) -> Result<XChaCha20Poly1305> {
+        let key = match self {
+            Key::File(key) => *key,
+            Key::Passphrase {
+                passphrase, derived, ..
+            } => {
+                let mut derived = derived.lock();
+                match derived.get(salt) {
+                    Some(key) => *key,
+                    None => {
+                        let mut key = [0; 32];
+                        Argon2::default()
+                            .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+                            .map_err(|e| Err::Config(e.to_string()))
+                            .with_context(|| "Failed to derive a key from the passphrase")?;
+                        *derived.entry(*salt).or_insert(key)
+                    }
+                }
+            }
+        };
+        Ok(XChaCha20Poly1305::new(&key.into()))
+    }
+}
+
+/// Decrypt `bytes` with the key set by `set_key` if they are encrypted, otherwise hand them back untouched
+pub(crate) fn decode<'a>(bytes: &'a [u8], path: &Path) -> Result<std::borrow::Cow<'a, [u8]>> {
+    if !is_encrypted(bytes) {
+        if key().is_some() {
+            log::warn!("`{}` is not encrypted yet, it will be on its next save", path.display());
+        }
+        return Ok(bytes.into());
+    }
+    let key = key().ok_or_else(|| Err::Config("No key".into())).with_context(|| {
+        format!(
+            "`{}` is encrypted, configure a key file or a passphrase",
+            path.display()
+        )
+    })?;
+    let plain = key
+        .open(bytes)
+        .with_context(|| format!("Failed to decrypt `{}`", path.display()))?;
+    Ok(plain.into())
+}
+
+/// Encrypt the plain phonebook file at `path` in place
+pub fn encrypt_file(path: &Path, key: &Key) -> Result<()> {
+    let plain = read(path)?;
+    if is_encrypted(&plain) {
+        return Err(Err::PhonebookEntry("Already encrypted".into()))
+            .with_context(|| format!("`{}` is already encrypted", path.display()));
+    }
+    // Refuse to seal garbage, it could never be loaded again
+    let (format, payload) = crate::format::decode(&plain, path)?;
+    crate::format::parse::<serde_json::Value>(format.encoding, &payload, path)
+        .with_context(|| format!("`{}` is not a phonebook", path.display()))?;
+    let sealed = key.seal(&plain)?;
+    crate::write_atomic(path, |wrt| crate::write_bytes_and_sync(wrt, &sealed))
+}
+
+/// Decrypt the phonebook file at `path` in place
+pub fn decrypt_file(path: &Path, key: &Key) -> Result<()> {
+    let sealed = read(path)?;
+    let plain = key
+        .open(&sealed)
+        .with_context(|| format!("Failed to decrypt `{}`", path.display()))?;
+    crate::write_atomic(path, |wrt| crate::write_bytes_and_sync(wrt, &plain))
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    let _lock = crate::lock::shared(path)?;
+    std::fs::read(path)
+        .map_err(Err::Io)
+        .with_context(|| format!("Failed to read `{}`", path.display()))
+}
+
+#[test]
+fn test_encrypt_roundtrip() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    let path = dir.join("book.json");
+    let plain = br#"{ "version": 1, "phonebook": [{ "id": 1, "name": "Ada Lovelace", "number": "1" }] }"#;
+    std::fs::write(&path, plain)?;
+    std::fs::write(dir.join("key"), hex::encode([7; 32]))?;
+    let key = Key::from_file(&dir.join("key"))?;
+
+    encrypt_file(&path, &key)?;
+    let sealed = std::fs::read(&path)?;
+    assert!(is_encrypted(&sealed));
+    assert!(!sealed.windows(3).any(|w| w == b"Ada"));
+    assert!(encrypt_file(&path, &key).is_err());
+    // Without the key there is nothing to read, with the wrong one neither
+    assert!(crate::read_json(&path).is_err());
+    assert!(Key::File([8; 32]).open(&sealed).is_err());
+    let mut flipped = sealed.clone();
+    *flipped.last_mut().unwrap() ^= 1;
+    assert!(key.open(&flipped).is_err());
+
+    decrypt_file(&path, &key)?;
+    assert_eq!(plain.as_slice(), std::fs::read(&path)?);
+    let key = Key::from_passphrase("correct horse battery staple")?;
+    assert_eq!(plain.as_slice(), key.open(&key.seal(plain)?)?);
+    Ok(())
+}
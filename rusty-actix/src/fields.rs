@@ -0,0 +1,253 @@
+//! Custom fields, the attributes a team adds to its contacts without a code change: an employee number,
+//! a cost centre, a pager id. Each phonebook has a `Schema` listing its fields with their type and whether
+//! they are required. Values live in `Person::fields` and are checked against the schema of their book
+//! whenever an entry is added, updated or restored.
+use crate::{Err, Person};
+use anyhow::{Context, Result};
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Display;
+
+/// Longest field name accepted
+pub const MAX_FIELD_NAME_LEN: usize = 64;
+
+/// The fields entries of a phonebook may carry
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Schema {
+    #[serde(default)]
+    pub fields: Vec<FieldDef>,
+}
+
+/// e.g. `{"name": "cost_centre", "type": "enum", "values": ["CC-1", "CC-2"], "required": true}`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FieldDef {
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: FieldType,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub required: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum FieldType {
+    String,
+    Number,
+    /// `YYYY-MM-DD`
+    Date,
+    /// One of `values`, matched without case and stored as listed
+    Enum {
+        values: Vec<String>,
+    },
+    Boolean,
+}
+
+/// The value of a custom field. Dates and enum values are text, in the form `Schema::check` left them in.
+#[derive(Deserialize, Debug, Clone, PartialEq, PartialOrd)]
+#[serde(untagged)]
+pub enum FieldValue {
+    Boolean(bool),
+    Number(f64),
+    Text(String),
+}
+
+impl Serialize for FieldValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            FieldValue::Boolean(b) => serializer.serialize_bool(*b),
+            // Whole numbers such as an employee number come back the way they were sent, not as `1234.0`
+            FieldValue::Number(n) if n.fract() == 0.0 && n.abs() < 2f64.powi(53) => serializer.serialize_i64(*n as i64),
+            FieldValue::Number(n) => serializer.serialize_f64(*n),
+            FieldValue::Text(text) => serializer.serialize_str(text),
+        }
+    }
+}
+
+impl FieldValue {
+    /// Whether the value reads as `text`, ignoring case, which is how `?field=name:value` filters match
+    pub fn matches(&self, text: &str) -> bool {
+        self.to_string().eq_ignore_ascii_case(text.trim())
+    }
+}
+
+impl Display for FieldValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FieldValue::Boolean(b) => write!(f, "{b}"),
+            FieldValue::Number(n) => write!(f, "{n}"),
+            FieldValue::Text(text) => f.write_str(text),
+        }
+    }
+}
+
+impl Schema {
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&FieldDef> {
+        self.fields.iter().find(|f| f.name == name)
+    }
+
+    /// Field names are unique lowercase identifiers such as `cost_centre`, enums need at least one value
+    pub fn validate(&self) -> Result<()> {
+        let mut names = HashSet::with_capacity(self.fields.len());
+        for field in &self.fields {
+            let valid = !field.name.is_empty()
+                && field.name.len() <= MAX_FIELD_NAME_LEN
+                && field
+                    .name
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+            if !valid {
+                return Err(Err::PhonebookEntry("Invalid field name".into())).with_context(|| {
+                    format!(
+                        "`{}` is not a field name, use up to {MAX_FIELD_NAME_LEN} lowercase letters, digits or `_`",
+                        field.name
+                    )
+                });
+            }
+            if !names.insert(field.name.as_str()) {
+                return Err(Err::PhonebookEntry("Duplicate field".into()))
+                    .with_context(|| format!("The schema lists field `{}` more than once", field.name));
+            }
+            if let FieldType::Enum { values } = &field.kind {
+                let mut seen = HashSet::with_capacity(values.len());
+                if values.is_empty()
+                    || values
+                        .iter()
+                        .any(|v| v.trim().is_empty() || !seen.insert(v.to_lowercase()))
+                {
+                    return Err(Err::PhonebookEntry("Invalid enum".into())).with_context(|| {
+                        format!("Enum field `{}` needs a list of distinct, non-blank values", field.name)
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop the fields of `person` that are not in the schema
+    pub fn drop_unknown(&self, person: &mut Person) {
+        person.fields.retain(|name, _| self.get(name).is_some());
+    }
+
+    /// Check the custom fields of `person` against the schema and bring their values into canonical form.
+    /// Unknown fields, values of the wrong type and missing required fields are rejected, blank text is dropped.
+    pub fn check(&self, person: &mut Person) -> Result<()> {
+        let fields = std::mem::take(&mut person.fields);
+        let mut checked = BTreeMap::new();
+        for (name, value) in fields {
+            let Some(field) = self.get(&name) else {
+                return Err(Err::PhonebookEntry("Unknown field".into()))
+                    .with_context(|| format!("{} has field `{name}`, which is not in the schema", person.name));
+            };
+            if let Some(value) = field
+                .check(value)
+                .with_context(|| format!("Invalid field of {}", person.name))?
+            {
+                checked.insert(name, value);
+            }
+        }
+        if let Some(missing) = self
+            .fields
+            .iter()
+            .find(|f| f.required && !checked.contains_key(&f.name))
+        {
+            return Err(Err::PhonebookEntry("Missing field".into()))
+                .with_context(|| format!("{} lacks the required field `{}`", person.name, missing.name));
+        }
+        person.fields = checked;
+        Ok(())
+    }
+}
+
+impl FieldDef {
+    /// The canonical form of `value`, `None` for blank text
+    fn check(&self, value: FieldValue) -> Result<Option<FieldValue>> {
+        let value = match value {
+            FieldValue::Text(text) if text.trim().is_empty() => return Ok(None),
+            FieldValue::Text(text) => FieldValue::Text(text.trim().to_owned()),
+            value => value,
+        };
+        let checked = match (&self.kind, value) {
+            (FieldType::String, value @ FieldValue::Text(_)) => Some(value),
+            (FieldType::Boolean, value @ FieldValue::Boolean(_)) => Some(value),
+            (FieldType::Number, value @ FieldValue::Number(n)) if n.is_finite() => Some(value),
+            (FieldType::Date, FieldValue::Text(text)) => NaiveDate::parse_from_str(&text, "%Y-%m-%d")
+                .ok()
+                .map(|date| FieldValue::Text(date.format("%Y-%m-%d").to_string())),
+            (FieldType::Enum { values }, FieldValue::Text(text)) => values
+                .iter()
+                .find(|v| v.eq_ignore_ascii_case(&text))
+                .map(|v| FieldValue::Text(v.clone())),
+            _ => None,
+        };
+        checked
+            .map(Some)
+            .ok_or_else(|| Err::PhonebookEntry("Invalid field value".into()))
+            .with_context(|| format!("Field `{}` should be {}", self.name, self.kind))
+    }
+}
+
+impl Display for FieldType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FieldType::String => f.write_str("text"),
+            FieldType::Number => f.write_str("a number"),
+            FieldType::Date => f.write_str("a date like 2024-05-31"),
+            FieldType::Enum { values } => write!(f, "one of {}", values.join(", ")),
+            FieldType::Boolean => f.write_str("true or false"),
+        }
+    }
+}
+
+#[test]
+fn test_fields_follow_the_schema() -> Result<()> {
+    let schema: Schema = serde_json::from_str(
+        r#"{"fields": [
+            {"name": "employee_number", "type": "number", "required": true},
+            {"name": "cost_centre", "type": "enum", "values": ["CC-1", "CC-2"]},
+            {"name": "started", "type": "date"},
+            {"name": "pager", "type": "string"},
+            {"name": "contractor", "type": "boolean"}
+        ]}"#,
+    )?;
+    schema.validate()?;
+    let with = |fields: &str| -> Result<Person> {
+        let mut person = Person {
+            fields: serde_json::from_str(fields)?,
+            ..person!("Ada Lovelace", "1")
+        };
+        schema.check(&mut person)?;
+        Ok(person)
+    };
+    let ada = with(r#"{"employee_number": 1815, "cost_centre": "cc-2", "started": "1833-6-5", "pager": "  "}"#)?;
+    assert_eq!(
+        r#"{"cost_centre":"CC-2","employee_number":1815,"started":"1833-06-05"}"#,
+        serde_json::to_string(&ada.fields)?
+    );
+    assert!(ada.fields["employee_number"].matches("1815"));
+    for bad in [
+        r#"{}"#,
+        r#"{"employee_number": "1815"}"#,
+        r#"{"employee_number": 1, "cost_centre": "CC-3"}"#,
+        r#"{"employee_number": 1, "started": "yesterday"}"#,
+        r#"{"employee_number": 1, "contractor": "yes"}"#,
+        r#"{"employee_number": 1, "shoe_size": 44}"#,
+    ] {
+        assert!(with(bad).is_err(), "{bad} passed");
+    }
+
+    for bad in [
+        r#"{"fields": [{"name": "Cost Centre", "type": "string"}]}"#,
+        r#"{"fields": [{"name": "a", "type": "string"}, {"name": "a", "type": "date"}]}"#,
+        r#"{"fields": [{"name": "a", "type": "enum", "values": []}]}"#,
+    ] {
+        assert!(serde_json::from_str::<Schema>(bad)?.validate().is_err(), "{bad} passed");
+    }
+    assert!(serde_json::from_str::<Schema>(r#"{"fields": [{"name": "a", "type": "colour"}]}"#).is_err());
+    Ok(())
+}
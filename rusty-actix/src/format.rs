@@ -0,0 +1,150 @@
+//! Storage formats of the phonebook file.
+//! Pretty-printed JSON stays the default so the file can be read and edited by hand. Large books can be
+//! stored as CBOR instead, and either encoding can be zstd compressed. Anything but plain JSON starts with
+//! `MAGIC` followed by the encoding and the compression, so `read_json` picks the right decoder by itself.
+use crate::Err;
+use anyhow::{Context, Result};
+use parking_lot::RwLock;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::path::Path;
+
+/// Every file that isn't plain JSON starts with these bytes
+pub const MAGIC: &[u8; 8] = b"PBOOKBIN";
+const HEADER_LEN: usize = MAGIC.len() + 2;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    // The discriminants are stored in the header
+    #[default]
+    Json = 0,
+    Cbor = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Format {
+    pub encoding: Encoding,
+    /// zstd compress the encoded phonebook
+    pub compress: bool,
+}
+
+// Set once at startup, read by `write_json`
+static FORMAT: RwLock<Format> = RwLock::new(Format {
+    encoding: Encoding::Json,
+    compress: false,
+});
+
+/// Write every phonebook from now on in `format`
+pub fn set_format(format: Format) {
+    *FORMAT.write() = format;
+}
+
+/// The format set with `set_format`
+pub fn format() -> Format {
+    *FORMAT.read()
+}
+
+impl Format {
+    /// Whether this is the pretty-printed JSON that `write_json` streams straight into the file
+    pub fn is_plain_json(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Serialize `value`, with a header unless it is plain JSON
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        let payload = match self.encoding {
+            Encoding::Json => serde_json::to_vec_pretty(value).map_err(Err::Json)?,
+            Encoding::Cbor => {
+                let mut payload = vec![];
+                ciborium::into_writer(value, &mut payload)
+                    .map_err(|e| Err::Corrupt(e.to_string()))
+                    .with_context(|| "Failed to encode CBOR")?;
+                payload
+            }
+        };
+        if self.is_plain_json() {
+            return Ok(payload);
+        }
+        let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&[self.encoding as u8, self.compress as u8]);
+        if self.compress {
+            zstd::stream::copy_encode(payload.as_slice(), &mut bytes, 0)
+                .map_err(Err::Io)
+                .with_context(|| "Failed to compress")?;
+        } else {
+            bytes.extend_from_slice(&payload);
+        }
+        Ok(bytes)
+    }
+}
+
+/// Split what `Format::encode` produced into its format and the uncompressed payload
+pub fn decode<'a>(bytes: &'a [u8], path: &Path) -> Result<(Format, Cow<'a, [u8]>)> {
+    if !bytes.starts_with(MAGIC) {
+        return Ok((Format::default(), bytes.into()));
+    }
+    let corrupt = |what: &str| {
+        Err(Err::Corrupt(what.to_owned()))
+            .with_context(|| format!("`{}` has an invalid header: {what}", path.display()))
+    };
+    let Some(&[encoding, compress]) = bytes.get(MAGIC.len()..HEADER_LEN) else {
+        return corrupt("truncated");
+    };
+    let encoding = match encoding {
+        0 => Encoding::Json,
+        1 => Encoding::Cbor,
+        _ => return corrupt("unknown encoding"),
+    };
+    let payload = &bytes[HEADER_LEN..];
+    let format = Format {
+        encoding,
+        compress: compress != 0,
+    };
+    if !format.compress {
+        return Ok((format, payload.into()));
+    }
+    let payload = zstd::decode_all(payload)
+        .map_err(|e| Err::Corrupt(e.to_string()))
+        .with_context(|| format!("Failed to decompress `{}`", path.display()))?;
+    Ok((format, payload.into()))
+}
+
+/// Deserialize a payload returned by `decode`
+pub fn parse<T: DeserializeOwned>(encoding: Encoding, payload: &[u8], path: &Path) -> Result<T> {
+    match encoding {
+        Encoding::Json => serde_json::from_slice(payload)
+            .map_err(Err::Json)
+            // serde_json's error already knows where it stopped, but say which file it was
+            .with_context(|| format!("json file parse error in `{}`", path.display())),
+        Encoding::Cbor => ciborium::from_reader(payload)
+            .map_err(|e| Err::Corrupt(e.to_string()))
+            .with_context(|| format!("cbor file parse error in `{}`", path.display())),
+    }
+}
+
+#[test]
+fn test_formats_roundtrip() -> Result<()> {
+    use crate::{read_json, write_json_as, JsonFile, Person};
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    let path = dir.join("book.json");
+    let mut json_file = JsonFile::default();
+    for i in 0..100 {
+        json_file.add_to_phonebook(person!(format!("Person {i}"), "39-44-5323523"))?;
+    }
+    let mut sizes = vec![];
+    for encoding in [Encoding::Json, Encoding::Cbor] {
+        for compress in [false, true] {
+            write_json_as(&path, &json_file, Format { encoding, compress })?;
+            sizes.push(std::fs::metadata(&path)?.len());
+            assert_eq!(json_file.persons(), read_json(&path)?.persons());
+        }
+    }
+    // Plain JSON is the largest, compressed CBOR the smallest
+    assert_eq!(Some(&sizes[0]), sizes.iter().max());
+    assert!(sizes[3] < sizes[2]);
+    Ok(())
+}
@@ -10,17 +10,39 @@ impl<T> IntoActixResult<T> for anyhow::Result<T, anyhow::Error> {
     fn actix_result(self) -> core::result::Result<T, actix_web::Error> {
         match self {
             Ok(val) => Ok(val),
-            Err(err) => match err.downcast() {
-                Ok(AppErr::Io(inner)) => {
-                    Err(actix_error::InternalError::new(inner, StatusCode::INTERNAL_SERVER_ERROR).into())
+            Err(err) => {
+                // Downcasting drops the context, so whatever ends up a 500 is logged with its whole chain first
+                let expected = matches!(
+                    err.downcast_ref::<AppErr>(),
+                    Some(
+                        AppErr::PhonebookEntry(_)
+                            | AppErr::NotFound(_)
+                            | AppErr::ReadLockTimeout(_)
+                            | AppErr::WriteLockTimeout(_)
+                    )
+                );
+                if !expected {
+                    log::error!("Request failed: {err:?}");
                 }
-                Ok(AppErr::Json(inner)) => Err(actix_error::ErrorInternalServerError(inner)),
-                // Ok(AppErr::PhonebookEntry(inner)) => Err(actix_error::ErrorBadRequest(inner)),
-                Ok(AppErr::PhonebookEntry(inner)) => Err(actix_error::ErrorBadRequest(inner)),
-                _ => Err(
-                    actix_error::InternalError::new("Something went wrong", StatusCode::INTERNAL_SERVER_ERROR).into(),
-                ),
-            },
+                match err.downcast() {
+                    Ok(AppErr::Io(inner)) => {
+                        Err(actix_error::InternalError::new(inner, StatusCode::INTERNAL_SERVER_ERROR).into())
+                    }
+                    Ok(AppErr::Json(inner)) => Err(actix_error::ErrorInternalServerError(inner)),
+                    // Ok(AppErr::PhonebookEntry(inner)) => Err(actix_error::ErrorBadRequest(inner)),
+                    Ok(AppErr::PhonebookEntry(inner)) => Err(actix_error::ErrorBadRequest(inner)),
+                    Ok(AppErr::NotFound(inner)) => Err(actix_error::ErrorNotFound(inner)),
+                    // Another process holds the file, worth retrying
+                    Ok(AppErr::ReadLockTimeout(inner) | AppErr::WriteLockTimeout(inner)) => {
+                        Err(actix_error::ErrorServiceUnavailable(inner))
+                    }
+                    _ => Err(actix_error::InternalError::new(
+                        "Something went wrong",
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                    )
+                    .into()),
+                }
+            }
         }
     }
 }
@@ -0,0 +1,352 @@
+//! An append-only write-ahead journal of phonebook mutations.
+//! Every successful mutation, from `add_to_phonebook` to `purge`, is recorded as one JSON line
+//! next to the data file, so a change costs one small append instead of a full rewrite.
+//! On startup the journal is replayed over the last snapshot and `compact` folds it back
+//! into the JSON file once it grows past `JOURNAL_COMPACT_THRESHOLD` entries.
+//! With encryption at rest, see `phonebook::crypto`, each line is a sealed entry in hex instead.
+use crate::{crypto, write_json, Err, JsonFile, Organization, OrganizationID, Person, PersonID, Schema};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+/// Number of journal entries after which the journal gets folded into the snapshot
+pub const JOURNAL_COMPACT_THRESHOLD: usize = 1024;
+
+/// A single mutation as it is stored on disk, one per line e.g. `{"delete":{"id":4}}`
+// Externally tagged on purpose: internally tagged enums buffer their content and serde can't buffer a u128 `PersonID`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JournalEntry {
+    /// `person.id` is the id that was assigned when the entry was first added
+    Add {
+        person: Person,
+    },
+    /// `person` is the edit as requested, `revision` what the entry was at afterwards.
+    /// `revision` and `at` are missing from journals written before entries had metadata.
+    Update {
+        id: PersonID,
+        person: Person,
+        #[serde(default)]
+        revision: u64,
+        #[serde(default)]
+        at: Option<DateTime<Utc>>,
+    },
+    /// Moves the entry to the trash, see `JsonFile::delete`
+    Delete {
+        id: PersonID,
+        #[serde(default)]
+        at: Option<DateTime<Utc>>,
+    },
+    /// `revision` is what the entry was at once restored
+    Restore {
+        id: PersonID,
+        revision: u64,
+        at: DateTime<Utc>,
+    },
+    Purge {
+        before: DateTime<Utc>,
+    },
+    /// A new custom field schema, see `JsonFile::set_schema`
+    Schema {
+        schema: Schema,
+        at: DateTime<Utc>,
+    },
+    /// Bulk group membership changes, see `JsonFile::tag` and `JsonFile::untag`
+    Tag {
+        tag: String,
+        ids: Vec<PersonID>,
+        #[serde(default)]
+        at: Option<DateTime<Utc>>,
+    },
+    Untag {
+        tag: String,
+        ids: Vec<PersonID>,
+        #[serde(default)]
+        at: Option<DateTime<Utc>>,
+    },
+    /// An organization as it was once added or edited, see `JsonFile::update_organization`
+    Organization {
+        organization: Organization,
+        at: DateTime<Utc>,
+    },
+    #[serde(rename = "delete_organization")]
+    DeleteOrganization {
+        id: OrganizationID,
+        at: DateTime<Utc>,
+    },
+    /// Bulk moves between organizations, see `JsonFile::move_to`
+    Move {
+        ids: Vec<PersonID>,
+        organization: Option<OrganizationID>,
+        department: Option<String>,
+        at: DateTime<Utc>,
+    },
+}
+
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    len: usize,
+}
+
+/// The journal belonging to a data file: `files/mock.json` -> `files/mock.json.journal`
+pub fn journal_path_for(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".journal");
+    PathBuf::from(name)
+}
+
+impl Journal {
+    /// Open the journal at `path`. A missing journal is treated as an empty one.
+    pub fn open(path: &Path) -> Result<Self> {
+        let len = match File::open(path) {
+            Ok(file) => io::BufReader::new(file).lines().count(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+            Err(err) => {
+                return Err(Err::Io(err)).with_context(|| format!("Failed to open journal `{}`", path.display()))
+            }
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            len,
+        })
+    }
+
+    /// Number of entries not yet folded into the snapshot
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Append an entry and make sure it hit the disk before returning
+    pub fn append(&mut self, entry: &JournalEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        if let Some(key) = crypto::key() {
+            line = hex::encode(key.seal(&line)?).into_bytes();
+        }
+        line.push(b'\n');
+        let mut file = File::options()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(Err::Io)
+            .with_context(|| format!("Failed to open journal `{}`", self.path.display()))?;
+        // A single write_all keeps the line contiguous, O_APPEND takes care of the offset
+        file.write_all(&line)
+            .and_then(|_| file.sync_data())
+            .map_err(Err::Io)
+            .with_context(|| format!("Failed to append to journal `{}`", self.path.display()))?;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Re-apply every journal entry on top of `json_file`, returning how many were applied.
+    /// Replaying is idempotent, so a journal that was already folded into the snapshot is harmless.
+    pub fn replay(&self, json_file: &mut JsonFile) -> Result<usize> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => {
+                return Err(Err::Io(err)).with_context(|| format!("Failed to open journal `{}`", self.path.display()))
+            }
+        };
+        let lines = io::BufReader::new(file)
+            .lines()
+            .collect::<io::Result<Vec<String>>>()
+            .map_err(Err::Io)
+            .with_context(|| format!("Failed to read journal `{}`", self.path.display()))?;
+        let mut applied = 0;
+        for (lineno, line) in lines.iter().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let line = self
+                .decode(line, crypto::key().as_deref())
+                .with_context(|| format!("Corrupt journal `{}` at line {}", self.path.display(), lineno + 1))?;
+            let entry = match serde_json::from_slice::<JournalEntry>(&line) {
+                Ok(entry) => entry,
+                // A crash in the middle of an append can only ever tear the last line.
+                // Only plain lines get here, an encrypted one that doesn't open is never taken for a torn write.
+                Err(err) if lineno + 1 == lines.len() => {
+                    log::warn!("Ignoring torn last line of journal `{}`: {err}", self.path.display());
+                    break;
+                }
+                Err(err) => {
+                    return Err(Err::Json(err))
+                        .with_context(|| format!("Corrupt journal `{}` at line {}", self.path.display(), lineno + 1))
+                }
+            };
+            json_file.apply(entry)?;
+            applied += 1;
+        }
+        log::info!("Replayed {applied} journal entries from `{}`", self.path.display());
+        Ok(applied)
+    }
+
+    /// A plain JSON line as is, or the entry sealed in an encrypted line.
+    /// An encrypted line that fails to open is an error, dropping it could silently lose a mutation.
+    fn decode(&self, line: &str, key: Option<&crypto::Key>) -> Result<Vec<u8>> {
+        if line.starts_with('{') {
+            return Ok(line.as_bytes().to_vec());
+        }
+        let sealed = hex::decode(line.trim())
+            .map_err(|e| Err::Corrupt(e.to_string()))
+            .with_context(|| "Neither a JSON nor an encrypted line")?;
+        let key = key
+            .ok_or_else(|| Err::Config("No key".into()))
+            .with_context(|| format!("Journal `{}` is encrypted, configure a key", self.path.display()))?;
+        key.open(&sealed).with_context(|| "Failed to decrypt line")
+    }
+
+    /// Fold the journal into a fresh snapshot at `snapshot` and empty the journal.
+    /// The snapshot is written first, so a crash in between only leaves an idempotent journal behind.
+    pub fn compact(&mut self, snapshot: &Path, json_file: &JsonFile) -> Result<()> {
+        write_json(snapshot, json_file)?;
+        match std::fs::remove_file(&self.path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(Err::Io(err))
+                    .with_context(|| format!("Failed to truncate journal `{}`", self.path.display()))
+            }
+        }
+        log::info!("Compacted {} journal entries into `{}`", self.len, snapshot.display());
+        self.len = 0;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_journal_replay() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    let snapshot = dir.join("book.json");
+    let mut journal = Journal::open(&journal_path_for(&snapshot))?;
+
+    let mut live = JsonFile::default();
+    let id = live.add_to_phonebook(person!("Ada Lovelace", "39-44-5323523"))?;
+    journal.append(&JournalEntry::Add {
+        person: live.get_by_id(id).unwrap(),
+    })?;
+    live.update(id, person!("", "000"))?;
+    journal.append(&JournalEntry::Update {
+        id,
+        person: person!("", "000"),
+        revision: live.get_by_id(id).unwrap().revision,
+        at: live.get_by_id(id).unwrap().updated_at,
+    })?;
+    let gone = live.add_to_phonebook(person!("Dan Abramov", "12-43-234345"))?;
+    journal.append(&JournalEntry::Add {
+        person: live.get_by_id(gone).unwrap(),
+    })?;
+    live.delete(gone)?;
+    journal.append(&JournalEntry::Delete {
+        id: gone,
+        at: live.trash()[0].deleted_at,
+    })?;
+
+    let mut replayed = JsonFile::default();
+    assert_eq!(4, Journal::open(&journal_path_for(&snapshot))?.replay(&mut replayed)?);
+    assert_eq!(live.get_by_id(id), replayed.get_by_id(id));
+    assert_eq!(None, replayed.get_by_id(gone));
+    // Replaying over a book that already has the changes is harmless, revisions included
+    assert_eq!(4, Journal::open(&journal_path_for(&snapshot))?.replay(&mut replayed)?);
+    assert_eq!(live.get_by_id(id), replayed.get_by_id(id));
+    assert_eq!(2, replayed.get_by_id(id).unwrap().revision);
+    assert_eq!(live.trash(), replayed.trash());
+
+    journal.compact(&snapshot, &live)?;
+    assert!(journal.is_empty());
+    assert_eq!(live.get_by_id(id), crate::read_json(&snapshot)?.get_by_id(id));
+    Ok(())
+}
+
+#[test]
+fn test_journal_only_tolerates_torn_plain_lines() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    let snapshot = dir.join("book.json");
+    let path = journal_path_for(&snapshot);
+    let mut journal = Journal::open(&path)?;
+    journal.append(&JournalEntry::Add {
+        person: person!("Ada Lovelace", "39-44-5323523"),
+    })?;
+    let line = std::fs::read_to_string(&path)?;
+    std::fs::write(&path, format!("{line}{}", &line[..line.len() / 2]))?;
+    assert_eq!(1, Journal::open(&path)?.replay(&mut JsonFile::default())?);
+
+    let key = crypto::Key::File([7; 32]);
+    let sealed = hex::encode(key.seal(line.trim().as_bytes())?);
+    assert_eq!(line.trim().as_bytes(), journal.decode(&sealed, Some(&key))?);
+    // Neither a flipped bit nor a torn encrypted line is dropped like a torn plain one
+    let mut flipped = hex::decode(&sealed)?;
+    *flipped.last_mut().unwrap() ^= 1;
+    assert!(journal.decode(&hex::encode(flipped), Some(&key)).is_err());
+    assert!(journal.decode(&sealed[..sealed.len() / 2], Some(&key)).is_err());
+    assert!(journal.decode(&sealed, None).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_journal_replays_entries_with_a_single_number() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let path = journal_path_for(&tmp.path().join("book.json"));
+    // As journalled before entries had several numbers
+    std::fs::write(
+        &path,
+        concat!(
+            r#"{"add":{"person":{"id":1,"name":"Ada Lovelace","number":"39-44-5323523"}}}"#,
+            "\n",
+            r#"{"add":{"person":{"id":2,"name":"Dan Abramov","number":"12-43-234345"}}}"#,
+            "\n",
+            r#"{"update":{"id":2,"person":{"name":"","number":"000"}}}"#,
+            "\n",
+        ),
+    )?;
+    let mut replayed = JsonFile::default();
+    assert_eq!(3, Journal::open(&path)?.replay(&mut replayed)?);
+    let number = |id| {
+        replayed
+            .get_by_id(id)
+            .and_then(|p| p.primary_number().map(|n| n.number.clone()))
+    };
+    assert_eq!(Some("39-44-5323523".into()), number(1));
+    assert_eq!(Some("000".into()), number(2));
+    assert_eq!(1, replayed.get_by_id(2).unwrap().numbers.len());
+    Ok(())
+}
+
+#[test]
+fn test_journal_keeps_the_lists_an_update_cleared() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let path = journal_path_for(&tmp.path().join("book.json"));
+    let mut journal = Journal::open(&path)?;
+    let mut live = JsonFile::default();
+    let id = live.add_to_phonebook(Person {
+        tags: vec!["vendor".into()],
+        ..person!("Ada Lovelace", "39-44-5323523")
+    })?;
+    journal.append(&JournalEntry::Add {
+        person: live.get_by_id(id).unwrap(),
+    })?;
+    let cleared: Person = serde_json::from_str(r#"{"name": "", "tags": []}"#)?;
+    live.update(id, cleared.clone())?;
+    journal.append(&JournalEntry::Update {
+        id,
+        person: cleared,
+        revision: live.get_by_id(id).unwrap().revision,
+        at: live.get_by_id(id).unwrap().updated_at,
+    })?;
+    assert!(live.get_by_id(id).unwrap().tags.is_empty());
+
+    let mut replayed = JsonFile::default();
+    assert_eq!(2, Journal::open(&path)?.replay(&mut replayed)?);
+    assert_eq!(live.get_by_id(id), replayed.get_by_id(id));
+    Ok(())
+}
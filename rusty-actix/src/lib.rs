@@ -1,6 +1,7 @@
 use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Display;
 use std::fs::File;
 use std::io;
@@ -10,6 +11,36 @@ use std::sync::Arc;
 use parking_lot::RwLock;
 #[macro_use]
 mod macros;
+pub mod backup;
+pub mod config;
+pub mod contact;
+pub use contact::{Address, Email, Label, Website};
+pub mod crypto;
+pub mod fields;
+pub use fields::{FieldDef, FieldType, FieldValue, Schema};
+pub mod format;
+pub use backup::{backup_dir_for, BackupInfo, Backups};
+pub mod journal;
+pub mod library;
+pub use library::{Book, BookInfo, Library, DEFAULT_BOOK};
+pub mod lock;
+pub mod migrate;
+pub mod name;
+pub mod organization;
+pub use organization::{Organization, OrganizationID};
+pub mod photo;
+pub use photo::{photos_dir_for, Photos};
+pub mod relation;
+pub use journal::{journal_path_for, Journal, JournalEntry};
+pub use name::Name;
+pub use relation::{Expanded, Related, Relation, RelationKind};
+pub mod store;
+pub mod tag;
+pub use store::{JsonFileStore, MemoryStore, PhonebookStore, Reload, SqliteStore};
+pub use tag::Group;
+pub mod watch;
+pub mod writer;
+pub use writer::Writer;
 // If interested in the gory details of anyhow::Result<T, E = anyhow::Error>
 // https://rust-lang.github.io/rfcs/0213-defaulted-type-params.html
 // https://rust-lang.github.io/rfcs/0213-defaulted-type-params.html#type-parameters-with-defaults
@@ -21,31 +52,544 @@ pub enum Err {
     Io(#[from] io::Error),
     #[error("JSON ERROR")]
     Json(#[from] serde_json::error::Error),
+    #[error("SQLITE ERROR")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("CONFIG ERROR: {0}")]
+    Config(String),
     #[error("Phonebook entry doesn't match expectation")]
     PhonebookEntry(String),
+    #[error("NOT FOUND: {0}")]
+    NotFound(String),
+    #[error("CORRUPT DATA: {0}")]
+    Corrupt(String),
+    #[error("TIMED OUT WAITING TO READ: {0}")]
+    ReadLockTimeout(String),
+    #[error("TIMED OUT WAITING TO WRITE: {0}")]
+    WriteLockTimeout(String),
 }
 
 // impl actix_web::error::ResponseError for Err {}
 
 pub type PersonID = u128;
+/// How many days a purge leaves deleted entries in the trash when nothing else is configured
+pub const DEFAULT_TRASH_RETENTION_DAYS: u32 = 30;
 // TODO : How is PartialEq and PartialOrd implemented for Person struct?
 #[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, PartialOrd)]
+#[serde(from = "PersonFields", into = "PersonFields")]
 pub struct Person {
-    #[serde(default)]
     pub id: PersonID,
-    pub name: String,
-    pub number: String,
+    /// Requests may also send it as free text, see `name::deserialize`
+    pub name: Name,
+    /// In the order they were given, the primary one isn't necessarily first
+    pub numbers: Vec<PhoneNumber>,
+    /// The single `number` of entries journalled before they had several, and of clients that still send one.
+    /// Never written back, `normalize` turns it into the primary number.
+    legacy_number: Option<String>,
+    pub emails: Vec<Email>,
+    pub addresses: Vec<Address>,
+    pub urls: Vec<Website>,
+    /// Sorted and in their canonical form, see `tag::normalize`
+    pub tags: Vec<String>,
+    /// Custom fields by name, see `fields::Schema`
+    pub fields: BTreeMap<String, FieldValue>,
+    /// Sorted by kind then id, see `relation`
+    pub relations: Vec<Relation>,
+    /// The organization the person belongs to, see `organization`
+    pub organization: Option<OrganizationID>,
+    /// One of the departments of `organization`
+    pub department: Option<String>,
+    /// The lists an update sent empty, which `merge` clears instead of leaving alone
+    cleared: Vec<List>,
+    // The metadata below is kept by the phonebook itself, whatever a request sends is ignored.
+    // Entries added before it existed have none.
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+    /// 1 once added, goes up by one with every change to the entry
+    pub revision: u64,
+    /// Only set on entries in the trash
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+/// The lists of a `Person` that an update can empty by sending `[]`, or `{}` for `fields`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
+enum List {
+    Emails,
+    Addresses,
+    Urls,
+    Tags,
+    Fields,
+    Relations,
+}
+
+/// `Person` as it is sent and saved, where a list left out and one sent empty differ
+#[derive(Serialize, Deserialize)]
+struct PersonFields {
+    #[serde(default)]
+    id: PersonID,
+    #[serde(deserialize_with = "name::deserialize")]
+    name: Name,
+    #[serde(default)]
+    numbers: Vec<PhoneNumber>,
+    #[serde(default, rename = "number", skip_serializing)]
+    legacy_number: Option<String>,
+    // Omitted while empty, so entries saved before these existed still match their checksum
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    emails: Option<Vec<Email>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    addresses: Option<Vec<Address>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    urls: Option<Vec<Website>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tags: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fields: Option<BTreeMap<String, FieldValue>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    relations: Option<Vec<Relation>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    organization: Option<OrganizationID>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    department: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    updated_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    revision: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+// For deserializing
+impl From<PersonFields> for Person {
+    fn from(fields: PersonFields) -> Self {
+        let mut cleared = Vec::new();
+        let mut sent_empty = |list, empty: Option<bool>| {
+            if empty == Some(true) {
+                cleared.push(list);
+            }
+        };
+        sent_empty(List::Emails, fields.emails.as_ref().map(Vec::is_empty));
+        sent_empty(List::Addresses, fields.addresses.as_ref().map(Vec::is_empty));
+        sent_empty(List::Urls, fields.urls.as_ref().map(Vec::is_empty));
+        sent_empty(List::Tags, fields.tags.as_ref().map(Vec::is_empty));
+        sent_empty(List::Fields, fields.fields.as_ref().map(BTreeMap::is_empty));
+        sent_empty(List::Relations, fields.relations.as_ref().map(Vec::is_empty));
+        Self {
+            id: fields.id,
+            name: fields.name,
+            numbers: fields.numbers,
+            legacy_number: fields.legacy_number,
+            emails: fields.emails.unwrap_or_default(),
+            addresses: fields.addresses.unwrap_or_default(),
+            urls: fields.urls.unwrap_or_default(),
+            tags: fields.tags.unwrap_or_default(),
+            fields: fields.fields.unwrap_or_default(),
+            relations: fields.relations.unwrap_or_default(),
+            organization: fields.organization,
+            department: fields.department,
+            cleared,
+            created_at: fields.created_at,
+            updated_at: fields.updated_at,
+            revision: fields.revision,
+            deleted_at: fields.deleted_at,
+        }
+    }
+}
+// For serializing, so the journal keeps the lists an update cleared
+impl From<Person> for PersonFields {
+    fn from(p: Person) -> Self {
+        let given = |list, empty: bool| !empty || p.cleared.contains(&list);
+        Self {
+            id: p.id,
+            name: p.name,
+            numbers: p.numbers,
+            legacy_number: None,
+            emails: given(List::Emails, p.emails.is_empty()).then_some(p.emails),
+            addresses: given(List::Addresses, p.addresses.is_empty()).then_some(p.addresses),
+            urls: given(List::Urls, p.urls.is_empty()).then_some(p.urls),
+            tags: given(List::Tags, p.tags.is_empty()).then_some(p.tags),
+            fields: given(List::Fields, p.fields.is_empty()).then_some(p.fields),
+            relations: given(List::Relations, p.relations.is_empty()).then_some(p.relations),
+            organization: p.organization,
+            department: p.department,
+            created_at: p.created_at,
+            updated_at: p.updated_at,
+            revision: p.revision,
+            deleted_at: p.deleted_at,
+        }
+    }
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
 }
 impl Display for Person {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let Person { name, id, number } = self;
-        write!(f, "{{ name: {name} id: {id} number: {number} }})")
+        fn list<T: Display>(items: &[T]) -> String {
+            items.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+        }
+        let Person {
+            name,
+            id,
+            numbers,
+            emails,
+            addresses,
+            urls,
+            tags,
+            fields,
+            relations,
+            organization,
+            department,
+            ..
+        } = self;
+        write!(f, "{{ name: {name} id: {id} numbers: [{}]", list(numbers))?;
+        if !emails.is_empty() {
+            write!(f, " emails: [{}]", list(emails))?;
+        }
+        if !addresses.is_empty() {
+            write!(f, " addresses: [{}]", list(addresses))?;
+        }
+        if !urls.is_empty() {
+            write!(f, " urls: [{}]", list(urls))?;
+        }
+        if !tags.is_empty() {
+            write!(f, " tags: [{}]", tags.join(", "))?;
+        }
+        if !fields.is_empty() {
+            let fields: Vec<String> = fields.iter().map(|(name, value)| format!("{name}: {value}")).collect();
+            write!(f, " fields: {{{}}}", fields.join(", "))?;
+        }
+        if !relations.is_empty() {
+            write!(f, " relations: [{}]", list(relations))?;
+        }
+        if let Some(organization) = organization {
+            write!(f, " organization: #{organization}")?;
+        }
+        if let Some(department) = department {
+            write!(f, " department: {department}")?;
+        }
+        write!(f, " }})")
+    }
+}
+
+impl Person {
+    /// Take over the fields of `update` that were given, which is how `update` edits an entry.
+    /// Lists replace the current list as a whole, the id never changes. An empty list leaves the current one alone
+    /// unless the update was sent with it as `[]`, which clears it. That doesn't go for `numbers`, journals written
+    /// before always carry them.
+    pub fn merge(&mut self, update: Person) {
+        let given = |list, empty: bool| !empty || update.cleared.contains(&list);
+        if !update.name.is_empty() {
+            self.name = update.name;
+        }
+        // A legacy `number` stands for all of them, `normalize` rejects it alongside `numbers`
+        if update.legacy_number.is_some() {
+            self.numbers.clear();
+            self.legacy_number = update.legacy_number;
+        }
+        if !update.numbers.is_empty() {
+            self.numbers = update.numbers;
+        }
+        if given(List::Emails, update.emails.is_empty()) {
+            self.emails = update.emails;
+        }
+        if given(List::Addresses, update.addresses.is_empty()) {
+            self.addresses = update.addresses;
+        }
+        if given(List::Urls, update.urls.is_empty()) {
+            self.urls = update.urls;
+        }
+        if given(List::Tags, update.tags.is_empty()) {
+            self.tags = update.tags;
+        }
+        if given(List::Fields, update.fields.is_empty()) {
+            self.fields = update.fields;
+        }
+        if given(List::Relations, update.relations.is_empty()) {
+            self.relations = update.relations;
+        }
+        // A new organization comes with its own department, or none
+        if update.organization.is_some() {
+            self.organization = update.organization;
+            self.department = update.department;
+        } else if update.department.is_some() {
+            self.department = update.department;
+        }
+    }
+
+    /// Stamp a newly added entry, replacing any metadata it came with
+    pub fn stamp_created(&mut self, at: DateTime<Utc>) {
+        self.created_at = Some(at);
+        self.updated_at = Some(at);
+        self.revision = 1;
+        self.deleted_at = None;
+    }
+
+    /// Record a change to the entry
+    pub fn stamp_updated(&mut self, at: DateTime<Utc>) {
+        self.updated_at = Some(at);
+        self.revision += 1;
+    }
+
+    /// Whether the entry changed after `since`, entries without timestamps never did
+    pub fn modified_since(&self, since: DateTime<Utc>) -> bool {
+        self.updated_at.is_some_and(|at| at > since)
+    }
+
+    /// Whether `query` occurs in the name, a number, an email, an address or a url, ignoring case.
+    /// A query of nothing but digits and punctuation matches numbers however they were formatted.
+    pub fn matches(&self, query: &str) -> bool {
+        let query = query.trim().to_lowercase();
+        let found = |text: &str| text.to_lowercase().contains(&query);
+        let digits: String = query.chars().filter(char::is_ascii_digit).collect();
+        let numeric = !digits.is_empty() && query.chars().all(|c| c.is_ascii_digit() || " +-()./".contains(c));
+        found(&self.name.to_string())
+            || self
+                .numbers
+                .iter()
+                .any(|n| found(&n.number) || (numeric && n.digits().contains(&digits)))
+            || self.emails.iter().any(|e| found(&e.address))
+            || self.addresses.iter().any(|a| {
+                [&a.street, &a.city, &a.region, &a.postal_code, &a.country]
+                    .into_iter()
+                    .flatten()
+                    .any(|part| found(part))
+            })
+            || self.urls.iter().any(|w| found(&w.url))
+    }
+
+    /// The number flagged as primary
+    pub fn primary_number(&self) -> Option<&PhoneNumber> {
+        self.numbers.iter().find(|n| n.primary)
+    }
+
+    /// Validate the entry, uniqueness of the name is up to the duplicate checks.
+    /// Requires a given or family name. Rejects empty and repeated numbers as well as more than one primary number, without an explicit primary
+    /// number the first one becomes primary. Emails, addresses and urls are trimmed and checked as well, tags are
+    /// brought into their canonical form, sorted and deduplicated. Relationships are sorted and deduplicated,
+    /// whether they point at existing entries is up to `relation::check`.
+    pub fn normalize(&mut self) -> Result<()> {
+        self.upgrade_number()?;
+        // Only `merge` cares which lists were sent empty, a saved entry has no need to know
+        self.cleared.clear();
+        self.name.normalize();
+        if !self.name.is_valid() {
+            log::warn!("Phonebook entry without a name");
+            return Err(Err::PhonebookEntry("Name missing".into()))
+                .with_context(|| "Phonebook entry should have a given or a family name");
+        }
+        let mut seen = std::collections::HashSet::with_capacity(self.numbers.len());
+        for number in &self.numbers {
+            if number.digits().is_empty() {
+                return Err(Err::PhonebookEntry("Empty number".into()))
+                    .with_context(|| format!("{} has a {} number without any digits", self.name, number.label));
+            }
+            if !seen.insert((number.digits(), number.extension.as_deref().unwrap_or_default())) {
+                return Err(Err::PhonebookEntry("Duplicate number".into()))
+                    .with_context(|| format!("{} lists {} more than once", self.name, number.number));
+            }
+        }
+        match self.numbers.iter().filter(|n| n.primary).count() {
+            0 => {
+                if let Some(first) = self.numbers.first_mut() {
+                    first.primary = true;
+                }
+            }
+            1 => {}
+            _ => {
+                return Err(Err::PhonebookEntry("More than one primary number".into()))
+                    .with_context(|| format!("{} has more than one primary number", self.name))
+            }
+        }
+        let mut seen = std::collections::HashSet::with_capacity(self.emails.len());
+        for email in &mut self.emails {
+            email.normalize()?;
+            if !seen.insert(email.address.to_lowercase()) {
+                return Err(Err::PhonebookEntry("Duplicate email".into()))
+                    .with_context(|| format!("{} lists {} more than once", self.name, email.address));
+            }
+        }
+        for address in &mut self.addresses {
+            address.normalize()?;
+        }
+        let mut seen = std::collections::HashSet::with_capacity(self.urls.len());
+        for website in &mut self.urls {
+            website.normalize()?;
+            if !seen.insert(website.url.clone()) {
+                return Err(Err::PhonebookEntry("Duplicate url".into()))
+                    .with_context(|| format!("{} lists {} more than once", self.name, website.url));
+            }
+        }
+        self.tags = self.tags.iter().map(|t| tag::normalize(t)).collect::<Result<_>>()?;
+        self.tags.sort_unstable();
+        self.tags.dedup();
+        self.relations.sort_unstable();
+        self.relations.dedup();
+        Ok(())
+    }
+    /// Turn a legacy `number` into the only, primary number, the way `migrate::v1_to_v2` does for saved files
+    fn upgrade_number(&mut self) -> Result<()> {
+        let Some(number) = self.legacy_number.take() else {
+            return Ok(());
+        };
+        if !self.numbers.is_empty() {
+            return Err(Err::PhonebookEntry("Both number and numbers".into()))
+                .with_context(|| format!("{} has both a number and numbers, send numbers only", self.name));
+        }
+        if !number.trim().is_empty() {
+            self.numbers.push(PhoneNumber {
+                number: number.trim().into(),
+                primary: true,
+                ..Default::default()
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, PartialOrd)]
+pub struct PhoneNumber {
+    #[serde(default)]
+    pub label: PhoneLabel,
+    /// As entered, e.g. `+44 20 7946 0958`
+    pub number: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extension: Option<String>,
+    #[serde(default)]
+    pub primary: bool,
+}
+
+impl PhoneNumber {
+    /// Only the digits, `+44 (20) 7946-0958` and `+442079460958` are the same number
+    pub fn digits(&self) -> String {
+        self.number.chars().filter(char::is_ascii_digit).collect()
+    }
+}
+
+impl Display for PhoneNumber {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.label, self.number)?;
+        if let Some(extension) = &self.extension {
+            write!(f, " ext. {extension}")?;
+        }
+        if self.primary {
+            write!(f, " (primary)")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum PhoneLabel {
+    Mobile,
+    Office,
+    Home,
+    Fax,
+    #[default]
+    Other,
+}
+
+impl PhoneLabel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PhoneLabel::Mobile => "mobile",
+            PhoneLabel::Office => "office",
+            PhoneLabel::Home => "home",
+            PhoneLabel::Fax => "fax",
+            PhoneLabel::Other => "other",
+        }
+    }
+}
+
+impl std::str::FromStr for PhoneLabel {
+    type Err = Err;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "mobile" => Ok(PhoneLabel::Mobile),
+            "office" => Ok(PhoneLabel::Office),
+            "home" => Ok(PhoneLabel::Home),
+            "fax" => Ok(PhoneLabel::Fax),
+            "other" => Ok(PhoneLabel::Other),
+            _ => Err(Err::PhonebookEntry(format!("Unknown label `{s}`"))),
+        }
+    }
+}
+
+impl Display for PhoneLabel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct JsonFile {
+    /// Format version of the file, see `phonebook::migrate`
+    #[serde(default)]
+    version: u32,
+    /// As read from the file, `write_json` stores a fresh one with every save
+    #[serde(default, skip_serializing)]
+    checksum: Option<String>,
     phonebook: Vec<Person>,
+    /// Deleted entries in the order they were deleted, until they are restored or purged
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    trash: Vec<Person>,
+    #[serde(default, skip_serializing_if = "Schema::is_empty")]
+    schema: Schema,
+    /// Sorted by id
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    organizations: Vec<Organization>,
+}
+
+/// What `write_json` actually writes, a `JsonFile` along with the checksum of its entries
+#[derive(Serialize)]
+struct StoredJsonFile<'a> {
+    version: u32,
+    checksum: String,
+    phonebook: &'a [Person],
+    #[serde(skip_serializing_if = "<[Person]>::is_empty")]
+    trash: &'a [Person],
+    #[serde(skip_serializing_if = "Schema::is_empty")]
+    schema: &'a Schema,
+    #[serde(skip_serializing_if = "<[Organization]>::is_empty")]
+    organizations: &'a [Organization],
+}
+
+/// `sha256:<hex digest>` of the compact serialization of the entries, followed by that of the trash, the schema and the
+/// organizations unless they are empty. Computed from the parsed entries rather than the raw bytes, so it doesn't depend on formatting.
+fn checksum_of(json_file: &JsonFile) -> Result<String> {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    hasher.update(serde_json::to_vec(&json_file.phonebook).map_err(Err::Json)?);
+    if !json_file.trash.is_empty() {
+        hasher.update(serde_json::to_vec(&json_file.trash).map_err(Err::Json)?);
+    }
+    if !json_file.schema.is_empty() {
+        hasher.update(serde_json::to_vec(&json_file.schema).map_err(Err::Json)?);
+    }
+    if !json_file.organizations.is_empty() {
+        hasher.update(serde_json::to_vec(&json_file.organizations).map_err(Err::Json)?);
+    }
+    Ok(format!("sha256:{}", hex::encode(hasher.finalize())))
+}
+
+impl Default for JsonFile {
+    fn default() -> Self {
+        Self::from(vec![])
+    }
+}
+
+impl From<Vec<Person>> for JsonFile {
+    fn from(phonebook: Vec<Person>) -> Self {
+        Self {
+            version: migrate::CURRENT_VERSION,
+            checksum: None,
+            phonebook,
+            trash: vec![],
+            schema: Schema::default(),
+            organizations: vec![],
+        }
+    }
 }
 
 // An alternative to JsonFile
@@ -78,58 +622,134 @@ impl From<Phonebook> for Vec<Person> {
         pb.0.into_values().collect::<Vec<Person>>()
     }
 }
-/// Read a JsonFile and write it to a Path, while locking the file
+/// Write a JsonFile to a Path atomically, encrypted if a key was set with `crypto::set_key`.
+/// The contents are first written to a sibling temp file which is fsynced and then renamed over `path`.
+/// Readers will therefore always observe either the previous file or the new one, never a half written one.
+/// It is written in the format set with `format::set_format`, pretty-printed JSON unless configured otherwise.
 pub fn write_json(path: &Path, json_file: &JsonFile) -> Result<()> {
-    // You can use this lib for locking https://docs.rs/fs2/latest/fs2/trait.FileExt.html
-    // and this for ensuring everything got written https://doc.rust-lang.org/std/io/trait.Write.html#method.write_all
+    write_json_as(path, json_file, format::format())
+}
+
+/// Like `write_json`, in `format` rather than the configured one
+pub fn write_json_as(path: &Path, json_file: &JsonFile, format: format::Format) -> Result<()> {
+    let stored = StoredJsonFile {
+        version: json_file.version,
+        checksum: checksum_of(json_file)?,
+        phonebook: &json_file.phonebook,
+        trash: &json_file.trash,
+        schema: &json_file.schema,
+        organizations: &json_file.organizations,
+    };
+    let key = crypto::key();
+    if format.is_plain_json() && key.is_none() {
+        // The common case is streamed straight into the file
+        return write_atomic(path, |wrt| write_and_sync(wrt, &stored));
+    }
+    let mut bytes = format.encode(&stored)?;
+    if let Some(key) = key {
+        bytes = key.seal(&bytes)?;
+    }
+    write_atomic(path, |wrt| write_bytes_and_sync(wrt, &bytes))
+}
+
+/// Replace `path` with whatever `write` puts into the temp file, see `write_json`.
+/// Holds an exclusive lock on `path` throughout, so readers never race the rename and writers never share the temp file.
+pub(crate) fn write_atomic(path: &Path, write: impl FnOnce(&File) -> Result<()>) -> Result<()> {
+    let _lock = lock::exclusive(path)?;
+    let tmp_path = tmp_path_for(path);
     let wrt = File::options()
         .write(true)
-        // Truncate will delete the file if it exists which may not be optimal 
-        // We can instead write to a temporary file, then flush the temporary file
-        // to the original file path
-        // In our case, since the file contents live in program memory, it makes 
-        // no difference to the end user.
-        // Truncation is especially helpful when delete is invoked, as post requests 
-        // always add data and therefore don't need truncation
-        .truncate(true) 
-        .open(path)
-        .map_err(|err| Err::Io(err))
-        .with_context(|| format!("Writing json failed at `{}`", path.display()))?;
-    // This library provides whole-file locks in both shared (read) and exclusive (read-write) varieties.
-    // Uncomment this line to see the file being deleted in the interim
+        .create(true)
+        // Truncating the temp file is harmless, the original file is untouched until the rename below
+        .truncate(true)
+        .open(&tmp_path)
+        .map_err(Err::Io)
+        .with_context(|| format!("Writing json failed at `{}`", tmp_path.display()))?;
+    // Uncomment this line to see that the original file stays intact in the interim
     // std::thread::sleep(std::time::Duration::from_secs(40));
-    use fs2::FileExt;
-    wrt.try_lock_exclusive()
-        .map_err(|err| Err::Io(err))
-        .with_context(|| "Error on locking file")?;
+    let result = write(&wrt).and_then(|_| {
+        // rename(2) is atomic on POSIX filesystems as long as both paths live on the same filesystem,
+        // which is why the temp file is a sibling of `path`
+        std::fs::rename(&tmp_path, path)
+            .map_err(Err::Io)
+            .with_context(|| format!("Failed to move `{}` to `{}`", tmp_path.display(), path.display()))
+    });
+    if result.is_err() {
+        // Best effort cleanup, the original file is still in place
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result?;
+    // The rename itself is only durable once the directory entry has been flushed
+    sync_dir(path)
+}
+
+/// Serialize the JsonFile into `wrt` and make sure the bytes have hit the disk
+fn write_and_sync(wrt: &File, json_file: &StoredJsonFile) -> Result<()> {
     // https://stackoverflow.com/questions/57232515/why-does-serde-jsonto-writer-not-require-its-argument-to-be-mut
     // https://doc.rust-lang.org/std/io/trait.Write.html#implementors
     // io::Write takes a &mut &File here
     // the mutablilty of a binding and the mutability of the bound value are not necessarily the same.
-    // json_file.sort();
-    serde_json::to_writer_pretty(&wrt, &json_file)?;
-    wrt.unlock()
-        .map_err(|err| Err::Io(err))
-        .with_context(|| "Error on unlocking locking file")?;
-    Ok(())
+    let mut buf = io::BufWriter::new(wrt);
+    serde_json::to_writer_pretty(&mut buf, json_file)?;
+    io::Write::flush(&mut buf)
+        .map_err(Err::Io)
+        .with_context(|| "Error on flushing json")?;
+    wrt.sync_all().map_err(Err::Io).with_context(|| "Error on fsync")
+}
+
+/// Write `bytes` into `wrt` and make sure they have hit the disk
+pub(crate) fn write_bytes_and_sync(mut wrt: &File, bytes: &[u8]) -> Result<()> {
+    io::Write::write_all(&mut wrt, bytes)
+        .map_err(Err::Io)
+        .with_context(|| "Error on writing")?;
+    wrt.sync_all().map_err(Err::Io).with_context(|| "Error on fsync")
+}
+
+/// The temp file used by `write_json`: `files/mock.json` -> `files/.mock.json.tmp`
+fn tmp_path_for(path: &Path) -> std::path::PathBuf {
+    let file_name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    path.with_file_name(format!(".{file_name}.tmp"))
+}
+
+/// fsync the directory containing `path` so that a rename into it survives a crash
+fn sync_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)
+        .and_then(|d| d.sync_all())
+        .map_err(Err::Io)
+        .with_context(|| format!("Error on syncing directory `{}`", dir.display()))
 }
 // 'static lifetime is fine here since our JsonFile handle and path will remain the same for the entirety of the program
 // however, TOOD: explore alternatives
 pub async fn async_write_json(p: &'static Path, j: Arc<RwLock<JsonFile>>) -> Result<()> {
     let async_writer = tokio::task::spawn_blocking(move || {
         let guard = j.read(); // .expect("Mutex should be unlocked before trying to lock again");
-        write_json(&*p, &*guard)
+        write_json(p, &guard)
     });
     async_writer.await?
 }
 
+/// Read the phonebook at `path` and verify its checksum, see `read_json_unchecked`
 pub fn read_json(path: &Path) -> Result<JsonFile> {
+    let json_file = read_json_unchecked(path)?;
+    json_file
+        .verify_checksum()
+        .with_context(|| format!("`{}` failed its integrity check", path.display()))?;
+    Ok(json_file)
+}
+
+/// Read the phonebook at `path`, decrypting and migrating it as needed, without verifying its checksum.
+/// Meant for files that were deliberately edited by hand, which leaves their checksum stale.
+pub fn read_json_unchecked(path: &Path) -> Result<JsonFile> {
+    // Held until parsing is done, the map below must not see a writer's changes
+    let _lock = lock::shared(path)?;
     let rdr = File::options()
-        // TODO: Check if write access is required 
-        .write(true)
         .read(true)
         .open(path)
-        .map_err(|err| Err::Io(err))
+        .map_err(Err::Io)
         .with_context(|| format!("Failed to read `{}`", path.display()))?;
     // The content of the IO stream is deserialized directly from the stream without being buffered in memory by serde_json.
     // let phonebook = serde_json::from_reader::<File, JsonValue>(rdr)?;
@@ -139,15 +759,56 @@ pub fn read_json(path: &Path) -> Result<JsonFile> {
     // let phonebook = serde_json::from_reader::<BufReader<File>, JsonValue>(buf_rdr)?;
     // Apparently reading the entire file into memory is the fastest way to deserialize i.e. `from_slice` and `from_str` methods
     // are faster than the `from_reader` method
-    let bytes = unsafe {
+    let mmap = unsafe {
         memmap2::Mmap::map(&rdr)
-            .map_err(|err| Err::Io(err))
+            .map_err(Err::Io)
             .with_context(|| "IO error at mmap")?
     };
+    // Encrypted or compressed files are decoded into memory, plain ones are parsed straight from the map
+    let plain = crypto::decode(&mmap, path)?;
+    let (format, bytes) = format::decode(&plain, path)?;
 
-    serde_json::from_slice::<JsonFile>(&bytes)
-        .map_err(|err| Err::Json(err))
-        .with_context(|| "json file parse error")
+    // A zero-byte (or whitespace only) file is an empty phonebook, not a corrupt one
+    if format.is_plain_json() && bytes.iter().all(u8::is_ascii_whitespace) {
+        log::info!("`{}` is empty, starting with an empty phonebook", path.display());
+        return Ok(JsonFile::default());
+    }
+    // Up to date files, i.e. nearly all of them, are deserialized straight away
+    #[derive(Deserialize)]
+    struct VersionProbe {
+        #[serde(default)]
+        version: u32,
+    }
+    if matches!(format::parse::<VersionProbe>(format.encoding, &bytes, path), Ok(probe) if probe.version == migrate::CURRENT_VERSION)
+    {
+        return format::parse::<JsonFile>(format.encoding, &bytes, path);
+    }
+    let mut doc = format::parse::<serde_json::Value>(format.encoding, &bytes, path)?;
+    let report = migrate::migrate(&mut doc).with_context(|| format!("Failed to migrate `{}`", path.display()))?;
+    log::info!("Migrated `{}` in memory, {report}", path.display());
+    let mut json_file = serde_json::from_value::<JsonFile>(doc)
+        .map_err(Err::Json)
+        .with_context(|| format!("json file parse error in `{}` after migration", path.display()))?;
+    // It was computed over the entries as they were before the migration
+    json_file.checksum = None;
+    Ok(json_file)
+}
+
+/// Like `read_json`, but a missing file is created as an empty phonebook (along with its directory)
+/// so that a fresh deployment can start without any data.
+pub fn read_or_create_json(path: &Path) -> Result<JsonFile> {
+    if path.exists() {
+        return read_json(path);
+    }
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)
+            .map_err(Err::Io)
+            .with_context(|| format!("Failed to create `{}`", dir.display()))?;
+    }
+    let json_file = JsonFile::default();
+    write_json(path, &json_file)?;
+    log::info!("Created an empty phonebook at `{}`", path.display());
+    Ok(json_file)
 }
 pub async fn async_read_json(path: &'static Path) -> Result<JsonFile> {
     let async_reader = tokio::task::spawn_blocking(|| read_json(path));
@@ -156,39 +817,151 @@ pub async fn async_read_json(path: &'static Path) -> Result<JsonFile> {
 
 #[allow(unused)]
 impl JsonFile {
-    /// Delete an entry
+    /// Move an entry to the trash, stamped with the time it was deleted, and unlink it from the entries relating to it
     pub fn delete(&mut self, id: PersonID) -> Result<()> {
-        // iter() returns references
-        // self.phonebook = self.phonebook.into_iter().filter(|p| p.id != id).collect();
-        let before = self.phonebook.len();
-        self.phonebook.retain(|p| p.id != id);
-        let after = self.phonebook.len();
-        if after == before {
+        self.delete_at(id, Utc::now())
+    }
+    pub(crate) fn delete_at(&mut self, id: PersonID, at: DateTime<Utc>) -> Result<()> {
+        let Some(index) = self.phonebook.iter().position(|p| p.id == id) else {
             log::info!("DELETE: id #{id} doesn't exist");
+            return Err(Err::NotFound(format!("id {id}")))
+                .with_context(|| format!("id {id} does not exist in phonebook"));
+        };
+        let mut person = self.phonebook.remove(index);
+        person.deleted_at = Some(at);
+        // An id is only ever in the trash once, with its latest deletion
+        self.trash.retain(|p| p.id != id);
+        self.trash.push(person);
+        self.unlink(id, at);
+        Ok(())
+    }
+    /// Drop the relationships pointing at `id`, returning how many entries had one
+    fn unlink(&mut self, id: PersonID, at: DateTime<Utc>) -> usize {
+        let mut changed = 0;
+        for person in &mut self.phonebook {
+            let count = person.relations.len();
+            person.relations.retain(|r| r.id != id);
+            if person.relations.len() != count {
+                person.stamp_updated(at);
+                changed += 1;
+            }
+        }
+        changed
+    }
+    /// Bring an entry back from the trash under its original id, without its relationships to entries deleted since
+    /// and its organization or department if that is gone.
+    /// Fails if its name was taken by an entry added in the meantime.
+    pub fn restore(&mut self, id: PersonID) -> Result<()> {
+        self.restore_at(id, Utc::now())
+    }
+    pub(crate) fn restore_at(&mut self, id: PersonID, at: DateTime<Utc>) -> Result<()> {
+        let index = self
+            .trash
+            .iter()
+            .position(|p| p.id == id)
+            .ok_or_else(|| Err::NotFound(format!("id {id}")))
+            .with_context(|| format!("id {id} is not in the trash"))?;
+        if self.get_by_id(id).is_some() {
+            return Err(Err::PhonebookEntry("Person ID already exists".into()))
+                .with_context(|| format!("Can't restore id {id}, another entry has taken it"));
+        }
+        let name = &self.trash[index].name;
+        if self.check_if_name_exists(name).0 {
+            return Err(Err::PhonebookEntry("Duplicate name".into()))
+                .with_context(|| format!("Can't restore {name}, an entry with that name was added since"));
         }
+        let mut person = self.trash[index].clone();
+        // The schema may have gained a required field since
+        self.schema.check(&mut person)?;
+        person.relations.retain(|r| self.get_by_id(r.id).is_some());
+        let organization = person.organization.and_then(|id| self.organization(id));
+        organization::forget_missing(&mut person, organization);
+        self.trash.remove(index);
+        person.deleted_at = None;
+        person.stamp_updated(at);
+        let index = self.phonebook.partition_point(|p| p.id < id);
+        self.phonebook.insert(index, person);
         Ok(())
     }
-    /// Edit a pre-existing phonebook entry
+    /// Permanently remove the entries deleted before `before`, returning how many
+    pub fn purge(&mut self, before: DateTime<Utc>) -> usize {
+        let count = self.trash.len();
+        self.trash.retain(|p| p.deleted_at.is_some_and(|at| at >= before));
+        count - self.trash.len()
+    }
+    /// Entries in the trash, in the order they were deleted
+    pub fn trash(&self) -> &[Person] {
+        &self.trash
+    }
+    /// The custom fields entries of this phonebook may carry
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+    /// Replace the schema after checking every entry against it. Fields the new schema leaves out are dropped
+    /// from the entries, the change is refused if an entry lacks a newly required field or holds a value of the wrong type.
+    pub fn set_schema(&mut self, schema: Schema) -> Result<()> {
+        self.set_schema_at(schema, Utc::now())
+    }
+    pub(crate) fn set_schema_at(&mut self, schema: Schema, at: DateTime<Utc>) -> Result<()> {
+        schema.validate()?;
+        let mut checked = Vec::with_capacity(self.phonebook.len());
+        for person in &self.phonebook {
+            let mut person = person.clone();
+            schema.drop_unknown(&mut person);
+            schema
+                .check(&mut person)
+                .with_context(|| format!("Entry {} doesn't fit the new schema", person.id))?;
+            checked.push(person);
+        }
+        for (person, mut checked) in self.phonebook.iter_mut().zip(checked) {
+            if checked != *person {
+                checked.stamp_updated(at);
+                *person = checked;
+            }
+        }
+        // Restoring checks entries in the trash against the schema of the day
+        for person in &mut self.trash {
+            schema.drop_unknown(person);
+        }
+        self.schema = schema;
+        Ok(())
+    }
+    /// Edit a pre-existing phonebook entry, an edit that changes nothing leaves its revision alone
     pub fn update(&mut self, id: PersonID, p: Person) -> Result<()> {
-        let entry = self
+        self.update_at(id, p, Utc::now())
+    }
+    /// `update`, with the time of the change given, for replaying the journal
+    pub(crate) fn update_at(&mut self, id: PersonID, p: Person, at: DateTime<Utc>) -> Result<()> {
+        let index = self
             .phonebook
-            .iter_mut()
-            .find(|person| person.id == id)
+            .iter()
+            .position(|person| person.id == id)
             .ok_or(Err::PhonebookEntry("id does not exist".into()))
             .with_context(|| {
                 log::info!("id: {id} does not exist in the phonebook");
                 "id does not exist in phonebook"
             })?;
 
-        let (nname, nnum) = (p.name, p.number);
-        // Check if they are not default values
-        if nname.len() > 0 {
-            entry.name = nname;
+        let mut updated = self.phonebook[index].clone();
+        updated.merge(p);
+        updated.normalize()?;
+        let (_, existing) = self.check_if_name_exists(&updated.name);
+        if existing.is_some_and(|other| other != index) {
+            log::warn!(
+                "Name {} already exists in the phonebook. Names must be unique",
+                &updated.name
+            );
+            return Err(Err::PhonebookEntry("Duplicate name".into()))
+                .with_context(|| format!("Person with name {} already exists, Names must be unique", updated.name));
         }
-        if nnum.len() > 0 {
-            entry.number = nnum;
+        self.schema.check(&mut updated)?;
+        relation::check(&updated, |id| Ok(self.get_by_id(id).is_some()))?;
+        let organization = updated.organization.and_then(|id| self.organization(id));
+        organization::check(&mut updated, organization)?;
+        if updated != self.phonebook[index] {
+            updated.stamp_updated(at);
+            self.phonebook[index] = updated;
         }
-        // Ignore ID change requests
         Ok(())
     }
     // TODO : Sort by key (id) and then perform a binary search for performance gains
@@ -212,19 +985,25 @@ impl JsonFile {
     ///
     /// Note: Our JsonFile, in memory is always sorted.
     pub fn get_by_id(&self, id: PersonID) -> Option<Person> {
-        if let Some(index) = self.phonebook.binary_search_by_key(&id, |p| p.id).ok() {
+        if let Ok(index) = self.phonebook.binary_search_by_key(&id, |p| p.id) {
             return Some(&self.phonebook[index]).cloned();
         }
         None
     }
 
     pub fn get_by_name(&self, name: &str) -> Option<Person> {
-        // Throwaway the error
-        let (p, index) = self.check_if_name_exists(name).ok()?;
-        if !p {return None;}
+        let (p, index) = self.check_if_name_exists(&Name::parse(name));
+        if !p {
+            return None;
+        }
         self.phonebook.get(index.unwrap()).cloned()
     }
 
+    /// All entries, sorted by id
+    pub fn persons(&self) -> &[Person] {
+        &self.phonebook
+    }
+
     pub fn print_phonebook(&self) {
         let entries = self.phonebook.iter();
         println!("❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯❯");
@@ -234,25 +1013,367 @@ impl JsonFile {
         println!("❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮❮");
     }
 
-    /// Add to a phonebook only if that name is unique
-    pub fn add_to_phonebook(&mut self, mut p: Person) -> Result<()> {
+    /// Add to a phonebook only if that name is unique, returning the id assigned to the new entry
+    pub fn add_to_phonebook(&mut self, mut p: Person) -> Result<PersonID> {
         // Handle bad requests such as an `id` not being in their default state 0_u128
         if self.get_by_id(p.id).is_some() {
             log::warn!("Person with id {} already exists in the phonebook", p.id);
             return Err(Err::PhonebookEntry("Person ID already exists".into()))
                 .with_context(|| format!("Person with id {} already exists, please do not provide an id", p.id));
         }
+        p.normalize()?;
+        self.schema.check(&mut p)?;
+        relation::check(&p, |id| Ok(self.get_by_id(id).is_some()))?;
+        let organization = p.organization.and_then(|id| self.organization(id));
+        organization::check(&mut p, organization)?;
         let id = self.generate_id();
         p.id = id;
-        if !self.check_if_name_exists(&p.name)?.0 {
+        p.stamp_created(Utc::now());
+        if !self.check_if_name_exists(&p.name).0 {
             self.phonebook.push(p);
         } else {
             log::warn!("Name {} already exists in the phonebook. Names must be unique", &p.name);
             return Err(Err::PhonebookEntry("Duplicate name".into()))
                 .with_context(|| format!("Person with name {} already exists, Names must be unique", p.name));
         }
+        Ok(id)
+    }
+    /// Apply a journalled mutation. Unlike `add_to_phonebook`, an `Add` keeps the id it was recorded with
+    /// and replaces any entry already holding that id, which makes replaying a journal twice harmless.
+    pub fn apply(&mut self, entry: JournalEntry) -> Result<()> {
+        match entry {
+            JournalEntry::Add { mut person } => {
+                person.upgrade_number()?;
+                match self.phonebook.binary_search_by_key(&person.id, |p| p.id) {
+                    Ok(index) => self.phonebook[index] = person,
+                    Err(index) => self.phonebook.insert(index, person),
+                }
+            }
+            JournalEntry::Update {
+                id,
+                person,
+                revision,
+                at,
+            } => {
+                // Already part of the snapshot the journal is replayed over, don't count it twice.
+                // An entry deleted later on may not even be there anymore.
+                if self
+                    .get_by_id(id)
+                    .is_none_or(|p| revision != 0 && p.revision >= revision)
+                {
+                    return Ok(());
+                }
+                self.update_at(id, person, at.unwrap_or_else(Utc::now))?;
+            }
+            JournalEntry::Delete { id, at } => {
+                // Replaying a delete that is part of the snapshot already finds nothing to delete,
+                // though replaying the `Add` of an entry relating to it may have brought back a link
+                let at = at.unwrap_or_else(Utc::now);
+                if self.get_by_id(id).is_some() {
+                    self.delete_at(id, at)?;
+                } else {
+                    self.unlink(id, at);
+                }
+            }
+            JournalEntry::Restore { id, revision, at } => {
+                if self.trash.iter().any(|p| p.id == id) {
+                    self.restore_at(id, at)?;
+                    let index = self
+                        .phonebook
+                        .binary_search_by_key(&id, |p| p.id)
+                        .expect("Just restored");
+                    self.phonebook[index].revision = revision;
+                }
+            }
+            JournalEntry::Purge { before } => {
+                self.purge(before);
+            }
+            JournalEntry::Schema { schema, at } => self.set_schema_at(schema, at)?,
+            JournalEntry::Tag { tag, ids, at } => {
+                // An entry deleted later on may already be gone from the snapshot the journal is replayed over
+                let ids: Vec<PersonID> = ids.into_iter().filter(|&id| self.get_by_id(id).is_some()).collect();
+                self.tag_at(&tag, &ids, at.unwrap_or_else(Utc::now))?;
+            }
+            JournalEntry::Untag { tag, ids, at } => {
+                self.untag_at(&tag, &ids, at.unwrap_or_else(Utc::now))?;
+            }
+            JournalEntry::Organization { organization, at } => {
+                match self.organizations.binary_search_by_key(&organization.id, |o| o.id) {
+                    Ok(_) => self.update_organization_at(organization.id, organization, at)?,
+                    Err(index) => self.organizations.insert(index, organization),
+                }
+            }
+            JournalEntry::DeleteOrganization { id, at } => {
+                // Like `Delete`, an organization that is already gone may still have members brought back by an `Add`
+                if self.organization(id).is_some() {
+                    self.delete_organization_at(id, at)?;
+                } else {
+                    self.refresh_members(id, at);
+                }
+            }
+            JournalEntry::Move {
+                ids,
+                organization,
+                department,
+                at,
+            } => {
+                // Neither the entries nor the organization have to be there anymore
+                if organization.is_none_or(|id| self.organization(id).is_some()) {
+                    let ids: Vec<PersonID> = ids.into_iter().filter(|&id| self.get_by_id(id).is_some()).collect();
+                    self.move_to_at(&ids, organization, department.as_deref(), at)?;
+                }
+            }
+        }
         Ok(())
     }
+    /// Add `tag` to the entries `ids`, returning how many didn't carry it yet.
+    /// Fails without changing anything if one of the ids doesn't exist.
+    pub fn tag(&mut self, tag: &str, ids: &[PersonID]) -> Result<usize> {
+        self.tag_at(tag, ids, Utc::now())
+    }
+    pub(crate) fn tag_at(&mut self, tag: &str, ids: &[PersonID], at: DateTime<Utc>) -> Result<usize> {
+        let tag = tag::normalize(tag)?;
+        if let Some(missing) = ids.iter().find(|&&id| self.get_by_id(id).is_none()) {
+            return Err(Err::NotFound(format!("id {missing}")))
+                .with_context(|| format!("Can't tag id {missing} as {tag}, it does not exist in the phonebook"));
+        }
+        let mut changed = 0;
+        for person in self.phonebook.iter_mut().filter(|p| ids.contains(&p.id)) {
+            if let Err(index) = person.tags.binary_search(&tag) {
+                person.tags.insert(index, tag.clone());
+                person.stamp_updated(at);
+                changed += 1;
+            }
+        }
+        Ok(changed)
+    }
+    /// Remove `tag` from the entries `ids`, returning how many carried it. Missing ids are skipped.
+    pub fn untag(&mut self, tag: &str, ids: &[PersonID]) -> Result<usize> {
+        self.untag_at(tag, ids, Utc::now())
+    }
+    pub(crate) fn untag_at(&mut self, tag: &str, ids: &[PersonID], at: DateTime<Utc>) -> Result<usize> {
+        let tag = tag::normalize(tag)?;
+        let mut changed = 0;
+        for person in self.phonebook.iter_mut().filter(|p| ids.contains(&p.id)) {
+            if let Ok(index) = person.tags.binary_search(&tag) {
+                person.tags.remove(index);
+                person.stamp_updated(at);
+                changed += 1;
+            }
+        }
+        Ok(changed)
+    }
+    /// Every entry carrying `tag`, sorted by id
+    pub fn tagged(&self, tag: &str) -> Result<Vec<Person>> {
+        let tag = tag::normalize(tag)?;
+        Ok(self
+            .phonebook
+            .iter()
+            .filter(|p| p.tags.binary_search(&tag).is_ok())
+            .cloned()
+            .collect())
+    }
+    /// The entry `id` along with the entries it relates to, see `relation::expand`
+    pub fn expand(&self, id: PersonID) -> Result<Option<Expanded>> {
+        let Some(person) = self.get_by_id(id) else {
+            return Ok(None);
+        };
+        relation::expand(person, |id| Ok(self.get_by_id(id))).map(Some)
+    }
+    /// Every organization, sorted by id
+    pub fn organizations(&self) -> &[Organization] {
+        &self.organizations
+    }
+    pub fn organization(&self, id: OrganizationID) -> Option<&Organization> {
+        let index = self.organizations.binary_search_by_key(&id, |o| o.id).ok()?;
+        Some(&self.organizations[index])
+    }
+    /// Add an organization if its name is unique, returning the id assigned to it
+    pub fn add_organization(&mut self, mut organization: Organization) -> Result<OrganizationID> {
+        organization.normalize()?;
+        organization.id = 0;
+        self.check_organization_name(&organization)?;
+        organization.id = self.organizations.last().map_or(1, |o| o.id + 1);
+        let id = organization.id;
+        self.organizations.push(organization);
+        Ok(id)
+    }
+    /// Replace an organization as a whole. People in a department it no longer lists are left without a department.
+    pub fn update_organization(&mut self, id: OrganizationID, organization: Organization) -> Result<()> {
+        self.update_organization_at(id, organization, Utc::now())
+    }
+    pub(crate) fn update_organization_at(
+        &mut self,
+        id: OrganizationID,
+        mut organization: Organization,
+        at: DateTime<Utc>,
+    ) -> Result<()> {
+        let index = self
+            .organizations
+            .binary_search_by_key(&id, |o| o.id)
+            .map_err(|_| Err::NotFound(format!("organization {id}")))
+            .with_context(|| format!("Organization {id} does not exist"))?;
+        organization.normalize()?;
+        organization.id = id;
+        self.check_organization_name(&organization)?;
+        self.organizations[index] = organization;
+        self.refresh_members(id, at);
+        Ok(())
+    }
+    /// Remove an organization, its people stay in the phonebook without one
+    pub fn delete_organization(&mut self, id: OrganizationID) -> Result<()> {
+        self.delete_organization_at(id, Utc::now())
+    }
+    pub(crate) fn delete_organization_at(&mut self, id: OrganizationID, at: DateTime<Utc>) -> Result<()> {
+        let index = self
+            .organizations
+            .binary_search_by_key(&id, |o| o.id)
+            .map_err(|_| Err::NotFound(format!("organization {id}")))
+            .with_context(|| format!("Organization {id} does not exist"))?;
+        self.organizations.remove(index);
+        self.refresh_members(id, at);
+        Ok(())
+    }
+    /// Everyone in the organization `id`, sorted by id
+    pub fn members(&self, id: OrganizationID) -> Result<Vec<Person>> {
+        if self.organization(id).is_none() {
+            return Err(Err::NotFound(format!("organization {id}")))
+                .with_context(|| format!("Organization {id} does not exist"));
+        }
+        Ok(self
+            .phonebook
+            .iter()
+            .filter(|p| p.organization == Some(id))
+            .cloned()
+            .collect())
+    }
+    /// Move the entries `ids` into `organization` and its `department`, or out of any organization with `None`.
+    /// Returns how many entries weren't there already. Fails without changing anything if one of the ids doesn't exist.
+    pub fn move_to(
+        &mut self,
+        ids: &[PersonID],
+        organization: Option<OrganizationID>,
+        department: Option<&str>,
+    ) -> Result<usize> {
+        self.move_to_at(ids, organization, department, Utc::now())
+    }
+    pub(crate) fn move_to_at(
+        &mut self,
+        ids: &[PersonID],
+        organization: Option<OrganizationID>,
+        department: Option<&str>,
+        at: DateTime<Utc>,
+    ) -> Result<usize> {
+        if let Some(missing) = ids.iter().find(|&&id| self.get_by_id(id).is_none()) {
+            return Err(Err::NotFound(format!("id {missing}")))
+                .with_context(|| format!("Can't move id {missing}, it does not exist in the phonebook"));
+        }
+        let mut target = Person {
+            organization,
+            department: department.map(Into::into),
+            ..Default::default()
+        };
+        organization::check(&mut target, organization.and_then(|id| self.organization(id)))?;
+        let mut changed = 0;
+        for person in self.phonebook.iter_mut().filter(|p| ids.contains(&p.id)) {
+            if (person.organization, &person.department) != (target.organization, &target.department) {
+                person.organization = target.organization;
+                person.department = target.department.clone();
+                person.stamp_updated(at);
+                changed += 1;
+            }
+        }
+        Ok(changed)
+    }
+    /// Bring the people of organization `id` in line with its current departments, or detach them if it is gone
+    fn refresh_members(&mut self, id: OrganizationID, at: DateTime<Utc>) {
+        let organization = self.organization(id).cloned();
+        for person in self.phonebook.iter_mut().filter(|p| p.organization == Some(id)) {
+            if organization::forget_missing(person, organization.as_ref()) {
+                person.stamp_updated(at);
+            }
+        }
+        // Organization ids may be handed out again, entries restored later must not join the new one
+        for person in self.trash.iter_mut().filter(|p| p.organization == Some(id)) {
+            organization::forget_missing(person, organization.as_ref());
+        }
+    }
+    /// Organization names are unique the same way entry names are
+    fn check_organization_name(&self, organization: &Organization) -> Result<()> {
+        let key = organization.key();
+        if self
+            .organizations
+            .iter()
+            .any(|o| o.id != organization.id && o.key() == key)
+        {
+            return Err(Err::PhonebookEntry("Duplicate name".into())).with_context(|| {
+                format!(
+                    "Organization {} already exists, names must be unique",
+                    organization.name
+                )
+            });
+        }
+        Ok(())
+    }
+    /// Every tag in use, see `tag::groups`
+    pub fn groups(&self) -> Vec<Group> {
+        tag::groups(&self.phonebook)
+    }
+    /// Check the invariants the rest of the code relies on: unique ids, a name on every entry, custom fields that fit
+    /// the schema, and relationships and organizations that point at something.
+    /// Useful whenever the file might have been edited by hand or comes from a backup.
+    pub fn validate(&self) -> Result<()> {
+        self.schema.validate()?;
+        let mut ids = std::collections::HashSet::with_capacity(self.phonebook.len());
+        for person in &self.phonebook {
+            if !ids.insert(person.id) {
+                return Err(Err::PhonebookEntry("Duplicate id".into()))
+                    .with_context(|| format!("id {} appears more than once", person.id));
+            }
+            if !person.name.is_valid() {
+                return Err(Err::PhonebookEntry("Name missing".into()))
+                    .with_context(|| format!("Entry with id {} has no name", person.id));
+            }
+        }
+        let mut organization_ids = std::collections::HashSet::with_capacity(self.organizations.len());
+        for organization in &self.organizations {
+            if !organization_ids.insert(organization.id) {
+                return Err(Err::PhonebookEntry("Duplicate id".into()))
+                    .with_context(|| format!("Organization id {} appears more than once", organization.id));
+            }
+        }
+        for person in &self.phonebook {
+            // The checks bring values into canonical form, which is none of our business here
+            let mut person = person.clone();
+            self.schema
+                .check(&mut person)
+                .with_context(|| format!("Entry {} doesn't fit the schema", person.id))?;
+            relation::check(&person, |id| Ok(ids.contains(&id)))?;
+            let organization = person.organization.and_then(|id| self.organization(id));
+            organization::check(&mut person, organization)?;
+        }
+        // Ids in the trash stay reserved, see `generate_id`
+        for person in &self.trash {
+            if !ids.insert(person.id) {
+                return Err(Err::PhonebookEntry("Duplicate id".into()))
+                    .with_context(|| format!("id {} of the trash is taken", person.id));
+            }
+        }
+        Ok(())
+    }
+    /// Check the entries against the checksum they were saved with.
+    /// Files without a checksum, written by hand or before checksums existed, always pass.
+    pub fn verify_checksum(&self) -> Result<()> {
+        let Some(expected) = &self.checksum else {
+            return Ok(());
+        };
+        let actual = checksum_of(self)?;
+        if *expected == actual {
+            return Ok(());
+        }
+        Err(Err::Corrupt("Checksum mismatch".into()))
+            .with_context(|| format!("Checksum mismatch, the file says {expected} but its entries hash to {actual}"))
+    }
     /// Sort the phonebook by id
     pub fn sort(&mut self) {
         // if self.phonebook.iter().is_sorted_by_key(|p| p.id) {
@@ -262,19 +1383,21 @@ impl JsonFile {
         log::info!("Phonebook sorted by id");
     }
 
-    // TODO: Currently we do not assign missing ids i.e. ids that were deleted do not 
+    // TODO: Currently we do not assign missing ids i.e. ids that were deleted do not
     // get assinged to newly added entries. Let's fix this
     fn generate_id(&self) -> PersonID {
+        // Ids in the trash stay reserved, so entries can be restored under their original id
         let max_phonebook_id = self
             .phonebook
             .iter()
-            .max_by_key(|person| (**person).id)
-            .and_then(|person| Some(person.id))
+            .chain(&self.trash)
+            .max_by_key(|person| person.id)
+            .map(|person| person.id)
             .unwrap_or(<PersonID>::default());
         /* IDs should start with 1 incase this phonebook is empty */
         // Generates a very large id
         let mut candidate = if max_phonebook_id == 0 { 1 } else { max_phonebook_id + 1 };
-        while matches!(self.phonebook.iter().next(), Some(person) if person.id == candidate ) {
+        while matches!(self.phonebook.first(), Some(person) if person.id == candidate ) {
             // This debug should practically never log
             log::debug!("candidate ID collision found");
             candidate = rand::random::<PersonID>();
@@ -282,45 +1405,18 @@ impl JsonFile {
         candidate
     }
 
-    fn check_if_name_exists(&self, new_name: &str) -> Result<(bool, Option<usize>)> {
-        let new_name = new_name.trim().to_lowercase();
-        let mut new_name = new_name.split_whitespace();
-        // Important to get length before calling any `next`|`next_back`
-        let new_name_len = new_name.size_hint();
-        let (new_fname, new_lname) = (
-            new_name
-                .next()
-                // previously ok_or which is a const fn
-                .ok_or_else(|| Err::PhonebookEntry("First name missing".into()))
-                .with_context(|| {
-                    let err = "Phonebook entry should have a first name";
-                    log::warn!("{err}");
-                    err
-                })?,
-            new_name.next_back(),
-        );
-        // We take the size_hint before calling `next` and `next_back` on new_name and name
-        // exactly once
-        let pos = self
-            .phonebook
-            .iter()
-            .map(|person| person.name.trim().to_lowercase())
-            .position(|pname| {
-                let mut name = pname.split_whitespace();
-                let name_len = name.size_hint();
-                // compare upper bounds
-                name_len.1 == new_name_len.1
-                    && name.next().expect("Safe to unwrap pre-existing fname") == new_fname
-                    && matches!(name.next_back(), Some(lname) if matches!(new_lname, Some(n) if n == lname))
-            });
-        Ok((pos.is_some(), pos))
+    /// Whether an entry has the same name as `new_name`, see `Name::key`
+    fn check_if_name_exists(&self, new_name: &Name) -> (bool, Option<usize>) {
+        let key = new_name.key();
+        let pos = self.phonebook.iter().position(|person| person.name.key() == key);
+        (pos.is_some(), pos)
     }
 }
 
 #[tokio::test]
 async fn test_methods() -> Result<()> {
     let path = Path::new("files/mock.json");
-    let mut json_file = read_json(&path)?;
+    let mut json_file = read_json(path)?;
 
     println!("Before any operation:");
     json_file.print_phonebook();
@@ -334,8 +1430,69 @@ async fn test_methods() -> Result<()> {
     json_file.print_phonebook();
     println!("Writing JSON to {}", path.display());
     // Write updated phonebook to file :
-    write_json(&path, &json_file)?;
+    write_json(path, &json_file)?;
 
     debug_assert_eq!(None, json_file.get_by_id(10));
     Ok(())
 }
+
+#[test]
+fn test_atomic_write() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    let path = dir.join("book.json");
+    let mut json_file = JsonFile::default();
+    json_file.add_to_phonebook(person!("Ada Lovelace", "39-44-5323523"))?;
+    write_json(&path, &json_file)?;
+    json_file.add_to_phonebook(person!("Dan Abramov", "12-43-234345"))?;
+    write_json(&path, &json_file)?;
+
+    assert_eq!(2, read_json(&path)?.phonebook.len());
+    // The temp file must not outlive a successful save
+    assert!(!tmp_path_for(&path).exists());
+    Ok(())
+}
+
+#[test]
+fn test_first_run() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    let path = dir.join("files").join("book.json");
+    assert!(read_or_create_json(&path)?.persons().is_empty());
+    assert!(read_json(&path)?.persons().is_empty());
+
+    std::fs::write(&path, "")?;
+    assert!(read_json(&path)?.persons().is_empty());
+
+    std::fs::write(
+        &path,
+        "{\n  \"phonebook\": [\n    { \"id\": 1, \"name\": \"Ada\" \n  ]\n}",
+    )?;
+    let err = format!("{:#}", read_or_create_json(&path).unwrap_err());
+    assert!(err.contains("line 4 column 3"), "{err}");
+    Ok(())
+}
+
+#[test]
+fn test_search_covers_the_contact_details() -> Result<()> {
+    let ada: Person = serde_json::from_str(
+        r#"{"name": "Ada Lovelace", "numbers": [{"number": "+44 20 7946-0958"}],
+            "emails": [{"address": "ada@example.com"}], "addresses": [{"city": "London"}],
+            "urls": [{"url": "https://ada.example.org/"}]}"#,
+    )?;
+    for query in [
+        "lovelace",
+        "7946-0958",
+        "2079460958",
+        "ADA@",
+        "london",
+        "example.org",
+        "",
+    ] {
+        assert!(ada.matches(query), "{query}");
+    }
+    for query in ["babbage", "555", "work"] {
+        assert!(!ada.matches(query), "{query}");
+    }
+    Ok(())
+}
@@ -0,0 +1,497 @@
+//! Multiple named phonebooks served from one server.
+//! The default book is the one configured through `data_path` (or `db_path`) and is what the `/book` routes serve.
+//! Every other book is a file of its own in `books_dir`, `<name>.json` or `<name>.db` depending on the store,
+//! with its own background writer, backups, photos and, for JSON books, file watcher.
+use crate::backup::{backup_dir_for, Backups};
+use crate::config::{Config, StoreKind};
+use crate::photo::{photos_dir_for, Photos};
+use crate::store::{JsonFileStore, MemoryStore, PhonebookStore, SqliteStore};
+use crate::writer::Writer;
+use crate::Err;
+use anyhow::{Context, Result};
+use parking_lot::RwLock;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The book behind the `/book` routes, it can be neither renamed nor deleted
+pub const DEFAULT_BOOK: &str = "default";
+
+/// A phonebook along with everything that keeps it saved
+pub struct Book {
+    name: String,
+    store: Arc<dyn PhonebookStore>,
+    writer: Writer,
+    backups: Backups,
+    photos: Photos,
+    // Cleared once the book is renamed or deleted, late callers then get an error instead of a stale store
+    open: RwLock<bool>,
+}
+
+impl Book {
+    /// Must be called from within a tokio runtime, which the background writer is spawned on
+    fn new(name: &str, store: Arc<dyn PhonebookStore>, backups: Backups, photos: Photos, window: Duration) -> Self {
+        Self {
+            name: name.to_owned(),
+            writer: Writer::spawn(Arc::clone(&store), window),
+            store,
+            backups,
+            photos,
+            open: RwLock::new(true),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn backups(&self) -> &Backups {
+        &self.backups
+    }
+
+    pub fn photos(&self) -> &Photos {
+        &self.photos
+    }
+
+    /// Run a read-only call against the book's store
+    pub fn read<T>(&self, f: impl FnOnce(&dyn PhonebookStore) -> Result<T>) -> Result<T> {
+        let open = self.open.read();
+        self.ensure_open(*open)?;
+        f(self.store.as_ref())
+    }
+
+    /// Like `read` for calls that change the book, the background writer takes care of saving them
+    pub fn write<T>(&self, f: impl FnOnce(&dyn PhonebookStore) -> Result<T>) -> Result<T> {
+        let open = self.open.read();
+        self.ensure_open(*open)?;
+        let ret = f(self.store.as_ref())?;
+        self.writer.mark_dirty();
+        Ok(ret)
+    }
+
+    /// Save pending changes right away instead of waiting for the background writer
+    pub async fn flush(&self) -> Result<()> {
+        self.writer.flush().await
+    }
+
+    fn ensure_open(&self, open: bool) -> Result<()> {
+        if open {
+            return Ok(());
+        }
+        Err(Err::NotFound("No such book".into())).with_context(|| format!("Book `{}` is gone", self.name))
+    }
+
+    /// Wait for calls in flight, refuse any further call and let go of the store's files.
+    /// Returns the whole book as it was last, for whatever takes its place.
+    fn close(&self) -> Result<crate::JsonFile> {
+        let mut open = self.open.write();
+        *open = false;
+        let json_file = self.store.export()?;
+        self.store.close()?;
+        Ok(json_file)
+    }
+}
+
+/// A book as reported by `Library::list`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BookInfo {
+    pub name: String,
+    pub entries: usize,
+}
+
+/// Every book the server knows about
+pub struct Library {
+    books_dir: PathBuf,
+    kind: StoreKind,
+    backup_count: usize,
+    backup_interval: Duration,
+    accept_edits: bool,
+    write_window: Duration,
+    books: RwLock<BTreeMap<String, Arc<Book>>>,
+}
+
+/// Book names end up in file names and URLs, so they are limited to ASCII letters, digits, `-` and `_`
+pub fn validate_name(name: &str) -> Result<()> {
+    let valid =
+        (1..=64).contains(&name.len()) && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        return Ok(());
+    }
+    Err(Err::PhonebookEntry("Invalid book name".into()))
+        .with_context(|| format!("`{name}` is not a valid book name, use up to 64 letters, digits, `-` or `_`"))
+}
+
+impl Library {
+    /// Open the default book and every book found in `config.books_dir`.
+    /// Must be called from within a tokio runtime, see `Book::new`.
+    pub fn open(config: &Config) -> Result<Self> {
+        let library = Self {
+            books_dir: config.books_dir.clone(),
+            kind: config.store,
+            backup_count: config.backup_count,
+            backup_interval: config.backup_interval(),
+            accept_edits: config.accept_edits,
+            write_window: config.write_window(),
+            books: RwLock::new(BTreeMap::new()),
+        };
+        let default = library.open_default(config)?;
+        let mut books = BTreeMap::from([(DEFAULT_BOOK.to_owned(), Arc::new(default))]);
+        for name in library.discover(&config.data_path)? {
+            let book = library.open_book(&name)?;
+            books.insert(name, Arc::new(book));
+        }
+        log::info!("Serving {} phonebook(s)", books.len());
+        *library.books.write() = books;
+        Ok(library)
+    }
+
+    /// The book called `name`
+    pub fn get(&self, name: &str) -> Result<Arc<Book>> {
+        self.books
+            .read()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Err::NotFound("No such book".into()))
+            .with_context(|| format!("There is no book called `{name}`"))
+    }
+
+    /// Every book, sorted by name
+    pub fn list(&self) -> Result<Vec<BookInfo>> {
+        let books: Vec<Arc<Book>> = self.books.read().values().cloned().collect();
+        books
+            .iter()
+            .map(|book| {
+                Ok(BookInfo {
+                    name: book.name.clone(),
+                    entries: book.read(|store| store.list())?.len(),
+                })
+            })
+            .collect()
+    }
+
+    /// Create a new empty book
+    pub fn create(&self, name: &str) -> Result<Arc<Book>> {
+        validate_name(name)?;
+        let mut books = self.books.write();
+        if books.contains_key(name) || self.book_path(name).is_some_and(|p| p.exists()) {
+            return Err(Err::PhonebookEntry("Duplicate book".into()))
+                .with_context(|| format!("A book called `{name}` already exists"));
+        }
+        let book = Arc::new(self.open_book(name)?);
+        books.insert(name.to_owned(), Arc::clone(&book));
+        log::info!("Created book `{name}`");
+        Ok(book)
+    }
+
+    /// Rename a book along with its file, backups and photos
+    pub fn rename(&self, from: &str, to: &str) -> Result<()> {
+        validate_name(to)?;
+        Self::ensure_not_default(from)?;
+        let mut books = self.books.write();
+        let book = books
+            .get(from)
+            .cloned()
+            .ok_or_else(|| Err::NotFound("No such book".into()))
+            .with_context(|| format!("There is no book called `{from}`"))?;
+        if books.contains_key(to) || self.book_path(to).is_some_and(|p| p.exists()) {
+            return Err(Err::PhonebookEntry("Duplicate book".into()))
+                .with_context(|| format!("A book called `{to}` already exists"));
+        }
+        let json_file = book.close()?;
+        books.remove(from);
+        let moved = self.move_files(from, to).and_then(|_| self.open_book(to));
+        let renamed = match moved {
+            Ok(renamed) => renamed,
+            Err(e) => {
+                // Put the book back under its old name rather than losing it
+                log::error!("Failed to rename book `{from}` to `{to}`: {e:?}");
+                let book = self.open_book(from)?;
+                if self.kind == StoreKind::Memory {
+                    book.store.replace_all(json_file)?;
+                }
+                books.insert(from.to_owned(), Arc::new(book));
+                return Err(e);
+            }
+        };
+        // Carried over by hand, there is no file to move
+        if self.kind == StoreKind::Memory {
+            renamed.store.replace_all(json_file)?;
+        }
+        books.insert(to.to_owned(), Arc::new(renamed));
+        log::info!("Renamed book `{from}` to `{to}`");
+        Ok(())
+    }
+
+    /// Delete a book. Its backups are kept, along with a last backup of its contents, its photos are not.
+    pub fn delete(&self, name: &str) -> Result<()> {
+        Self::ensure_not_default(name)?;
+        let book = self
+            .books
+            .write()
+            .remove(name)
+            .ok_or_else(|| Err::NotFound("No such book".into()))
+            .with_context(|| format!("There is no book called `{name}`"))?;
+        let json_file = book.close()?;
+        if let Some(path) = self.book_path(name) {
+            // Saved as JSON whatever the store, so that it can be listed and restored like any other backup
+            book.backups.save(name, &json_file)?;
+            for path in std::iter::once(path.clone()).chain(sidecars(&path)) {
+                match std::fs::remove_file(&path) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                        return Err(Err::Io(e)).with_context(|| format!("Failed to remove `{}`", path.display()))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        // Would otherwise show up on the entries of a new book of the same name
+        match std::fs::remove_dir_all(book.photos.dir()) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(Err::Io(e)).with_context(|| format!("Failed to remove the photos of `{name}`"))
+            }
+            _ => {}
+        }
+        log::warn!("Deleted book `{name}`");
+        Ok(())
+    }
+
+    /// Save pending changes of every book, e.g. on shutdown
+    pub async fn flush(&self) -> Result<()> {
+        let books: Vec<Arc<Book>> = self.books.read().values().cloned().collect();
+        for book in books {
+            book.flush()
+                .await
+                .with_context(|| format!("Failed to flush book `{}`", book.name))?;
+        }
+        Ok(())
+    }
+
+    fn ensure_not_default(name: &str) -> Result<()> {
+        if name != DEFAULT_BOOK {
+            return Ok(());
+        }
+        Err(Err::PhonebookEntry("Default book".into()))
+            .with_context(|| "The default book can neither be renamed nor deleted")
+    }
+
+    /// The file of a named book, `None` for books only kept in memory
+    fn book_path(&self, name: &str) -> Option<PathBuf> {
+        match self.kind {
+            StoreKind::Json => Some(self.books_dir.join(format!("{name}.json"))),
+            StoreKind::Sqlite => Some(self.books_dir.join(format!("{name}.db"))),
+            StoreKind::Memory => None,
+        }
+    }
+
+    fn backups_for(&self, name: &str) -> Backups {
+        Backups::new(self.books_dir.join("backups").join(name), self.backup_count).every(self.backup_interval)
+    }
+
+    fn photos_for(&self, name: &str) -> Photos {
+        Photos::new(self.books_dir.join("photos").join(name))
+    }
+
+    fn open_default(&self, config: &Config) -> Result<Book> {
+        let backups = Backups::new(backup_dir_for(&config.data_path), config.backup_count).every(self.backup_interval);
+        let photos = Photos::new(photos_dir_for(&config.data_path));
+        let store: Arc<dyn PhonebookStore> = match config.store {
+            StoreKind::Memory => Arc::new(MemoryStore::default()),
+            // The JSON phonebook is imported the first time the database is opened
+            StoreKind::Sqlite => Arc::new(SqliteStore::open(&config.db_path, Some(&config.data_path))?),
+            StoreKind::Json => self.open_json(&config.data_path, &backups)?,
+        };
+        Ok(Book::new(DEFAULT_BOOK, store, backups, photos, self.write_window))
+    }
+
+    fn open_book(&self, name: &str) -> Result<Book> {
+        let backups = self.backups_for(name);
+        let store: Arc<dyn PhonebookStore> = match (self.kind, self.book_path(name)) {
+            (StoreKind::Json, Some(path)) => self.open_json(&path, &backups)?,
+            (StoreKind::Sqlite, Some(path)) => Arc::new(SqliteStore::open(&path, None)?),
+            _ => Arc::new(MemoryStore::default()),
+        };
+        Ok(Book::new(
+            name,
+            store,
+            backups,
+            self.photos_for(name),
+            self.write_window,
+        ))
+    }
+
+    fn open_json(&self, path: &Path, backups: &Backups) -> Result<Arc<dyn PhonebookStore>> {
+        let store = Arc::new(JsonFileStore::open(path, Some(backups.clone()), self.accept_edits)?);
+        // The file is sometimes edited by hand, pick those edits up instead of overwriting them
+        crate::watch::watch(&store)?;
+        Ok(store)
+    }
+
+    /// Names of the books found in `books_dir`, skipping `data_path` should it live there too
+    fn discover(&self, data_path: &Path) -> Result<Vec<String>> {
+        let extension = match self.kind {
+            StoreKind::Json => "json",
+            StoreKind::Sqlite => "db",
+            StoreKind::Memory => return Ok(vec![]),
+        };
+        let entries = match std::fs::read_dir(&self.books_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => {
+                return Err(Err::Io(e))
+                    .with_context(|| format!("Failed to list books in `{}`", self.books_dir.display()))
+            }
+        };
+        let mut names = vec![];
+        for entry in entries {
+            let path = entry.map_err(Err::Io)?.path();
+            if !path.is_file() || path.extension().and_then(|e| e.to_str()) != Some(extension) || path == data_path {
+                continue;
+            }
+            let name = path
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            if name == DEFAULT_BOOK || validate_name(&name).is_err() {
+                log::warn!("Ignoring `{}`, `{name}` is not a usable book name", path.display());
+                continue;
+            }
+            names.push(name);
+        }
+        Ok(names)
+    }
+
+    fn move_files(&self, from: &str, to: &str) -> Result<()> {
+        if let (Some(old), Some(new)) = (self.book_path(from), self.book_path(to)) {
+            std::fs::rename(&old, &new)
+                .map_err(Err::Io)
+                .with_context(|| format!("Failed to move `{}` to `{}`", old.display(), new.display()))?;
+            for (old, new) in sidecars(&old)
+                .into_iter()
+                .zip(sidecars(&new))
+                .filter(|(old, _)| old.exists())
+            {
+                std::fs::rename(&old, &new)
+                    .map_err(Err::Io)
+                    .with_context(|| format!("Failed to move `{}` to `{}`", old.display(), new.display()))?;
+            }
+            let (old_backups, new_backups) = (self.backups_for(from), self.backups_for(to));
+            if old_backups.dir().exists() && !new_backups.dir().exists() {
+                std::fs::rename(old_backups.dir(), new_backups.dir())
+                    .map_err(Err::Io)
+                    .with_context(|| format!("Failed to move the backups of `{from}`"))?;
+            }
+        }
+        // Photos are files even for books only kept in memory
+        let (old_photos, new_photos) = (self.photos_for(from), self.photos_for(to));
+        if old_photos.dir().exists() && !new_photos.dir().exists() {
+            std::fs::rename(old_photos.dir(), new_photos.dir())
+                .map_err(Err::Io)
+                .with_context(|| format!("Failed to move the photos of `{from}`"))?;
+        }
+        Ok(())
+    }
+}
+
+/// The files a store may keep next to the book at `path`: SQLite's `-wal` and `-shm`, the journal and the lock file
+fn sidecars(path: &Path) -> [PathBuf; 4] {
+    [
+        sibling(path, "-wal"),
+        sibling(path, "-shm"),
+        crate::journal::journal_path_for(path),
+        crate::lock::lock_path_for(path),
+    ]
+}
+
+/// `files/books/sales.db` -> `files/books/sales.db-wal`
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[tokio::test]
+async fn test_library_manages_books() -> Result<()> {
+    use crate::Person;
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    let config = Config {
+        data_path: dir.join("mock.json"),
+        books_dir: dir.join("books"),
+        ..Config::default()
+    };
+    let library = Library::open(&config)?;
+    library
+        .create("sales")?
+        .write(|store| store.add(person!("Ada Lovelace", "39-44-5323523")))?;
+    assert!(library.create("sales").is_err());
+    assert!(library.create("../etc").is_err());
+    assert!(library.rename(DEFAULT_BOOK, "main").is_err());
+
+    library.rename("sales", "marketing")?;
+    assert!(library.get("sales").is_err());
+    let marketing = library.get("marketing")?;
+    assert!(marketing.read(|store| store.get_by_name("Ada Lovelace"))?.is_some());
+    marketing.flush().await?;
+    drop(library);
+
+    // Books are picked up again on the next start
+    let library = Library::open(&config)?;
+    let names: Vec<_> = library.list()?.into_iter().map(|b| (b.name, b.entries)).collect();
+    assert_eq!(vec![(DEFAULT_BOOK.to_owned(), 0), ("marketing".to_owned(), 1)], names);
+    library.delete("marketing")?;
+    assert!(!config.books_dir.join("marketing.json").exists());
+    assert_eq!(1, library.list()?.len());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_library_moves_whole_books() -> Result<()> {
+    use crate::{Organization, Person};
+    for store in [StoreKind::Sqlite, StoreKind::Memory] {
+        let tmp = tempfile::tempdir()?;
+        let dir = tmp.path();
+        let config = Config {
+            data_path: dir.join("mock.json"),
+            db_path: dir.join("phonebook.db"),
+            books_dir: dir.join("books"),
+            store,
+            ..Config::default()
+        };
+        let library = Library::open(&config)?;
+        library.create("sales")?.write(|store| {
+            store.add_organization(Organization {
+                name: "Acme".into(),
+                ..Default::default()
+            })?;
+            store.add(person!("Ada Lovelace", "39-44-5323523"))?;
+            let gone = store.add(person!("Dan Abramov", "12-43-234345"))?;
+            store.delete(gone)
+        })?;
+
+        // Trash and organizations come along, and nothing is left behind under the old name
+        library.rename("sales", "marketing")?;
+        let marketing = library.get("marketing")?;
+        assert_eq!(1, marketing.read(|store| store.list())?.len());
+        assert_eq!(1, marketing.read(|store| store.trash())?.len());
+        assert_eq!(1, marketing.read(|store| store.organizations())?.len());
+        if let Some(old) = library.book_path("sales") {
+            assert!(!old.exists() && sidecars(&old).iter().all(|p| !p.exists()));
+        }
+
+        // The last backup of a deleted book can be restored like any other
+        library.delete("marketing")?;
+        let backups = library.backups_for("marketing");
+        match library.book_path("marketing") {
+            Some(path) => {
+                let backup = backups.load(&backups.list()?[0].name)?;
+                assert_eq!(1, backup.persons().len());
+                assert_eq!(1, backup.trash().len());
+                assert!(!path.exists() && sidecars(&path).iter().all(|p| !p.exists()));
+            }
+            None => assert!(backups.list()?.is_empty()),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,135 @@
+//! Advisory locks that keep the server and CLI tools from racing on the same phonebook file.
+//! Readers take a shared lock and writers an exclusive one before touching the contents, waiting up to
+//! the configured timeout for the other side to finish. The lock is taken on a sibling `.<file>.lock`
+//! rather than on the data file itself, since every save replaces the data file through a rename and
+//! a lock held on the old file would not keep anyone from opening the new one.
+use crate::Err;
+use anyhow::{Context, Result};
+use fs2::FileExt;
+use parking_lot::RwLock;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How long to wait for a lock when nothing else is configured
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Upper bound of the pause between two attempts
+const MAX_POLL: Duration = Duration::from_millis(50);
+
+// Set once at startup, read by `read_json` and `write_json`
+static TIMEOUT: RwLock<Duration> = RwLock::new(DEFAULT_LOCK_TIMEOUT);
+
+/// Wait up to `timeout` for every lock taken from now on
+pub fn set_timeout(timeout: Duration) {
+    *TIMEOUT.write() = timeout;
+}
+
+/// The timeout set with `set_timeout`
+pub fn timeout() -> Duration {
+    *TIMEOUT.read()
+}
+
+/// A held lock, released when dropped
+#[derive(Debug)]
+pub struct FileLock {
+    file: File,
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        // Closing the file releases the lock as well, this just does it right away
+        let _ = FileExt::unlock(&self.file);
+    }
+}
+
+/// Take a shared lock on `path`, any number of readers may hold one at the same time
+pub fn shared(path: &Path) -> Result<FileLock> {
+    acquire(path, false, timeout())
+}
+
+/// Take an exclusive lock on `path`, which waits for every reader and writer to let go
+pub fn exclusive(path: &Path) -> Result<FileLock> {
+    acquire(path, true, timeout())
+}
+
+/// `files/mock.json` -> `files/.mock.json.lock`
+pub fn lock_path_for(path: &Path) -> PathBuf {
+    let file_name = path.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    path.with_file_name(format!(".{file_name}.lock"))
+}
+
+fn acquire(path: &Path, exclusive: bool, timeout: Duration) -> Result<FileLock> {
+    let lock_path = lock_path_for(path);
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+        .map_err(Err::Io)
+        .with_context(|| format!("Failed to open lock file `{}`", lock_path.display()))?;
+    let deadline = Instant::now() + timeout;
+    let mut pause = Duration::from_millis(1);
+    loop {
+        let attempt = if exclusive {
+            FileExt::try_lock_exclusive(&file)
+        } else {
+            FileExt::try_lock_shared(&file)
+        };
+        match attempt {
+            Ok(()) => return Ok(FileLock { file }),
+            Err(e) if e.kind() == fs2::lock_contended_error().kind() => {}
+            Err(e) => {
+                return Err(Err::Io(e)).with_context(|| format!("Error on locking `{}`", lock_path.display()));
+            }
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            let (err, held_by) = if exclusive {
+                (
+                    Err::WriteLockTimeout(path.display().to_string()),
+                    "readers or another writer",
+                )
+            } else {
+                (Err::ReadLockTimeout(path.display().to_string()), "a writer")
+            };
+            return Err(err).with_context(|| {
+                format!(
+                    "`{}` stayed locked by {held_by} for longer than {}ms",
+                    path.display(),
+                    timeout.as_millis()
+                )
+            });
+        }
+        std::thread::sleep(pause.min(deadline - now));
+        pause = (pause * 2).min(MAX_POLL);
+    }
+}
+
+#[test]
+fn test_locks_wait_and_time_out() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    let path = dir.join("book.json");
+    let short = Duration::from_millis(50);
+
+    // Readers share, a writer has to wait for all of them
+    let reader = acquire(&path, false, short)?;
+    let other_reader = acquire(&path, false, short)?;
+    let err = acquire(&path, true, short).unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(Err::WriteLockTimeout(_))));
+    drop((reader, other_reader));
+
+    // A writer keeps out readers until it lets go
+    let writer = acquire(&path, true, short)?;
+    let err = acquire(&path, false, short).unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(Err::ReadLockTimeout(_))));
+    let waiting = {
+        let path = path.clone();
+        std::thread::spawn(move || acquire(&path, false, Duration::from_secs(5)).map(drop))
+    };
+    std::thread::sleep(short);
+    drop(writer);
+    waiting.join().expect("Reader panicked")?;
+    Ok(())
+}
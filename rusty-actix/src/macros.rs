@@ -9,8 +9,12 @@
 macro_rules! person {
     ($name:expr, $num:expr) => {{
         Person {
-            name: String::from($name),
-            number: String::from($num),
+            name: $crate::Name::parse(AsRef::<str>::as_ref(&$name)),
+            numbers: vec![$crate::PhoneNumber {
+                number: String::from($num),
+                primary: true,
+                ..Default::default()
+            }],
             ..Default::default()
         }
     }};
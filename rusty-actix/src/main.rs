@@ -4,10 +4,18 @@
 //! We take the dynamic json reader approach first i.e. no struct defining a schema, just Json JsonValue
 // Branch Actix_Files: Where we try to achieve the same results as master branch but using actix_files
 #![allow(unused_imports)]
-use ::phonebook::{read_json, JsonFile, Person};
+use ::phonebook::config::{Cli, Command, Config};
+use ::phonebook::crypto::Key;
+use ::phonebook::format::Format;
+use ::phonebook::photo::{Variant, MAX_PHOTO_BYTES};
+use ::phonebook::{
+    read_json, Book, JsonFile, Library, Organization, Person, PhonebookStore, RelationKind, Schema, DEFAULT_BOOK,
+};
 use actix_cors::Cors;
 use actix_files as afs;
 use actix_files::NamedFile;
+use actix_multipart::form::{bytes::Bytes as UploadBytes, MultipartForm, MultipartFormConfig};
+use actix_web::http::header::{self, CacheControl, CacheDirective, EntityTag, Header, IfNoneMatch};
 use actix_web::Result as ActixResult;
 use actix_web::{error as actix_error, web, App, HttpRequest, HttpResponse, HttpServer};
 #[allow(unused)]
@@ -15,7 +23,7 @@ use phonebook::{async_read_json, async_write_json};
 #[macro_use] // https://doc.rust-lang.org/reference/macros-by-example.html#the-macro_use-attribute
 mod macros;
 mod into_actix_trait;
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use into_actix_trait::IntoActixResult;
 use lazy_static::lazy_static;
 use parking_lot::RwLock;
@@ -24,33 +32,129 @@ use std::sync::{Arc, Once};
 // https://users.rust-lang.org/t/how-can-i-use-mutable-lazy-static/3751/3
 // Cannot call non-const fns in static/const context
 lazy_static! {
-    static ref PHONEBOOK_PATH: &'static std::path::Path = &std::path::Path::new("files/mock.json");
-    static ref APP_JSON_FILE: Arc<RwLock<JsonFile>> = Arc::new(RwLock::new(JsonFile::default()));
-    // Done : Select PORT from environment or start using port 80
-    static ref PORT: u16 = std::env::var("PORT")
-        .unwrap_or_else(|_| "80".into())
-        .parse::<u16>()
-        .expect("Invalid Port number");
+    static ref CLI: Cli = <Cli as clap::Parser>::parse();
+    // Defaults < config file < environment < command line flags, see `phonebook::config`
+    static ref CONFIG: Config = Config::from_cli(&CLI).unwrap_or_else(|e| {
+        eprintln!("{e:#}");
+        std::process::exit(2)
+    });
+    // Every phonebook served, handlers only ever see the `PhonebookStore` trait of a book.
+    // Spawns a background writer per book, so it must be first touched from within the runtime
+    static ref APP_LIBRARY: Library =
+        Library::open(&CONFIG).expect("Failed to open the phonebooks. App initialization failed");
 }
 static APP_INIT: Once = Once::new();
 pub(crate) type ActixResponse = ActixResult<HttpResponse>;
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    if let Some(command) = &CLI.command {
+        if let Err(e) = run_command(command) {
+            eprintln!("{e:#}");
+            std::process::exit(1)
+        }
+        return Ok(());
+    }
+    lazy_static::initialize(&CONFIG);
+    env_logger::Builder::new().parse_filters(&CONFIG.log_level).init();
+    let key = Key::load(CONFIG.key_file.as_deref(), CONFIG.passphrase.as_deref())
+        .expect("Failed to load the encryption key. App initialization failed");
+    if key.is_some() {
+        log::info!("Phonebooks are encrypted at rest");
+    }
+    phonebook::crypto::set_key(key);
+    phonebook::format::set_format(CONFIG.storage_format());
+    phonebook::lock::set_timeout(CONFIG.lock_timeout());
+    if !CONFIG.static_dir.is_dir() {
+        log::warn!(
+            "static_dir `{}` is not a directory, `/app` and `/` will answer 404",
+            CONFIG.static_dir.display()
+        );
+    }
     init();
-    env_logger::init();
-    std::env::set_var("RUST_LOG", "actix_web=info");
-    std::env::set_var("REACT_APP_SERVER_PORT", (PORT).to_string());
-    let tcp = TcpListener::bind(&format!("0.0.0.0:{}", *PORT))?;
+    std::env::set_var("REACT_APP_SERVER_PORT", CONFIG.port.to_string());
+    let tcp = TcpListener::bind(CONFIG.bind_address())?;
     let _port = tcp.local_addr()?.port();
-    println!("Started on port {}", *PORT);
+    println!("Started on {}", CONFIG.bind_address());
     HttpServer::new(move || {
         App::new()
-            // Cors::permissive is not recommended for production environments
-            .wrap(Cors::permissive())
+            .wrap(cors())
+            // Uploads are buffered in memory, the default allows less than a photo may weigh
+            .app_data(MultipartFormConfig::default().memory_limit(MAX_PHOTO_BYTES))
             // Get
             .route("/", web::get().to(index))
             .route("/book", web::get().to(get_phonebook_handler))
             .route("/book/{id}", web::get().to(get_by_id))
+            .route("/book/{id}/related", web::get().to(get_related))
+            .route("/book/{id}/photo", web::get().to(get_photo))
+            .route("/book/{id}/photo/thumbnail", web::get().to(get_thumbnail))
+            .route("/book/{id}/photo", web::post().to(post_photo))
+            .route("/book/{id}/photo", web::delete().to(delete_photo))
+            // Books, these have to come before the catch-all "/{name}"
+            .route("/books", web::get().to(list_books))
+            .route("/books", web::post().to(create_book))
+            .route("/books/{book}", web::put().to(rename_book))
+            .route("/books/{book}", web::delete().to(delete_book))
+            .route("/books/{book}/entries", web::get().to(get_phonebook_handler))
+            .route("/books/{book}/entries", web::post().to(post_phonebook_handler))
+            .route("/books/{book}/entries/{id}", web::get().to(get_by_id))
+            .route("/books/{book}/entries/{id}/related", web::get().to(get_related))
+            .route("/books/{book}/entries/{id}/photo", web::get().to(get_photo))
+            .route(
+                "/books/{book}/entries/{id}/photo/thumbnail",
+                web::get().to(get_thumbnail),
+            )
+            .route("/books/{book}/entries/{id}/photo", web::post().to(post_photo))
+            .route("/books/{book}/entries/{id}/photo", web::delete().to(delete_photo))
+            .route("/books/{book}/entries/{id}", web::put().to(put_update))
+            .route("/books/{book}/entries/{id}", web::delete().to(delete_id))
+            .route("/books/{book}/names/{name}", web::get().to(get_by_name))
+            .route("/books/{book}/groups", web::get().to(list_groups))
+            .route("/books/{book}/groups/{group}", web::get().to(get_group))
+            .route("/books/{book}/groups/{group}", web::delete().to(delete_group))
+            .route("/books/{book}/groups/{group}/members", web::post().to(add_members))
+            .route("/books/{book}/groups/{group}/members", web::delete().to(remove_members))
+            .route("/books/{book}/organizations", web::get().to(list_organizations))
+            .route("/books/{book}/organizations", web::post().to(post_organization))
+            .route("/books/{book}/organizations/{org}", web::get().to(get_organization))
+            .route("/books/{book}/organizations/{org}", web::put().to(put_organization))
+            .route(
+                "/books/{book}/organizations/{org}",
+                web::delete().to(delete_organization),
+            )
+            .route("/books/{book}/organizations/{org}/people", web::get().to(list_people))
+            .route("/books/{book}/organizations/{org}/people", web::post().to(move_people))
+            .route(
+                "/books/{book}/organizations/{org}/people",
+                web::delete().to(remove_people),
+            )
+            .route("/books/{book}/schema", web::get().to(get_schema))
+            .route("/books/{book}/schema", web::put().to(put_schema))
+            .route("/books/{book}/trash", web::get().to(list_trash))
+            .route("/books/{book}/trash/purge", web::post().to(purge_trash))
+            .route("/books/{book}/trash/{id}/restore", web::post().to(restore_entry))
+            .route("/books/{book}/backups", web::get().to(list_backups))
+            .route("/books/{book}/backups/{name}/restore", web::post().to(restore_backup))
+            // Groups of the default book
+            .route("/groups", web::get().to(list_groups))
+            .route("/groups/{group}", web::get().to(get_group))
+            .route("/groups/{group}", web::delete().to(delete_group))
+            .route("/groups/{group}/members", web::post().to(add_members))
+            .route("/groups/{group}/members", web::delete().to(remove_members))
+            // Organizations of the default book
+            .route("/organizations", web::get().to(list_organizations))
+            .route("/organizations", web::post().to(post_organization))
+            .route("/organizations/{org}", web::get().to(get_organization))
+            .route("/organizations/{org}", web::put().to(put_organization))
+            .route("/organizations/{org}", web::delete().to(delete_organization))
+            .route("/organizations/{org}/people", web::get().to(list_people))
+            .route("/organizations/{org}/people", web::post().to(move_people))
+            .route("/organizations/{org}/people", web::delete().to(remove_people))
+            .route("/schema", web::get().to(get_schema))
+            .route("/schema", web::put().to(put_schema))
+            // Trash of the default book
+            .route("/trash", web::get().to(list_trash))
+            .route("/trash/purge", web::post().to(purge_trash))
+            .route("/trash/{id}/restore", web::post().to(restore_entry))
             .route("/{name}", web::get().to(get_by_name))
             // Delete
             .route("/book/{id}", web::delete().to(delete_id))
@@ -60,57 +164,158 @@ async fn main() -> std::io::Result<()> {
             // we can use "/book" and perform the checking of ids in rust or we can do better
             // and make a put "/book/id", which let's us surgically update a complete record, be it name or number
             .route("/book/{id}", web::put().to(put_update))
+            // Admin, on the default book
+            .route("/admin/backups", web::get().to(list_backups))
+            .route("/admin/backups/{name}/restore", web::post().to(restore_backup))
             // This needs to be placed after routers
-            .service(afs::Files::new("/app", "./react-front").index_file("index.html"))
+            .service(afs::Files::new("/app", &CONFIG.static_dir).index_file("index.html"))
         // .route("/book/{name}", web::get().to(get_by_name))
     })
     .listen(tcp)?
     .run()
-    .await
+    .await?;
+    // Fold any outstanding changes into the backend before exiting
+    if let Err(e) = APP_LIBRARY.flush().await {
+        log::error!("Failed to flush the phonebooks on shutdown: {e:?}");
+    }
+    Ok(())
 }
 
 async fn index(_req: HttpRequest) -> actix_web::Result<NamedFile, std::io::Error> {
-    NamedFile::open("react-front/index.html")
+    NamedFile::open(CONFIG.static_dir.join("index.html"))
 }
 
-async fn put_update(path: web::Path<u32>, person: web::Json<Person>, req: HttpRequest) -> ActixResponse {
+/// Allow the configured origins, or any origin if none were configured
+fn cors() -> Cors {
+    if CONFIG.cors_origins.is_empty() {
+        // Cors::permissive is not recommended for production environments
+        return Cors::permissive();
+    }
+    CONFIG
+        .cors_origins
+        .iter()
+        .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+        .allow_any_method()
+        .allow_any_header()
+}
+
+/// `{book}` is absent from the legacy `/book` routes, which serve the default book
+#[derive(serde::Deserialize)]
+struct BookPath {
+    book: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct EntryPath {
+    book: Option<String>,
+    id: u32,
+}
+
+#[derive(serde::Deserialize)]
+struct NamePath {
+    book: Option<String>,
+    name: String,
+}
+
+/// `?sort=` of the listings, by id unless asked otherwise
+#[derive(serde::Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum SortBy {
+    #[default]
+    Id,
+    /// Family name first, see `Name::sort_key`
+    Name,
+}
+
+#[derive(serde::Deserialize)]
+struct ListQuery {
+    #[serde(default)]
+    sort: SortBy,
+    /// Only the entries carrying this tag, e.g. `?tag=on-call`
+    tag: Option<String>,
+    /// Only the entries changed after this RFC 3339 time, e.g. `?modified_since=2024-05-01T12:00:00Z`
+    modified_since: Option<chrono::DateTime<chrono::Utc>>,
+    /// Only the entries with this custom field value, e.g. `?field=cost_centre:CC-42`
+    field: Option<String>,
+    /// Only the entries whose name, numbers, emails, addresses or urls contain this, e.g. `?q=london`
+    q: Option<String>,
+}
+
+/// `?kind=` of `GET /book/{id}/related`, e.g. `?kind=assistant` for just the assistants
+#[derive(serde::Deserialize)]
+struct RelatedQuery {
+    kind: Option<RelationKind>,
+}
+
+/// A group is a tag, see `phonebook::tag`
+#[derive(serde::Deserialize)]
+struct GroupPath {
+    book: Option<String>,
+    group: String,
+}
+
+/// Body of the requests adding or removing group members
+#[derive(serde::Deserialize)]
+struct Members {
+    ids: Vec<::phonebook::PersonID>,
+}
+
+#[derive(serde::Deserialize)]
+struct OrganizationPath {
+    book: Option<String>,
+    org: u32,
+}
+
+/// Body of the requests moving people into an organization, wherever they were before
+#[derive(serde::Deserialize)]
+struct Move {
+    ids: Vec<::phonebook::PersonID>,
+    /// One of the departments of the organization, none if left out
+    department: Option<String>,
+}
+
+/// `?older_than_days=` of a purge, the configured retention period unless given
+#[derive(serde::Deserialize)]
+struct PurgeQuery {
+    older_than_days: Option<u32>,
+}
+
+/// Body of `POST /book/{id}/photo`, a multipart form with the image in its `photo` field
+#[derive(MultipartForm)]
+struct PhotoUpload {
+    // Keep in line with `MAX_PHOTO_BYTES`
+    #[multipart(limit = "5MiB")]
+    photo: UploadBytes,
+}
+
+/// Body of the requests creating or renaming a book
+#[derive(serde::Deserialize)]
+struct BookName {
+    name: String,
+}
+
+async fn put_update(path: web::Path<EntryPath>, person: web::Json<Person>, req: HttpRequest) -> ActixResponse {
     log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
-    let id = path.into_inner() as ::phonebook::PersonID;
+    let EntryPath { book, id } = path.into_inner();
+    let id = id as ::phonebook::PersonID;
     log::info!("PUT {person:?}");
     let person = person.into_inner();
-    let mutex = Arc::clone(&APP_JSON_FILE);
-    let mut json_file = mutex.write();
-    json_file.update(id, person).map_err(|e| {
-        log::warn!("{:?}", e);
-        actix_error::ErrorInternalServerError(e)
-    })?;
-    drop(json_file);
-    // Mutex needs to be unlocked else async_write_json will fail and wait indefinitely
-    async_write_json(&PHONEBOOK_PATH, Arc::clone(&mutex))
+    with_store_mut(book, move |store| store.update(id, person))
         .await
+        .map_err(|e| {
+            log::warn!("{:?}", e);
+            e
+        })
         .actix_result()?;
     Ok(HttpResponse::Ok().finish())
 }
 
 // #[actix_web::get("/book/{id}")]
-async fn get_by_id(path: web::Path<u32>, req: HttpRequest) -> ActixResponse {
+async fn get_by_id(path: web::Path<EntryPath>, req: HttpRequest) -> ActixResponse {
     log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
-    let id = path.into_inner() as ::phonebook::PersonID;
-    let person = tokio::task::spawn_blocking(move || -> Result<Option<Person>, anyhow::Error> {
-        let mutex = Arc::clone(&APP_JSON_FILE);
-        let json_file = mutex.read();
-        Ok(json_file.get_by_id(id))
-    })
-    .await
-    // First we work on the JoinError
-    .map_err(|_join_err| anyhow!("JoinError on get_by_id"))
-    .actix_result()?
-    // Then on the internal Mutex error
-    // .map_err(|mutex_err| {
-    //     log::error!("mutex failed at get_by_id");
-    //     mutex_err
-    // })
-    .actix_result()?;
+    let EntryPath { book, id } = path.into_inner();
+    let id = id as ::phonebook::PersonID;
+    let person = with_store(book, move |store| store.get(id)).await.actix_result()?;
 
     if let Some(p) = person {
         let payload = serde_json::to_string_pretty(&p)?;
@@ -120,16 +325,33 @@ async fn get_by_id(path: web::Path<u32>, req: HttpRequest) -> ActixResponse {
     }
 }
 
+/// An entry with the entries it relates to expanded, see `phonebook::relation`
+async fn get_related(path: web::Path<EntryPath>, query: web::Query<RelatedQuery>, req: HttpRequest) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let EntryPath { book, id } = path.into_inner();
+    let id = id as ::phonebook::PersonID;
+    let expanded = with_store(book, move |store| store.expand(id)).await.actix_result()?;
+
+    if let Some(mut expanded) = expanded {
+        if let Some(kind) = query.into_inner().kind {
+            expanded.related.retain(|r| r.kind == kind);
+        }
+        let payload = serde_json::to_string_pretty(&expanded)?;
+        Ok(HttpResponse::Ok().content_type("application/json").body(payload))
+    } else {
+        Ok(HttpResponse::NoContent().finish())
+    }
+}
+
 // #[actix_web::get("/book/{name}")]
-async fn get_by_name(req: HttpRequest, path: web::Path<String>) -> ActixResponse {
+async fn get_by_name(req: HttpRequest, path: web::Path<NamePath>) -> ActixResponse {
     log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
-    let name = path.into_inner();
-    // If none found send a HTTP 204: Request was processed but no name was foun d
-    let mutex = Arc::clone(&APP_JSON_FILE);
-    let json_file = mutex.read();
-    // .map_err(|_e| anyhow!("RwLock poisoned at function get_by_name"))
-    // .actix_result()?;
-    Ok(if let Some(person) = json_file.get_by_name(&name) {
+    let NamePath { book, name } = path.into_inner();
+    // If none found send a HTTP 204: Request was processed but no name was found
+    let person = with_store(book, move |store| store.get_by_name(&name))
+        .await
+        .actix_result()?;
+    Ok(if let Some(person) = person {
         let payload = serde_json::to_string_pretty(&person)?;
         HttpResponse::Ok().content_type("application/json").body(payload)
     } else {
@@ -137,32 +359,66 @@ async fn get_by_name(req: HttpRequest, path: web::Path<String>) -> ActixResponse
     })
 }
 
-async fn post_phonebook_handler(req: HttpRequest, person: web::Json<Person>) -> ActixResponse {
+async fn post_phonebook_handler(
+    req: HttpRequest,
+    path: web::Path<BookPath>,
+    person: web::Json<Person>,
+) -> ActixResponse {
     log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
     log::info!("POST {person:?}");
     let person = person.into_inner();
-    // SAFETY: APP_JSON_FILE is properly initialized else the app will panic at start
-    let mutex = Arc::clone(&APP_JSON_FILE);
-    // Create a scope for mutex guard
-    // If the Mutex was "poisoned" we should just `expect` on it since the poison happened on some other thread
-    // that we don't control. Should return internal server error
-    let mut json_file = mutex.write();
-    json_file.add_to_phonebook(person).map_err(|e| {
-        log::warn!("{:?}", e);
-        actix_error::ErrorInternalServerError(e)
-    })?;
-    drop(json_file);
-    // Mutex needs to be unlocked else async_write_json will fail and wait indefinitely
-    async_write_json(&PHONEBOOK_PATH, Arc::clone(&mutex))
+    with_store_mut(path.into_inner().book, move |store| store.add(person))
         .await
+        .map_err(|e| {
+            log::warn!("{:?}", e);
+            e
+        })
         .actix_result()?;
     Ok(HttpResponse::Ok().finish())
 }
 
-async fn get_phonebook_handler(req: HttpRequest) -> ActixResponse {
+async fn get_phonebook_handler(
+    req: HttpRequest,
+    path: web::Path<BookPath>,
+    query: web::Query<ListQuery>,
+) -> ActixResponse {
     log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
-    // SAFETY: APP_JSON_FILE is properly initialized else the app will panic at start
-    let json_file = APP_JSON_FILE.read();
+    let ListQuery {
+        sort,
+        tag,
+        modified_since,
+        field,
+        q,
+    } = query.into_inner();
+    let field = match field {
+        Some(field) => match field.split_once(':') {
+            Some((name, value)) => Some((name.trim().to_owned(), value.to_owned())),
+            None => {
+                return Err(actix_error::ErrorBadRequest(
+                    "Filter custom fields as `?field=name:value`",
+                ))
+            }
+        },
+        None => None,
+    };
+    let mut persons = with_store(path.into_inner().book, move |store| match tag {
+        Some(tag) => store.tagged(&tag),
+        None => store.list(),
+    })
+    .await
+    .actix_result()?;
+    if let Some(since) = modified_since {
+        persons.retain(|p| p.modified_since(since));
+    }
+    if let Some((name, value)) = field {
+        persons.retain(|p| p.fields.get(&name).is_some_and(|v| v.matches(&value)));
+    }
+    if let Some(q) = q {
+        persons.retain(|p| p.matches(&q));
+    }
+    if sort == SortBy::Name {
+        persons.sort_by_cached_key(|p| (p.name.sort_key(), p.id));
+    }
 
     // Problem serde_json::error::Result<T> is returned here and must be converted to
     // anyhow::Result<T> before actix_result() will work
@@ -170,27 +426,502 @@ async fn get_phonebook_handler(req: HttpRequest) -> ActixResponse {
     // Fortunately, we have from actix_web
     // impl ResponseError for serde_json::Error {}
 
-    let payload = serde_json::to_string_pretty(&*json_file)?;
+    // Keep the `{ "phonebook": [...] }` shape the react app expects
+    let payload = serde_json::to_string_pretty(&JsonFile::from(persons))?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(payload))
+}
+
+async fn delete_id(req: HttpRequest, path: web::Path<EntryPath>) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let EntryPath { book, id } = path.into_inner();
+    let id = id as ::phonebook::PersonID;
+    with_store_mut(book, move |store| store.delete(id))
+        .await
+        .actix_result()?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Replace the photo of an entry, see `phonebook::photo`
+async fn post_photo(req: HttpRequest, path: web::Path<EntryPath>, form: MultipartForm<PhotoUpload>) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let EntryPath { book, id } = path.into_inner();
+    let id = id as ::phonebook::PersonID;
+    let bytes = form.into_inner().photo.data;
+    with_book(book, move |book| {
+        ensure_entry(book, id)?;
+        book.photos().save(id, &bytes)
+    })
+    .await
+    .map_err(|e| {
+        log::warn!("{:?}", e);
+        e
+    })
+    .actix_result()?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn get_photo(req: HttpRequest, path: web::Path<EntryPath>) -> ActixResponse {
+    serve_photo(req, path.into_inner(), Variant::Photo).await
+}
+
+async fn get_thumbnail(req: HttpRequest, path: web::Path<EntryPath>) -> ActixResponse {
+    serve_photo(req, path.into_inner(), Variant::Thumbnail).await
+}
+
+/// Photos change in place under the same URL, so clients always revalidate them and mostly get a 304 back
+async fn serve_photo(req: HttpRequest, path: EntryPath, variant: Variant) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let EntryPath { book, id } = path;
+    let id = id as ::phonebook::PersonID;
+    let photo = with_book(book, move |book| {
+        ensure_entry(book, id)?;
+        book.photos().load(id, variant)
+    })
+    .await
+    .actix_result()?;
+    let Some(photo) = photo else {
+        return Ok(HttpResponse::NotFound().body("No photo"));
+    };
+    // Hashed from the plain image, encrypting it again on a save with a new salt leaves it unchanged
+    let etag = EntityTag::new_strong(hex::encode(&<sha2::Sha256 as sha2::Digest>::digest(&photo)[..16]));
+    // Personal data, shared caches must not keep it
+    let cache_control = CacheControl(vec![CacheDirective::Private, CacheDirective::NoCache]);
+    let fresh = match IfNoneMatch::parse(&req) {
+        Ok(IfNoneMatch::Any) => true,
+        Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        Err(_) => false,
+    };
+    if fresh {
+        return Ok(HttpResponse::NotModified()
+            .insert_header(header::ETag(etag))
+            .insert_header(cache_control)
+            .finish());
+    }
+    Ok(HttpResponse::Ok()
+        .content_type("image/jpeg")
+        .insert_header(header::ETag(etag))
+        .insert_header(cache_control)
+        .body(photo))
+}
+
+async fn delete_photo(req: HttpRequest, path: web::Path<EntryPath>) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let EntryPath { book, id } = path.into_inner();
+    let id = id as ::phonebook::PersonID;
+    with_book(book, move |book| {
+        ensure_entry(book, id)?;
+        book.photos().remove(id)
+    })
+    .await
+    .actix_result()?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn list_groups(req: HttpRequest, path: web::Path<BookPath>) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let groups = with_store(path.into_inner().book, |store| store.groups())
+        .await
+        .actix_result()?;
+    let payload = serde_json::to_string_pretty(&groups)?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(payload))
+}
+
+/// The members of a group with all their numbers, in the same shape as `GET /book`
+async fn get_group(req: HttpRequest, path: web::Path<GroupPath>) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let GroupPath { book, group } = path.into_inner();
+    let persons = with_store(book, move |store| store.tagged(&group))
+        .await
+        .actix_result()?;
+    let payload = serde_json::to_string_pretty(&JsonFile::from(persons))?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(payload))
+}
+
+/// Disband a group by untagging all of its members, the entries themselves stay
+async fn delete_group(req: HttpRequest, path: web::Path<GroupPath>) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let GroupPath { book, group } = path.into_inner();
+    with_store_mut(book, move |store| {
+        let ids: Vec<_> = store.tagged(&group)?.iter().map(|p| p.id).collect();
+        store.untag(&group, &ids)
+    })
+    .await
+    .actix_result()?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn add_members(req: HttpRequest, path: web::Path<GroupPath>, body: web::Json<Members>) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let GroupPath { book, group } = path.into_inner();
+    let ids = body.into_inner().ids;
+    with_store_mut(book, move |store| store.tag(&group, &ids))
+        .await
+        .map_err(|e| {
+            log::warn!("{:?}", e);
+            e
+        })
+        .actix_result()?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn remove_members(req: HttpRequest, path: web::Path<GroupPath>, body: web::Json<Members>) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let GroupPath { book, group } = path.into_inner();
+    let ids = body.into_inner().ids;
+    with_store_mut(book, move |store| store.untag(&group, &ids))
+        .await
+        .actix_result()?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn list_organizations(req: HttpRequest, path: web::Path<BookPath>) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let organizations = with_store(path.into_inner().book, |store| store.organizations())
+        .await
+        .actix_result()?;
+    let payload = serde_json::to_string_pretty(&organizations)?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(payload))
+}
+
+async fn post_organization(
+    req: HttpRequest,
+    path: web::Path<BookPath>,
+    organization: web::Json<Organization>,
+) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let organization = organization.into_inner();
+    with_store_mut(path.into_inner().book, move |store| {
+        store.add_organization(organization)
+    })
+    .await
+    .map_err(|e| {
+        log::warn!("{:?}", e);
+        e
+    })
+    .actix_result()?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn get_organization(req: HttpRequest, path: web::Path<OrganizationPath>) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let OrganizationPath { book, org } = path.into_inner();
+    let organization = with_store(book, move |store| store.organization(org.into()))
+        .await
+        .actix_result()?;
+    Ok(if let Some(organization) = organization {
+        let payload = serde_json::to_string_pretty(&organization)?;
+        HttpResponse::Ok().content_type("application/json").body(payload)
+    } else {
+        HttpResponse::NoContent().finish()
+    })
+}
+
+/// Replace an organization as a whole, people in departments it drops are left without one
+async fn put_organization(
+    req: HttpRequest,
+    path: web::Path<OrganizationPath>,
+    organization: web::Json<Organization>,
+) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let OrganizationPath { book, org } = path.into_inner();
+    let organization = organization.into_inner();
+    with_store_mut(book, move |store| store.update_organization(org.into(), organization))
+        .await
+        .map_err(|e| {
+            log::warn!("{:?}", e);
+            e
+        })
+        .actix_result()?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Remove an organization, its people stay in the book without one
+async fn delete_organization(req: HttpRequest, path: web::Path<OrganizationPath>) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let OrganizationPath { book, org } = path.into_inner();
+    with_store_mut(book, move |store| store.delete_organization(org.into()))
+        .await
+        .actix_result()?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// The people of an organization, in the same shape as `GET /book`
+async fn list_people(req: HttpRequest, path: web::Path<OrganizationPath>) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let OrganizationPath { book, org } = path.into_inner();
+    let persons = with_store(book, move |store| store.members(org.into()))
+        .await
+        .actix_result()?;
+    let payload = serde_json::to_string_pretty(&JsonFile::from(persons))?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(payload))
+}
+
+/// Move people into an organization in bulk, from whichever organization they were in
+async fn move_people(req: HttpRequest, path: web::Path<OrganizationPath>, body: web::Json<Move>) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let OrganizationPath { book, org } = path.into_inner();
+    let Move { ids, department } = body.into_inner();
+    with_store_mut(book, move |store| {
+        store.move_to(&ids, Some(org.into()), department.as_deref())
+    })
+    .await
+    .map_err(|e| {
+        log::warn!("{:?}", e);
+        e
+    })
+    .actix_result()?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Take people out of an organization, ids of people in another one are left alone
+async fn remove_people(req: HttpRequest, path: web::Path<OrganizationPath>, body: web::Json<Members>) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let OrganizationPath { book, org } = path.into_inner();
+    let ids = body.into_inner().ids;
+    with_store_mut(book, move |store| {
+        let members = store.members(org.into())?;
+        let ids: Vec<_> = ids
+            .into_iter()
+            .filter(|&id| members.iter().any(|p| p.id == id))
+            .collect();
+        store.move_to(&ids, None, None)
+    })
+    .await
+    .actix_result()?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn get_schema(req: HttpRequest, path: web::Path<BookPath>) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let schema = with_store(path.into_inner().book, |store| store.schema())
+        .await
+        .actix_result()?;
+    let payload = serde_json::to_string_pretty(&schema)?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(payload))
+}
+
+/// Replace the custom field schema, refused if an entry doesn't fit the new one
+async fn put_schema(req: HttpRequest, path: web::Path<BookPath>, schema: web::Json<Schema>) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let schema = schema.into_inner();
+    with_store_mut(path.into_inner().book, move |store| store.set_schema(schema))
+        .await
+        .map_err(|e| {
+            log::warn!("{:?}", e);
+            e
+        })
+        .actix_result()?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Deleted entries, in the order they were deleted
+async fn list_trash(req: HttpRequest, path: web::Path<BookPath>) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let trash = with_store(path.into_inner().book, |store| store.trash())
+        .await
+        .actix_result()?;
+    let payload = serde_json::to_string_pretty(&trash)?;
     Ok(HttpResponse::Ok().content_type("application/json").body(payload))
 }
 
-async fn delete_id(req: HttpRequest, id: web::Path<u32>) -> ActixResponse {
+async fn restore_entry(req: HttpRequest, path: web::Path<EntryPath>) -> ActixResponse {
     log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
-    let id = id.into_inner() as ::phonebook::PersonID;
-    let json_file = Arc::clone(&APP_JSON_FILE);
-    // Infallible
-    json_file.write().delete(id).expect("Infallible");
-    async_write_json(&PHONEBOOK_PATH, json_file).await.actix_result()?;
+    let EntryPath { book, id } = path.into_inner();
+    let id = id as ::phonebook::PersonID;
+    with_store_mut(book, move |store| store.restore(id))
+        .await
+        .map_err(|e| {
+            log::warn!("{:?}", e);
+            e
+        })
+        .actix_result()?;
+    Ok(HttpResponse::Ok().finish())
+}
 
+/// Permanently remove what was deleted longer ago than the retention period
+async fn purge_trash(req: HttpRequest, path: web::Path<BookPath>, query: web::Query<PurgeQuery>) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let retention = match query.older_than_days {
+        Some(days) => chrono::TimeDelta::days(days.into()),
+        None => CONFIG.trash_retention(),
+    };
+    let before = chrono::Utc::now() - retention;
+    let purged = with_book(path.into_inner().book, move |book| {
+        let trashed = |book: &Book| -> anyhow::Result<std::collections::HashSet<_>> {
+            Ok(book.read(|store| store.trash())?.into_iter().map(|p| p.id).collect())
+        };
+        let before_purge = trashed(book)?;
+        let purged = book.write(|store| store.purge(before))?;
+        // Gone for good, and so are their photos
+        for id in before_purge.difference(&trashed(book)?) {
+            book.photos().remove(*id)?;
+        }
+        Ok(purged)
+    })
+    .await
+    .actix_result()?;
+    log::info!("Purged {purged} entries deleted before {before}");
+    let payload = serde_json::to_string_pretty(&serde_json::json!({ "purged": purged }))?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(payload))
+}
+
+async fn list_books(req: HttpRequest) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let books = with_library(|library| library.list()).await.actix_result()?;
+    let payload = serde_json::to_string_pretty(&books)?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(payload))
+}
+
+async fn create_book(req: HttpRequest, body: web::Json<BookName>) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let name = body.into_inner().name;
+    with_library(move |library| library.create(&name).map(drop))
+        .await
+        .actix_result()?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn rename_book(req: HttpRequest, path: web::Path<String>, body: web::Json<BookName>) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let (from, to) = (path.into_inner(), body.into_inner().name);
+    with_library(move |library| library.rename(&from, &to))
+        .await
+        .actix_result()?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+async fn delete_book(req: HttpRequest, path: web::Path<String>) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let name = path.into_inner();
+    with_library(move |library| library.delete(&name))
+        .await
+        .actix_result()?;
     Ok(HttpResponse::NoContent().finish())
 }
 
+async fn list_backups(req: HttpRequest, path: web::Path<BookPath>) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let book = path.into_inner().book;
+    let backups = with_library(move |library| library.get(book.as_deref().unwrap_or(DEFAULT_BOOK))?.backups().list())
+        .await
+        .actix_result()?;
+    let payload = serde_json::to_string_pretty(&backups)?;
+    Ok(HttpResponse::Ok().content_type("application/json").body(payload))
+}
+
+/// Swap a whole phonebook for the contents of one of its backups
+async fn restore_backup(req: HttpRequest, path: web::Path<NamePath>) -> ActixResponse {
+    log::info!("{} {:?} {}", req.method(), req.version(), req.uri());
+    let NamePath { book, name } = path.into_inner();
+    with_library(move |library| {
+        let book = library.get(book.as_deref().unwrap_or(DEFAULT_BOOK))?;
+        let json_file = book.backups().load(&name)?;
+        log::warn!("Restoring book `{}` from backup `{name}`", book.name());
+        book.write(|store| store.replace_all(json_file))
+    })
+    .await
+    .actix_result()?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Run a blocking `Library` call on tokio's blocking pool
+async fn with_library<T, F>(f: F) -> anyhow::Result<T>
+where
+    F: FnOnce(&Library) -> anyhow::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(move || f(&APP_LIBRARY))
+        .await
+        // First we work on the JoinError
+        .map_err(|_join_err| anyhow!("JoinError on library access"))?
+}
+
+/// Run a blocking call against `book`, or the default book if `None`
+async fn with_book<T, F>(book: Option<String>, f: F) -> anyhow::Result<T>
+where
+    F: FnOnce(&Book) -> anyhow::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    with_library(move |library| f(library.get(book.as_deref().unwrap_or(DEFAULT_BOOK))?.as_ref())).await
+}
+
+/// Run a blocking `PhonebookStore` call against `book`, or the default book if `None`
+async fn with_store<T, F>(book: Option<String>, f: F) -> anyhow::Result<T>
+where
+    F: FnOnce(&dyn PhonebookStore) -> anyhow::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    with_book(book, move |book| book.read(f)).await
+}
+
+/// Like `with_store` for calls that change the phonebook, the book's background writer takes care of saving them
+async fn with_store_mut<T, F>(book: Option<String>, f: F) -> anyhow::Result<T>
+where
+    F: FnOnce(&dyn PhonebookStore) -> anyhow::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    with_book(book, move |book| book.write(f)).await
+}
+
+/// Photos belong to entries, an entry that is not in the book (anymore) has none
+fn ensure_entry(book: &Book, id: ::phonebook::PersonID) -> anyhow::Result<()> {
+    if book.read(|store| store.get(id))?.is_some() {
+        return Ok(());
+    }
+    Err(::phonebook::Err::NotFound(format!("id {id}")))
+        .with_context(|| format!("There is no entry with id {id} in book `{}`", book.name()))
+}
+
+/// Maintenance commands run against a file without starting the server
+fn run_command(command: &Command) -> anyhow::Result<()> {
+    // Same layered settings as the server, so a key from the config file lets `migrate` and `convert`
+    // read encrypted files, and keeps them encrypted
+    phonebook::crypto::set_key(Key::load(CONFIG.key_file.as_deref(), CONFIG.passphrase.as_deref())?);
+    // Waits for a running server to finish its save instead of racing it
+    phonebook::lock::set_timeout(CONFIG.lock_timeout());
+    match command {
+        Command::Migrate { file, dry_run } => {
+            let report = phonebook::migrate::migrate_file(file, *dry_run)?;
+            match (report.is_noop(), dry_run) {
+                (true, _) => println!("{}: {report}", file.display()),
+                (false, true) => print!("{}: would migrate {report}", file.display()),
+                (false, false) => print!("{}: migrated {report}", file.display()),
+            }
+        }
+        Command::Encrypt { file } => {
+            phonebook::crypto::encrypt_file(file, &command_key()?)?;
+            println!("{}: encrypted", file.display());
+        }
+        Command::Decrypt { file } => {
+            phonebook::crypto::decrypt_file(file, &command_key()?)?;
+            println!("{}: decrypted", file.display());
+        }
+        Command::Convert { file, to, compress } => {
+            let format = Format {
+                encoding: *to,
+                compress: *compress,
+            };
+            phonebook::write_json_as(file, &read_json(file)?, format)?;
+            println!("{}: converted to {format:?}", file.display());
+        }
+    }
+    Ok(())
+}
+
+/// The key configured like the server's, through `--key-file` or `--passphrase`, their environment variables
+/// or the config file
+fn command_key() -> anyhow::Result<Key> {
+    Key::load(CONFIG.key_file.as_deref(), CONFIG.passphrase.as_deref())?.ok_or_else(|| {
+        anyhow!(
+            "Pass --key-file or --passphrase, set PHONEBOOK_KEY_FILE or PHONEBOOK_PASSPHRASE, or configure key_file"
+        )
+    })
+}
+
 fn init() {
     APP_INIT.call_once(|| {
         // TODO: Async read_json inside call_once || Not required since this is the app start anyway
-        let json_file = read_json(&PHONEBOOK_PATH).expect("Failed to read {PHONEBOOK_PATH}. App initialization failed");
-        let mut mutex = APP_JSON_FILE.write(); //.expect("Infallible");
-        mutex.sort();
-        *mutex = json_file;
+        lazy_static::initialize(&APP_LIBRARY);
     })
 }
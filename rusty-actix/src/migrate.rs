@@ -0,0 +1,243 @@
+//! Versioning of the JSON file format.
+//! Every file written by `write_json` carries a `version`. Older documents are upgraded one step at a
+//! time by the migrations registered in `MIGRATIONS` when they are read, so archived phonebooks keep loading.
+//! Files without a `version` predate versioning and are treated as version 0.
+use crate::{crypto, format, lock, read_json, write_json_as, Err, JsonFile};
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::fmt::Display;
+use std::path::Path;
+
+/// The version `write_json` writes
+pub const CURRENT_VERSION: u32 = 3;
+
+/// Upgrades a document from version `from` to `from + 1`
+pub struct Migration {
+    pub from: u32,
+    pub description: &'static str,
+    /// Rewrites the document in place, returning a human readable line per change it made
+    pub apply: fn(&mut Value) -> Result<Vec<String>>,
+}
+
+/// Every migration, in order. Adding a format change means bumping `CURRENT_VERSION` and registering a step here.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 0,
+        description: "add a version field, accept a bare array of entries and assign ids to entries without one",
+        apply: v0_to_v1,
+    },
+    Migration {
+        from: 1,
+        description: "turn the single `number` of every entry into a list of labelled `numbers`",
+        apply: v1_to_v2,
+    },
+    Migration {
+        from: 2,
+        description: "split every free text `name` into its parts",
+        apply: v2_to_v3,
+    },
+];
+
+/// What migrating a document did, or would do in a dry run
+#[derive(Debug, Default, PartialEq)]
+pub struct MigrationReport {
+    pub from: u32,
+    pub to: u32,
+    pub steps: Vec<(u32, &'static str, Vec<String>)>,
+}
+
+impl MigrationReport {
+    pub fn is_noop(&self) -> bool {
+        self.steps.is_empty()
+    }
+}
+
+impl Display for MigrationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_noop() {
+            return write!(f, "already at version {}", self.to);
+        }
+        writeln!(f, "version {} -> {}", self.from, self.to)?;
+        for (from, description, changes) in &self.steps {
+            writeln!(f, "  {from} -> {}: {description}", from + 1)?;
+            for change in changes {
+                writeln!(f, "    - {change}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The version of a raw document, 0 if it has none
+pub fn document_version(doc: &Value) -> Result<u32> {
+    match doc.get("version") {
+        None => Ok(0),
+        Some(v) => v
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| Err::PhonebookEntry("Invalid version".into()))
+            .with_context(|| format!("`version` should be a small positive number, found {v}")),
+    }
+}
+
+/// Upgrade `doc` to `CURRENT_VERSION` in place
+pub fn migrate(doc: &mut Value) -> Result<MigrationReport> {
+    let from = document_version(doc)?;
+    if from > CURRENT_VERSION {
+        return Err(Err::PhonebookEntry("Unsupported version".into())).with_context(|| {
+            format!("The phonebook is at version {from} but this build only understands up to {CURRENT_VERSION}")
+        });
+    }
+    let mut report = MigrationReport {
+        from,
+        to: CURRENT_VERSION,
+        steps: vec![],
+    };
+    for version in from..CURRENT_VERSION {
+        let step = MIGRATIONS
+            .iter()
+            .find(|m| m.from == version)
+            .unwrap_or_else(|| panic!("No migration registered from version {version}"));
+        let changes = (step.apply)(doc).with_context(|| format!("Migration from version {version} failed"))?;
+        doc["version"] = Value::from(version + 1);
+        report.steps.push((version, step.description, changes));
+    }
+    Ok(report)
+}
+
+/// Upgrade the file at `path`, leaving it untouched if `dry_run` is set
+pub fn migrate_file(path: &Path, dry_run: bool) -> Result<MigrationReport> {
+    let bytes = {
+        let _lock = lock::shared(path)?;
+        std::fs::read(path)
+            .map_err(Err::Io)
+            .with_context(|| format!("Failed to read `{}`", path.display()))?
+    };
+    let plain = crypto::decode(&bytes, path)?;
+    let (format, payload) = format::decode(&plain, path)?;
+    let mut doc: Value = format::parse(format.encoding, &payload, path)?;
+    let report = migrate(&mut doc)?;
+    // Make sure the result actually loads before reporting success
+    let json_file: JsonFile = serde_json::from_value(doc)
+        .map_err(Err::Json)
+        .with_context(|| "Migrated document does not match the current format")?;
+    if !dry_run && !report.is_noop() {
+        // Stay in the format the file was found in
+        write_json_as(path, &json_file, format)?;
+        debug_assert_eq!(read_json(path)?.persons(), json_file.persons());
+    }
+    Ok(report)
+}
+
+fn v0_to_v1(doc: &mut Value) -> Result<Vec<String>> {
+    let mut changes = vec![];
+    // Some early builds wrote the entries without the `phonebook` wrapper
+    if doc.is_array() {
+        *doc = serde_json::json!({ "phonebook": doc.take() });
+        changes.push("wrapped the bare array of entries in `phonebook`".to_owned());
+    }
+    let entries = doc
+        .get_mut("phonebook")
+        .and_then(Value::as_array_mut)
+        .ok_or_else(|| Err::PhonebookEntry("Missing phonebook".into()))
+        .with_context(|| "Expected a `phonebook` array")?;
+    // Missing ids used to default to 0, which made every such entry collide
+    let mut next_id = entries
+        .iter()
+        .filter_map(|p| p.get("id").and_then(Value::as_u64))
+        .max()
+        .unwrap_or_default();
+    for entry in entries.iter_mut() {
+        if matches!(entry.get("id").and_then(Value::as_u64), None | Some(0)) {
+            next_id += 1;
+            let name = entry.get("name").and_then(Value::as_str).unwrap_or_default().to_owned();
+            entry["id"] = Value::from(next_id);
+            changes.push(format!("assigned id {next_id} to `{name}`"));
+        }
+    }
+    Ok(changes)
+}
+
+fn v1_to_v2(doc: &mut Value) -> Result<Vec<String>> {
+    let mut changes = vec![];
+    let entries = doc
+        .get_mut("phonebook")
+        .and_then(Value::as_array_mut)
+        .ok_or_else(|| Err::PhonebookEntry("Missing phonebook".into()))
+        .with_context(|| "Expected a `phonebook` array")?;
+    for entry in entries.iter_mut().filter_map(Value::as_object_mut) {
+        let Some(number) = entry.remove("number") else {
+            continue;
+        };
+        let name = entry.get("name").and_then(Value::as_str).unwrap_or_default().to_owned();
+        // Nothing tells us what kind of number it was
+        let numbers = match number.as_str().map(str::trim) {
+            Some("") | None => vec![],
+            Some(number) => vec![serde_json::json!({ "label": "other", "number": number, "primary": true })],
+        };
+        changes.push(format!("moved {} number(s) of `{name}` into `numbers`", numbers.len()));
+        entry.insert("numbers".to_owned(), Value::Array(numbers));
+    }
+    Ok(changes)
+}
+
+fn v2_to_v3(doc: &mut Value) -> Result<Vec<String>> {
+    let mut changes = vec![];
+    let entries = doc
+        .get_mut("phonebook")
+        .and_then(Value::as_array_mut)
+        .ok_or_else(|| Err::PhonebookEntry("Missing phonebook".into()))
+        .with_context(|| "Expected a `phonebook` array")?;
+    for entry in entries.iter_mut() {
+        let Some(text) = entry.get("name").and_then(Value::as_str).map(str::to_owned) else {
+            continue;
+        };
+        let name = crate::Name::parse(&text);
+        let parts: Vec<String> = [
+            ("prefix", &name.prefix),
+            ("given", &name.given),
+            ("middle", &name.middle),
+            ("family", &name.family),
+            ("suffix", &name.suffix),
+            ("nickname", &name.nickname),
+        ]
+        .into_iter()
+        .filter(|(_, part)| !part.is_empty())
+        .map(|(what, part)| format!("{what} `{part}`"))
+        .collect();
+        changes.push(format!("split `{text}` into {}", parts.join(", ")));
+        entry["name"] = serde_json::to_value(&name).map_err(Err::Json)?;
+    }
+    Ok(changes)
+}
+
+#[test]
+fn test_migrate_legacy_file() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    let path = dir.join("book.json");
+    let legacy = r#"[{ "id": 4, "name": "Ada Lovelace", "number": "1" }, { "name": "Dan Abramov", "number": "2" }]"#;
+    std::fs::write(&path, legacy)?;
+
+    let report = migrate_file(&path, true)?;
+    assert_eq!((0, 3), (report.from, report.to));
+    assert_eq!(
+        vec![
+            "wrapped the bare array of entries in `phonebook`",
+            "assigned id 5 to `Dan Abramov`"
+        ],
+        report.steps[0].2
+    );
+    assert_eq!(2, report.steps[1].2.len());
+    assert_eq!(2, report.steps[2].2.len());
+    // A dry run leaves the file alone, but reading it migrates in memory
+    assert_eq!(legacy, std::fs::read_to_string(&path)?);
+    let dan = read_json(&path)?.get_by_name("Dan Abramov").unwrap();
+    assert_eq!((5, "2"), (dan.id, dan.primary_number().unwrap().number.as_str()));
+
+    migrate_file(&path, false)?;
+    assert!(migrate_file(&path, true)?.is_noop());
+    std::fs::write(&path, r#"{ "version": 99, "phonebook": [] }"#)?;
+    assert!(read_json(&path).is_err());
+    Ok(())
+}
@@ -0,0 +1,229 @@
+//! Structured person names.
+//! A `Name` keeps the prefix, given, middle and family names, the suffix and a nickname apart, so duplicate
+//! checks and sorting compare the right parts instead of whitespace separated tokens. Free text such as
+//! `Dr. Mary Ann Smith Jr.` or `smith, john` is split up by `Name::parse`, and requests may send either form.
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt::Display;
+
+/// Titles recognized in front of a name, compared without case and trailing dots
+const PREFIXES: &[&str] = &[
+    "mr", "mrs", "ms", "miss", "mx", "dr", "prof", "sir", "dame", "rev", "fr",
+];
+/// Recognized after a name
+const SUFFIXES: &[&str] = &["jr", "sr", "ii", "iii", "iv", "v", "phd", "md", "esq"];
+/// Lowercase words that belong to the family name following them, as in `Ludwig van Beethoven`
+const PARTICLES: &[&str] = &[
+    "van", "von", "de", "der", "den", "da", "del", "della", "di", "du", "la", "le", "bin", "al",
+];
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq, PartialOrd)]
+pub struct Name {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub prefix: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub given: String,
+    /// Every middle name, space separated
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub middle: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub family: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub suffix: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub nickname: String,
+}
+
+impl Name {
+    /// Split free text into its parts.
+    /// Understands `Given Middle Family`, `Family, Given Middle`, titles such as `Dr.` in front, suffixes such as `Jr.`
+    /// at the end (with or without a comma) and a nickname in quotes or parentheses.
+    pub fn parse(text: &str) -> Self {
+        let mut name = Name::default();
+        let mut text = text.trim().to_owned();
+        // `Robert "Bob" Smith` or `Robert (Bob) Smith`
+        for (open, close) in [('"', '"'), ('(', ')')] {
+            if let Some(start) = text.find(open) {
+                if let Some(len) = text[start + 1..].find(close) {
+                    name.nickname = collapse(&text[start + 1..start + 1 + len]);
+                    text.replace_range(start..start + len + 2, " ");
+                    break;
+                }
+            }
+        }
+        let mut parts: Vec<String> = text.split(',').map(collapse).filter(|p| !p.is_empty()).collect();
+        // `John Smith, Jr.`
+        let mut suffixes = vec![];
+        while parts.len() > 1 && is_suffix(parts.last().expect("More than one part")) {
+            suffixes.insert(0, parts.pop().expect("More than one part"));
+        }
+        let mut tokens: Vec<String> = match parts.as_slice() {
+            // `Smith, John Paul` puts the family name first
+            [family, rest, ..] => {
+                name.family = family.clone();
+                rest.split_whitespace().map(str::to_owned).collect()
+            }
+            [all] => all.split_whitespace().map(str::to_owned).collect(),
+            [] => vec![],
+        };
+        while tokens.len() > 1 && is_prefix(&tokens[0]) {
+            let prefix = tokens.remove(0);
+            name.prefix = join(&name.prefix, &prefix);
+        }
+        let has_family = !name.family.is_empty();
+        // A lone token is a given name, not a suffix
+        while tokens.len() > 1 + usize::from(!has_family) && is_suffix(tokens.last().expect("More than one token")) {
+            suffixes.insert(0, tokens.pop().expect("More than one token"));
+        }
+        name.suffix = suffixes.join(" ");
+        if !has_family && tokens.len() > 1 {
+            let mut family = vec![tokens.pop().expect("More than one token")];
+            // Particles only count when there is a given name left in front of them
+            while tokens.len() > 1 && PARTICLES.contains(&tokens.last().expect("More than one token").as_str()) {
+                family.insert(0, tokens.pop().expect("More than one token"));
+            }
+            name.family = family.join(" ");
+        }
+        if !tokens.is_empty() {
+            name.given = tokens.remove(0);
+        }
+        name.middle = tokens.join(" ");
+        name
+    }
+
+    /// Whether no part at all is set, an update with an empty name leaves the name alone
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// A person needs at least a given or a family name
+    pub fn is_valid(&self) -> bool {
+        !self.given.trim().is_empty() || !self.family.trim().is_empty()
+    }
+
+    /// Trim every part and collapse inner whitespace
+    pub fn normalize(&mut self) {
+        for part in [
+            &mut self.prefix,
+            &mut self.given,
+            &mut self.middle,
+            &mut self.family,
+            &mut self.suffix,
+            &mut self.nickname,
+        ] {
+            *part = collapse(part);
+        }
+    }
+
+    /// How the name is shown, e.g. `Dr. Martin Luther King Jr.`
+    pub fn display_name(&self) -> String {
+        [&self.prefix, &self.given, &self.middle, &self.family, &self.suffix]
+            .into_iter()
+            .filter(|part| !part.is_empty())
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// What duplicate checks compare: given, middle and family names and the suffix, ignoring case and dots.
+    /// Titles and nicknames don't make a different person.
+    pub fn key(&self) -> String {
+        [&self.given, &self.middle, &self.family, &self.suffix]
+            .map(|part| comparable(part))
+            .join("|")
+    }
+
+    /// Family name first, for sorting. Names without one sort by their given name.
+    pub fn sort_key(&self) -> (String, String, String, String) {
+        let family = if self.family.is_empty() { &self.given } else { &self.family };
+        (
+            comparable(family),
+            comparable(&self.given),
+            comparable(&self.middle),
+            comparable(&self.suffix),
+        )
+    }
+}
+
+impl Display for Name {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.display_name())
+    }
+}
+
+impl From<&str> for Name {
+    fn from(text: &str) -> Self {
+        Self::parse(text)
+    }
+}
+
+/// Accept a name as free text as well as in parts, for `#[serde(deserialize_with)]`
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Name, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Text(String),
+        Parts(Name),
+    }
+    Ok(match Repr::deserialize(deserializer)? {
+        Repr::Text(text) => Name::parse(&text),
+        Repr::Parts(name) => name,
+    })
+}
+
+fn collapse(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn join(a: &str, b: &str) -> String {
+    if a.is_empty() {
+        b.to_owned()
+    } else {
+        format!("{a} {b}")
+    }
+}
+
+/// Lowercased, without dots and with single spaces
+fn comparable(part: &str) -> String {
+    collapse(&part.replace('.', " ")).to_lowercase()
+}
+
+fn is_prefix(token: &str) -> bool {
+    PREFIXES.contains(&comparable(token).as_str())
+}
+
+fn is_suffix(token: &str) -> bool {
+    SUFFIXES.contains(&comparable(token).as_str())
+}
+
+#[test]
+fn test_parse_names() {
+    let parse = |text| {
+        let n = Name::parse(text);
+        [n.prefix, n.given, n.middle, n.family, n.suffix, n.nickname]
+    };
+    assert_eq!(["", "Ada", "", "Lovelace", "", ""], parse("  Ada   Lovelace "));
+    assert_eq!(["", "john", "", "smith", "", ""], parse("smith, john"));
+    assert_eq!(
+        ["Dr.", "Martin", "Luther", "King", "Jr.", ""],
+        parse("Dr. Martin Luther King Jr.")
+    );
+    assert_eq!(["", "John", "", "Smith", "Jr.", ""], parse("John Smith, Jr."));
+    assert_eq!(
+        ["", "Ludwig", "", "van Beethoven", "", ""],
+        parse("Ludwig van Beethoven")
+    );
+    assert_eq!(["", "Robert", "", "Smith", "", "Bob"], parse(r#"Robert "Bob" Smith"#));
+    assert_eq!(["", "Cher", "", "", "", ""], parse("Cher"));
+    assert_eq!(["", "Dr", "", "", "", ""], parse("Dr"));
+
+    // Middle names tell people apart, the order they were written in does not
+    let key = |text| Name::parse(text).key();
+    assert_ne!(key("Mary Ann Smith"), key("Mary Jo Smith"));
+    assert_eq!(key("John Smith"), key("smith, john"));
+    assert_eq!(key("Mr. John Smith"), key("John (Johnny) Smith"));
+    assert_ne!(key("John Smith Jr."), key("John Smith Sr."));
+    assert_eq!(
+        "Martin Luther King Jr.",
+        Name::parse("King, Martin Luther, Jr.").display_name()
+    );
+}
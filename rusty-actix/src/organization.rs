@@ -0,0 +1,220 @@
+//! Organizations such as the vendors and customers people in the book work for, with their name, main number,
+//! address and departments. A `Person` references at most one organization by id, and optionally one of its departments.
+//! Deleting an organization or dropping one of its departments detaches the people that referenced it.
+use crate::{Address, Err, Person};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt::Display;
+
+pub type OrganizationID = u128;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Organization {
+    #[serde(default)]
+    pub id: OrganizationID,
+    pub name: String,
+    /// As entered, e.g. `+44 20 7946 0958`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub main_number: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<Address>,
+    /// In the order they were given
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub departments: Vec<String>,
+}
+
+impl Organization {
+    /// Trim the name, number and departments and check them. Requires a name, a number needs digits
+    /// and departments must be distinct regardless of case.
+    pub fn normalize(&mut self) -> Result<()> {
+        self.name = collapse(&self.name);
+        if self.name.is_empty() {
+            return Err(Err::PhonebookEntry("Name missing".into()))
+                .with_context(|| "An organization should have a name");
+        }
+        self.main_number = self
+            .main_number
+            .take()
+            .map(|n| n.trim().to_owned())
+            .filter(|n| !n.is_empty());
+        if let Some(number) = &self.main_number {
+            if !number.chars().any(|c| c.is_ascii_digit()) {
+                return Err(Err::PhonebookEntry("Empty number".into()))
+                    .with_context(|| format!("The main number of {} has no digits", self.name));
+            }
+        }
+        if let Some(address) = &mut self.address {
+            address.normalize()?;
+        }
+        let mut seen = HashSet::with_capacity(self.departments.len());
+        for department in &mut self.departments {
+            *department = collapse(department);
+            if department.is_empty() || !seen.insert(department.to_lowercase()) {
+                return Err(Err::PhonebookEntry("Invalid department".into()))
+                    .with_context(|| format!("{} needs distinct, non-blank department names", self.name));
+            }
+        }
+        Ok(())
+    }
+
+    /// What duplicate name checks compare, `ACME  Corp` and `acme corp` are the same organization
+    pub fn key(&self) -> String {
+        collapse(&self.name).to_lowercase()
+    }
+
+    /// The department called `name`, matched without case, as the organization lists it
+    pub fn department(&self, name: &str) -> Option<&str> {
+        let name = collapse(name);
+        self.departments
+            .iter()
+            .find(|d| d.eq_ignore_ascii_case(&name))
+            .map(String::as_str)
+    }
+}
+
+impl Display for Organization {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{{ name: {} id: {}", self.name, self.id)?;
+        if let Some(number) = &self.main_number {
+            write!(f, " main number: {number}")?;
+        }
+        if let Some(address) = &self.address {
+            write!(f, " address: {address}")?;
+        }
+        if !self.departments.is_empty() {
+            write!(f, " departments: [{}]", self.departments.join(", "))?;
+        }
+        write!(f, " }}")
+    }
+}
+
+fn collapse(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Check the organization and department of `person` against `organization`, the organization it references if that
+/// exists, and bring the department into the case the organization lists it in
+pub fn check(person: &mut Person, organization: Option<&Organization>) -> Result<()> {
+    person.department = person.department.as_deref().map(collapse).filter(|d| !d.is_empty());
+    let Some(id) = person.organization else {
+        if person.department.is_some() {
+            return Err(Err::PhonebookEntry("Department without organization".into()))
+                .with_context(|| format!("{} has a department but no organization", person.name));
+        }
+        return Ok(());
+    };
+    let organization = organization
+        .filter(|o| o.id == id)
+        .ok_or_else(|| Err::NotFound(format!("organization {id}")))
+        .with_context(|| format!("{} references organization {id}, which does not exist", person.name))?;
+    if let Some(department) = &person.department {
+        let canonical = organization
+            .department(department)
+            .ok_or_else(|| Err::PhonebookEntry("Unknown department".into()))
+            .with_context(|| format!("{} has no department `{department}`", organization.name))?;
+        person.department = Some(canonical.to_owned());
+    }
+    Ok(())
+}
+
+/// Drop the organization or department of `person` that `organization`, the organization it references if that still
+/// exists, no longer has. Returns whether anything was dropped.
+pub fn forget_missing(person: &mut Person, organization: Option<&Organization>) -> bool {
+    let Some(id) = person.organization else {
+        return false;
+    };
+    match organization.filter(|o| o.id == id) {
+        None => {
+            person.organization = None;
+            person.department = None;
+            true
+        }
+        Some(organization) => match &person.department {
+            Some(department) if organization.department(department).is_none() => {
+                person.department = None;
+                true
+            }
+            _ => false,
+        },
+    }
+}
+
+#[test]
+fn test_people_belong_to_organizations() -> Result<()> {
+    let mut book = crate::JsonFile::default();
+    let acme = Organization {
+        name: " ACME   Corp ".into(),
+        main_number: Some("+1 555 0100".into()),
+        departments: vec!["Sales".into(), "Support".into()],
+        ..Default::default()
+    };
+    let acme = book.add_organization(acme)?;
+    assert_eq!("ACME Corp", book.organization(acme).unwrap().name);
+    for bad in [
+        Organization {
+            name: "acme corp".into(),
+            ..Default::default()
+        },
+        Organization {
+            name: "Initech".into(),
+            main_number: Some("n/a".into()),
+            ..Default::default()
+        },
+        Organization {
+            name: "Initech".into(),
+            departments: vec!["IT".into(), "it".into()],
+            ..Default::default()
+        },
+    ] {
+        assert!(book.add_organization(bad.clone()).is_err(), "{bad} passed");
+    }
+    let initech = book.add_organization(Organization {
+        name: "Initech".into(),
+        ..Default::default()
+    })?;
+
+    let at = |organization, department: Option<&str>| Person {
+        organization: Some(organization),
+        department: department.map(Into::into),
+        ..Default::default()
+    };
+    let ada = book.add_to_phonebook(person!("Ada Lovelace", "39-44-5323523"))?;
+    let dan = book.add_to_phonebook(Person {
+        name: crate::Name::parse("Dan Abramov"),
+        ..at(acme, Some("sales"))
+    })?;
+    assert_eq!(Some("Sales"), book.get_by_id(dan).unwrap().department.as_deref());
+    assert!(book.update(ada, at(acme, Some("Legal"))).is_err());
+    assert!(book.update(ada, at(99, None)).is_err());
+    book.update(ada, at(acme, Some("Support")))?;
+    let members: Vec<_> = book.members(acme)?.iter().map(|p| p.id).collect();
+    assert_eq!(vec![ada, dan], members);
+
+    // Moving in bulk is all or nothing, and only counts the entries that actually moved
+    assert!(book.move_to(&[ada, 99], Some(initech), None).is_err());
+    assert!(book.move_to(&[ada], Some(initech), Some("Sales")).is_err());
+    assert_eq!(1, book.move_to(&[ada, dan], Some(acme), Some("Sales"))?);
+    assert_eq!(2, book.move_to(&[ada, dan], Some(initech), None)?);
+    assert!(book.members(acme)?.is_empty());
+    assert!(book.members(99).is_err());
+
+    // Dropping a department or the whole organization detaches its people
+    book.move_to(&[ada], Some(acme), Some("Support"))?;
+    book.update_organization(
+        acme,
+        Organization {
+            name: "ACME Corp".into(),
+            departments: vec!["Sales".into()],
+            ..Default::default()
+        },
+    )?;
+    let ada_now = book.get_by_id(ada).unwrap();
+    assert_eq!((Some(acme), None), (ada_now.organization, ada_now.department));
+    book.delete(dan)?;
+    book.delete_organization(initech)?;
+    assert!(book.organization(initech).is_none());
+    book.restore(dan)?;
+    assert_eq!(None, book.get_by_id(dan).unwrap().organization);
+    Ok(())
+}
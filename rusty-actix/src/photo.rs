@@ -0,0 +1,191 @@
+//! Contact photos, kept as files next to the data file rather than inside it.
+//! An upload is checked, shrunk to fit `PHOTO_SIZE` and cut into a square `THUMBNAIL_SIZE` thumbnail, both saved as
+//! JPEG under `photos/<id>.jpg` and `photos/<id>-thumb.jpg`. Like the phonebook itself, they are encrypted at rest
+//! once a key is configured.
+use crate::{Err, PersonID};
+use anyhow::{Context, Result};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, ImageReader, Limits, Rgb, RgbImage};
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+
+/// Largest upload accepted, in bytes
+pub const MAX_PHOTO_BYTES: usize = 5 * 1024 * 1024;
+/// Longest side of a stored photo, in pixels
+pub const PHOTO_SIZE: u32 = 512;
+/// Side of the square thumbnails, in pixels
+pub const THUMBNAIL_SIZE: u32 = 128;
+/// Guards the decoder against images claiming absurd dimensions in a few bytes
+const MAX_DIMENSION: u32 = 8192;
+const JPEG_QUALITY: u8 = 85;
+
+/// The default photos directory for a data file: `files/mock.json` -> `files/photos`
+pub fn photos_dir_for(path: &Path) -> PathBuf {
+    path.with_file_name("photos")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    Photo,
+    Thumbnail,
+}
+
+#[derive(Debug, Clone)]
+pub struct Photos {
+    dir: PathBuf,
+}
+
+impl Photos {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Check the JPEG or PNG in `bytes` and store it, along with its thumbnail, as the photo of `id`.
+    /// Replaces any photo `id` already had.
+    pub fn save(&self, id: PersonID, bytes: &[u8]) -> Result<()> {
+        let (photo, thumbnail) = resize(bytes).with_context(|| format!("Rejected the photo of id {id}"))?;
+        std::fs::create_dir_all(&self.dir)
+            .map_err(Err::Io)
+            .with_context(|| format!("Failed to create photos directory `{}`", self.dir.display()))?;
+        for (variant, image) in [(Variant::Thumbnail, thumbnail), (Variant::Photo, photo)] {
+            let mut bytes = encode(&image)?;
+            if let Some(key) = crate::crypto::key() {
+                bytes = key.seal(&bytes)?;
+            }
+            let path = self.path(id, variant);
+            crate::write_atomic(&path, |file| crate::write_bytes_and_sync(file, &bytes))
+                .with_context(|| format!("Failed to save the photo of id {id}"))?;
+        }
+        log::info!("Saved the photo of id {id} in `{}`", self.dir.display());
+        Ok(())
+    }
+
+    /// The JPEG bytes of the photo of `id`, `None` if it has none
+    pub fn load(&self, id: PersonID, variant: Variant) -> Result<Option<Vec<u8>>> {
+        let path = self.path(id, variant);
+        let bytes = match std::fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(Err::Io(err)).with_context(|| format!("Failed to read `{}`", path.display())),
+        };
+        Ok(Some(crate::crypto::decode(&bytes, &path)?.into_owned()))
+    }
+
+    /// Remove the photo of `id`. Returns whether it had one.
+    pub fn remove(&self, id: PersonID) -> Result<bool> {
+        let mut removed = false;
+        for variant in [Variant::Photo, Variant::Thumbnail] {
+            let path = self.path(id, variant);
+            remove_file(&crate::lock::lock_path_for(&path))?;
+            removed |= remove_file(&path)?;
+        }
+        Ok(removed)
+    }
+
+    fn path(&self, id: PersonID, variant: Variant) -> PathBuf {
+        match variant {
+            Variant::Photo => self.dir.join(format!("{id}.jpg")),
+            Variant::Thumbnail => self.dir.join(format!("{id}-thumb.jpg")),
+        }
+    }
+}
+
+/// Returns whether there was a file to remove
+fn remove_file(path: &Path) -> Result<bool> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(Err::Io(err)).with_context(|| format!("Failed to remove `{}`", path.display())),
+    }
+}
+
+/// Decode an upload and scale it down to the stored photo and its thumbnail
+fn resize(bytes: &[u8]) -> Result<(RgbImage, RgbImage)> {
+    if bytes.len() > MAX_PHOTO_BYTES {
+        return Err(Err::PhonebookEntry("Photo too large".into())).with_context(|| {
+            format!(
+                "The photo is {} bytes, photos can be up to {MAX_PHOTO_BYTES} bytes",
+                bytes.len()
+            )
+        });
+    }
+    // Sniffed from the bytes themselves, whatever the upload claimed to be
+    let format = image::guess_format(bytes)
+        .ok()
+        .filter(|f| matches!(f, ImageFormat::Jpeg | ImageFormat::Png))
+        .ok_or_else(|| Err::PhonebookEntry("Unsupported photo type".into()))
+        .with_context(|| "Photos must be JPEG or PNG images")?;
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    let mut reader = ImageReader::with_format(Cursor::new(bytes), format);
+    reader.limits(limits);
+    let image = match reader.decode() {
+        Ok(image) => image,
+        Err(e) => {
+            return Err(Err::PhonebookEntry("Invalid photo".into()))
+                .with_context(|| format!("Failed to decode the {format:?} photo: {e}"))
+        }
+    };
+    let image = DynamicImage::ImageRgb8(flatten(&image));
+    let photo = if image.width() > PHOTO_SIZE || image.height() > PHOTO_SIZE {
+        image.resize(PHOTO_SIZE, PHOTO_SIZE, FilterType::Lanczos3)
+    } else {
+        image.clone()
+    };
+    let thumbnail = image.resize_to_fill(THUMBNAIL_SIZE, THUMBNAIL_SIZE, FilterType::Lanczos3);
+    Ok((photo.into_rgb8(), thumbnail.into_rgb8()))
+}
+
+/// JPEG has no transparency, so transparent pixels are laid over white rather than turning black
+fn flatten(image: &DynamicImage) -> RgbImage {
+    let rgba = image.to_rgba8();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let over_white = |c: u8| ((c as u16 * a as u16 + 255 * (255 - a as u16)) / 255) as u8;
+        Rgb([over_white(r), over_white(g), over_white(b)])
+    })
+}
+
+fn encode(image: &RgbImage) -> Result<Vec<u8>> {
+    let mut bytes = vec![];
+    image
+        .write_with_encoder(JpegEncoder::new_with_quality(&mut bytes, JPEG_QUALITY))
+        .map_err(|e| Err::Io(io::Error::other(e)))
+        .with_context(|| "Failed to encode photo")?;
+    Ok(bytes)
+}
+
+#[test]
+fn test_photos_are_resized_and_validated() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    let photos = Photos::new(dir);
+    let mut png = vec![];
+    DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1024, 768, image::Rgba([200, 40, 40, 128])))
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+    photos.save(7, &png)?;
+    let photo = image::load_from_memory(&photos.load(7, Variant::Photo)?.unwrap())?;
+    assert_eq!((PHOTO_SIZE, 384), (photo.width(), photo.height()));
+    let thumbnail = image::load_from_memory(&photos.load(7, Variant::Thumbnail)?.unwrap())?;
+    assert_eq!(
+        (THUMBNAIL_SIZE, THUMBNAIL_SIZE),
+        (thumbnail.width(), thumbnail.height())
+    );
+    // Half transparent red over white, give or take JPEG
+    assert!(thumbnail.to_rgb8().get_pixel(64, 64).0[1] > 120);
+
+    assert!(photos.save(8, b"GIF89a not a photo").is_err());
+    assert!(photos.save(8, &png[..png.len() / 2]).is_err());
+    assert!(photos.save(8, &vec![0; MAX_PHOTO_BYTES + 1]).is_err());
+    assert!(photos.load(8, Variant::Photo)?.is_none());
+    assert!(photos.remove(7)?);
+    assert!(!photos.remove(7)?);
+    assert!(photos.load(7, Variant::Thumbnail)?.is_none());
+    Ok(())
+}
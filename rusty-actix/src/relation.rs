@@ -0,0 +1,159 @@
+//! Typed relationships between entries of the same phonebook: an assistant, a manager, a spouse, an emergency contact.
+//! A relationship lives on the entry it describes, `{"kind": "assistant", "id": 7}` on an entry says entry 7 is its
+//! assistant, and only ever points at an entry that exists. Deleting an entry unlinks it from every entry relating to it.
+use crate::{Err, Person, PersonID};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum RelationKind {
+    Assistant,
+    EmergencyContact,
+    Manager,
+    Spouse,
+}
+
+impl RelationKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RelationKind::Assistant => "assistant",
+            RelationKind::EmergencyContact => "emergency_contact",
+            RelationKind::Manager => "manager",
+            RelationKind::Spouse => "spouse",
+        }
+    }
+}
+
+impl std::str::FromStr for RelationKind {
+    type Err = Err;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "assistant" => Ok(RelationKind::Assistant),
+            "emergency_contact" => Ok(RelationKind::EmergencyContact),
+            "manager" => Ok(RelationKind::Manager),
+            "spouse" => Ok(RelationKind::Spouse),
+            _ => Err(Err::PhonebookEntry(format!("Unknown relationship `{s}`"))),
+        }
+    }
+}
+
+impl Display for RelationKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The entry `id` is the `kind` of the entry carrying this
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Relation {
+    pub kind: RelationKind,
+    pub id: PersonID,
+}
+
+impl Display for Relation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: #{}", self.kind, self.id)
+    }
+}
+
+/// An entry along with the entries it relates to, as returned by `GET /book/{id}/related`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Expanded {
+    #[serde(flatten)]
+    pub person: Person,
+    pub related: Vec<Related>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Related {
+    pub kind: RelationKind,
+    pub person: Person,
+}
+
+/// Check that every relationship of `person` points at another entry for which `exists` holds
+pub fn check(person: &Person, mut exists: impl FnMut(PersonID) -> Result<bool>) -> Result<()> {
+    for relation in &person.relations {
+        if relation.id == person.id {
+            return Err(Err::PhonebookEntry("Related to itself".into()))
+                .with_context(|| format!("{} can't be their own {}", person.name, relation.kind));
+        }
+        if !exists(relation.id)? {
+            return Err(Err::NotFound(format!("id {}", relation.id))).with_context(|| {
+                format!(
+                    "Can't make id {} the {} of {}, it does not exist in the phonebook",
+                    relation.id, relation.kind, person.name
+                )
+            });
+        }
+    }
+    Ok(())
+}
+
+/// `person` with the entries it relates to, looked up with `get`. Entries that are gone are left out.
+pub fn expand(person: Person, mut get: impl FnMut(PersonID) -> Result<Option<Person>>) -> Result<Expanded> {
+    let mut related = Vec::with_capacity(person.relations.len());
+    for relation in &person.relations {
+        if let Some(other) = get(relation.id)? {
+            related.push(Related {
+                kind: relation.kind,
+                person: other,
+            });
+        }
+    }
+    Ok(Expanded { person, related })
+}
+
+#[test]
+fn test_relations_follow_their_entries() -> Result<()> {
+    let mut book = crate::JsonFile::default();
+    let ada = book.add_to_phonebook(person!("Ada Lovelace", "39-44-5323523"))?;
+    let relate = |kind, id| vec![Relation { kind, id }];
+    assert!(book
+        .add_to_phonebook(Person {
+            relations: relate(RelationKind::Manager, 99),
+            ..person!("Dan Abramov", "12-43-234345")
+        })
+        .is_err());
+    let dan = book.add_to_phonebook(Person {
+        relations: [relate(RelationKind::Manager, ada), relate(RelationKind::Manager, ada)].concat(),
+        ..person!("Dan Abramov", "12-43-234345")
+    })?;
+    assert_eq!(
+        relate(RelationKind::Manager, ada),
+        book.get_by_id(dan).unwrap().relations
+    );
+    assert!(book
+        .update(
+            ada,
+            Person {
+                relations: relate(RelationKind::Spouse, ada),
+                ..Default::default()
+            }
+        )
+        .is_err());
+    book.update(
+        ada,
+        Person {
+            relations: relate(RelationKind::Assistant, dan),
+            ..Default::default()
+        },
+    )?;
+    let expanded = book.expand(ada)?.unwrap();
+    assert_eq!(
+        (RelationKind::Assistant, dan),
+        (expanded.related[0].kind, expanded.related[0].person.id)
+    );
+    assert!(serde_json::to_string(&expanded)?.contains(r#""related":[{"kind":"assistant","person":{"id":2"#));
+
+    // Deleting one side unlinks the other, restoring doesn't bring back links to entries that are gone
+    let revision = book.get_by_id(dan).unwrap().revision;
+    book.delete(ada)?;
+    let unlinked = book.get_by_id(dan).unwrap();
+    assert_eq!((0, revision + 1), (unlinked.relations.len(), unlinked.revision));
+    book.delete(dan)?;
+    book.restore(ada)?;
+    assert!(book.get_by_id(ada).unwrap().relations.is_empty());
+    Ok(())
+}
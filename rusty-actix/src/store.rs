@@ -0,0 +1,1235 @@
+//! Storage backends for the phonebook.
+//! The HTTP layer only ever talks to a `PhonebookStore`, so a backend can be swapped
+//! without touching any handler. `JsonFileStore` is the journalled JSON file the server
+//! has always used, `MemoryStore` keeps everything in RAM and is handy for tests and demos,
+//! `SqliteStore` is meant for books that no longer fit comfortably in memory.
+use crate::backup::Backups;
+use crate::journal::{self, journal_path_for, Journal, JournalEntry};
+use crate::{
+    read_json_unchecked, read_or_create_json, write_json, Expanded, Group, JsonFile, Organization, OrganizationID,
+    Person, PersonID, Schema,
+};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use parking_lot::{Mutex, RwLock};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+mod sqlite;
+pub use sqlite::SqliteStore;
+
+/// Everything the server needs from a storage backend.
+/// Methods take `&self` so a single store can be shared between actix workers, implementations
+/// are expected to synchronize internally. All methods may block and should be called off the async runtime.
+pub trait PhonebookStore: Send + Sync {
+    /// Fetch a person by their id
+    fn get(&self, id: PersonID) -> Result<Option<Person>>;
+    /// Fetch a person by their name, see `JsonFile::get_by_name` for the matching rules
+    fn get_by_name(&self, name: &str) -> Result<Option<Person>>;
+    /// Every entry in the phonebook, sorted by id
+    fn list(&self) -> Result<Vec<Person>>;
+    /// Add a new entry, returning the id it was assigned
+    fn add(&self, p: Person) -> Result<PersonID>;
+    /// Edit a pre-existing entry, empty fields of `p` are left untouched
+    fn update(&self, id: PersonID, p: Person) -> Result<()>;
+    /// Fetch a person by their id along with the entries they relate to, see `relation::expand`
+    fn expand(&self, id: PersonID) -> Result<Option<Expanded>>;
+    /// Move an entry to the trash and unlink it from the entries relating to it, a missing id is an `Err::NotFound`
+    fn delete(&self, id: PersonID) -> Result<()>;
+    /// Deleted entries, in the order they were deleted
+    fn trash(&self) -> Result<Vec<Person>>;
+    /// Bring an entry back from the trash under its original id, see `JsonFile::restore`
+    fn restore(&self, id: PersonID) -> Result<()>;
+    /// Permanently remove the entries deleted before `before`, returning how many
+    fn purge(&self, before: DateTime<Utc>) -> Result<usize>;
+    /// The custom fields entries may carry
+    fn schema(&self) -> Result<Schema>;
+    /// Replace the custom field schema, see `JsonFile::set_schema`
+    fn set_schema(&self, schema: Schema) -> Result<()>;
+    /// Every entry carrying `tag`, sorted by id
+    fn tagged(&self, tag: &str) -> Result<Vec<Person>>;
+    /// Every tag in use with its number of members, sorted by name
+    fn groups(&self) -> Result<Vec<Group>>;
+    /// Add `tag` to every entry in `ids` at once, see `JsonFile::tag`
+    fn tag(&self, tag: &str, ids: &[PersonID]) -> Result<usize>;
+    /// Remove `tag` from every entry in `ids` at once, see `JsonFile::untag`
+    fn untag(&self, tag: &str, ids: &[PersonID]) -> Result<usize>;
+    /// Every organization, sorted by id
+    fn organizations(&self) -> Result<Vec<Organization>>;
+    fn organization(&self, id: OrganizationID) -> Result<Option<Organization>>;
+    /// Add an organization, returning the id it was assigned
+    fn add_organization(&self, organization: Organization) -> Result<OrganizationID>;
+    /// Replace an organization as a whole, see `JsonFile::update_organization`
+    fn update_organization(&self, id: OrganizationID, organization: Organization) -> Result<()>;
+    /// Remove an organization, its people stay without one
+    fn delete_organization(&self, id: OrganizationID) -> Result<()>;
+    /// Everyone in the organization `id`, sorted by id
+    fn members(&self, id: OrganizationID) -> Result<Vec<Person>>;
+    /// Move every entry in `ids` at once, see `JsonFile::move_to`
+    fn move_to(
+        &self,
+        ids: &[PersonID],
+        organization: Option<OrganizationID>,
+        department: Option<&str>,
+    ) -> Result<usize>;
+    /// Replace the whole book, trash, schema and organizations included, in one go, e.g. when restoring a backup.
+    /// `json_file` is checked with `JsonFile::validate` first and nothing changes if it fails.
+    /// Readers either see the old book or the new one, never a mix of both.
+    fn replace_all(&self, json_file: JsonFile) -> Result<()>;
+    /// The whole book, trash, schema and organizations included, e.g. to back it up or carry it over to another store
+    fn export(&self) -> Result<JsonFile> {
+        Ok(JsonFile {
+            version: crate::migrate::CURRENT_VERSION,
+            checksum: None,
+            phonebook: self.list()?,
+            trash: self.trash()?,
+            schema: self.schema()?,
+            organizations: self.organizations()?,
+        })
+    }
+    /// Make sure every change so far is persisted in the backend's canonical form
+    fn flush(&self) -> Result<()>;
+    /// `flush` and let go of the backend's files, e.g. before they get moved or removed. No other call may follow.
+    fn close(&self) -> Result<()> {
+        self.flush()
+    }
+}
+
+/// A phonebook that only lives in memory, nothing survives a restart
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    book: RwLock<JsonFile>,
+}
+
+impl MemoryStore {
+    pub fn new(mut json_file: JsonFile) -> Self {
+        json_file.sort();
+        Self {
+            book: RwLock::new(json_file),
+        }
+    }
+}
+
+impl PhonebookStore for MemoryStore {
+    fn get(&self, id: PersonID) -> Result<Option<Person>> {
+        Ok(self.book.read().get_by_id(id))
+    }
+    fn get_by_name(&self, name: &str) -> Result<Option<Person>> {
+        Ok(self.book.read().get_by_name(name))
+    }
+    fn list(&self) -> Result<Vec<Person>> {
+        Ok(self.book.read().persons().to_vec())
+    }
+    fn add(&self, p: Person) -> Result<PersonID> {
+        self.book.write().add_to_phonebook(p)
+    }
+    fn update(&self, id: PersonID, p: Person) -> Result<()> {
+        self.book.write().update(id, p)
+    }
+    fn expand(&self, id: PersonID) -> Result<Option<Expanded>> {
+        self.book.read().expand(id)
+    }
+    fn delete(&self, id: PersonID) -> Result<()> {
+        self.book.write().delete(id)
+    }
+    fn trash(&self) -> Result<Vec<Person>> {
+        Ok(self.book.read().trash().to_vec())
+    }
+    fn restore(&self, id: PersonID) -> Result<()> {
+        self.book.write().restore(id)
+    }
+    fn purge(&self, before: DateTime<Utc>) -> Result<usize> {
+        Ok(self.book.write().purge(before))
+    }
+    fn schema(&self) -> Result<Schema> {
+        Ok(self.book.read().schema().clone())
+    }
+    fn set_schema(&self, schema: Schema) -> Result<()> {
+        self.book.write().set_schema(schema)
+    }
+    fn tagged(&self, tag: &str) -> Result<Vec<Person>> {
+        self.book.read().tagged(tag)
+    }
+    fn groups(&self) -> Result<Vec<Group>> {
+        Ok(self.book.read().groups())
+    }
+    fn tag(&self, tag: &str, ids: &[PersonID]) -> Result<usize> {
+        self.book.write().tag(tag, ids)
+    }
+    fn untag(&self, tag: &str, ids: &[PersonID]) -> Result<usize> {
+        self.book.write().untag(tag, ids)
+    }
+    fn organizations(&self) -> Result<Vec<Organization>> {
+        Ok(self.book.read().organizations().to_vec())
+    }
+    fn organization(&self, id: OrganizationID) -> Result<Option<Organization>> {
+        Ok(self.book.read().organization(id).cloned())
+    }
+    fn add_organization(&self, organization: Organization) -> Result<OrganizationID> {
+        self.book.write().add_organization(organization)
+    }
+    fn update_organization(&self, id: OrganizationID, organization: Organization) -> Result<()> {
+        self.book.write().update_organization(id, organization)
+    }
+    fn delete_organization(&self, id: OrganizationID) -> Result<()> {
+        self.book.write().delete_organization(id)
+    }
+    fn members(&self, id: OrganizationID) -> Result<Vec<Person>> {
+        self.book.read().members(id)
+    }
+    fn move_to(
+        &self,
+        ids: &[PersonID],
+        organization: Option<OrganizationID>,
+        department: Option<&str>,
+    ) -> Result<usize> {
+        self.book.write().move_to(ids, organization, department)
+    }
+    fn replace_all(&self, mut json_file: JsonFile) -> Result<()> {
+        json_file.validate()?;
+        json_file.sort();
+        *self.book.write() = json_file;
+        Ok(())
+    }
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The JSON file at `path` plus its journal, see `phonebook::journal`.
+/// Mutations are appended to the journal and folded into the file on `flush` or once the journal grows large.
+/// With `backups` set, the file is backed up every time before it gets overwritten.
+#[derive(Debug)]
+pub struct JsonFileStore {
+    path: PathBuf,
+    book: RwLock<JsonFile>,
+    journal: Mutex<Journal>,
+    backups: Option<Backups>,
+    // Whether a file whose checksum no longer matches is taken for a hand edit rather than for corruption
+    accept_edits: bool,
+    // Lets `reload` tell our own writes apart from edits made by someone else
+    stamp: Mutex<Option<FileStamp>>,
+}
+
+/// What the data file looked like the last time this store read or wrote it
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FileStamp {
+    modified: SystemTime,
+    len: u64,
+}
+
+impl FileStamp {
+    fn of(path: &Path) -> Option<Self> {
+        let meta = std::fs::metadata(path).ok()?;
+        Some(Self {
+            modified: meta.modified().ok()?,
+            len: meta.len(),
+        })
+    }
+}
+
+/// Outcome of `JsonFileStore::reload`
+#[derive(Debug, PartialEq)]
+pub enum Reload {
+    /// The file is exactly as this store last left it
+    Unchanged,
+    /// The file was edited externally and its contents replaced the in-memory book
+    Reloaded,
+    /// The file was edited externally while the store had unsaved changes.
+    /// The external version was moved aside to the given path and the in-memory book was saved over it.
+    Conflict(PathBuf),
+    /// The file failed its checksum and hand edits are not accepted.
+    /// It was moved aside to the given path and the in-memory book was saved over it.
+    Rejected(PathBuf),
+}
+
+impl JsonFileStore {
+    /// Load the snapshot at `path` and bring it up to date with its journal.
+    /// A missing snapshot is created as an empty phonebook. A corrupt one is replaced by the newest valid backup,
+    /// if there is one, and kept aside for inspection. See `read_data_file` for what `accept_edits` changes.
+    pub fn open(path: &Path, backups: Option<Backups>, accept_edits: bool) -> Result<Self> {
+        let read = if path.exists() {
+            read_data_file(path, accept_edits)
+        } else {
+            read_or_create_json(path)
+        };
+        let mut json_file = match (read, &backups) {
+            (Ok(json_file), _) => json_file,
+            (Err(e), Some(backups)) if is_corruption(&e) => recover(path, backups, e)?,
+            (Err(e), _) => return Err(e),
+        };
+        json_file.sort();
+        let journal = Journal::open(&journal_path_for(path))?;
+        journal.replay(&mut json_file)?;
+        let store = Self {
+            path: path.to_path_buf(),
+            book: RwLock::new(json_file),
+            journal: Mutex::new(journal),
+            backups,
+            accept_edits,
+            stamp: Mutex::new(FileStamp::of(path)),
+        };
+        store.flush()?;
+        Ok(store)
+    }
+
+    /// The data file backing this store
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Pick up changes made to the data file by someone else, e.g. by hand.
+    /// The new contents are validated and only swapped in if there are no unsaved changes,
+    /// otherwise neither side is lost: the external version is kept next to the data file.
+    /// A file failing its checksum is treated like `open` treats it, see `read_data_file`.
+    pub fn reload(&self) -> Result<Reload> {
+        let mut book = self.book.write();
+        let mut journal = self.journal.lock();
+        let current = FileStamp::of(&self.path);
+        // A missing file is most likely the middle of an editor's save dance, wait for it to reappear
+        if current.is_none() || current == *self.stamp.lock() {
+            return Ok(Reload::Unchanged);
+        }
+        if !journal.is_empty() {
+            let timestamp = chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ");
+            let mut conflict = self.path.as_os_str().to_owned();
+            conflict.push(format!(".conflict-{timestamp}"));
+            let conflict = PathBuf::from(conflict);
+            std::fs::copy(&self.path, &conflict)
+                .map_err(crate::Err::Io)
+                .with_context(|| format!("Failed to set aside conflicting `{}`", self.path.display()))?;
+            log::error!(
+                "`{}` was edited externally while {} changes were unsaved. Kept the in-memory phonebook, \
+                 the external version was moved to `{}`",
+                self.path.display(),
+                journal.len(),
+                conflict.display()
+            );
+            self.compact(&mut journal, &book)?;
+            return Ok(Reload::Conflict(conflict));
+        }
+        let mut json_file = match read_data_file(&self.path, self.accept_edits) {
+            Ok(json_file) => json_file,
+            Err(e) if is_corruption(&e) => {
+                let aside = set_aside(&self.path, "corrupt")?;
+                write_json(&self.path, &book)?;
+                *self.stamp.lock() = FileStamp::of(&self.path);
+                log::error!(
+                    "Rejected `{}`: {e:#}. Kept the in-memory phonebook, the rejected file was moved to `{}`",
+                    self.path.display(),
+                    aside.display()
+                );
+                return Ok(Reload::Rejected(aside));
+            }
+            Err(e) => return Err(e),
+        };
+        json_file
+            .validate()
+            .with_context(|| format!("Refusing to reload invalid `{}`", self.path.display()))?;
+        json_file.sort();
+        *book = json_file;
+        *self.stamp.lock() = FileStamp::of(&self.path);
+        log::info!("Reloaded externally edited `{}`", self.path.display());
+        Ok(Reload::Reloaded)
+    }
+
+    /// Back up the current file if a backup is due, then fold the journal into a fresh one
+    fn compact(&self, journal: &mut Journal, book: &JsonFile) -> Result<()> {
+        if let Some(backups) = &self.backups {
+            backups.snapshot_if_due(&self.path)?;
+        }
+        journal.compact(&self.path, book)?;
+        *self.stamp.lock() = FileStamp::of(&self.path);
+        Ok(())
+    }
+
+    /// Run `mutation` and journal the resulting entry.
+    /// Both happen under the phonebook write lock so the journal order always matches the in-memory order.
+    fn commit<T>(&self, mutation: impl FnOnce(&mut JsonFile) -> Result<(T, JournalEntry)>) -> Result<T> {
+        let mut book = self.book.write();
+        let (ret, entry) = mutation(&mut book)?;
+        let mut journal = self.journal.lock();
+        journal.append(&entry)?;
+        if journal.len() >= journal::JOURNAL_COMPACT_THRESHOLD {
+            self.compact(&mut journal, &book)?;
+        }
+        Ok(ret)
+    }
+}
+
+/// Whether `err` means the data file's contents are damaged, as opposed to e.g. unreadable or encrypted with another key
+fn is_corruption(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<crate::Err>(),
+        Some(crate::Err::Corrupt(_) | crate::Err::Json(_))
+    )
+}
+
+/// Read the data file at `path`, the one rule `open` and `reload` share for files failing their checksum.
+/// Such a file is corrupt, unless `accept_edits` is set and its contents pass `JsonFile::validate`:
+/// it is then taken for a hand edit and saved again with a fresh checksum.
+fn read_data_file(path: &Path, accept_edits: bool) -> Result<JsonFile> {
+    let json_file = read_json_unchecked(path)?;
+    let Err(mismatch) = json_file.verify_checksum() else {
+        return Ok(json_file);
+    };
+    let mismatch = mismatch.context(format!("`{}` failed its integrity check", path.display()));
+    if !accept_edits {
+        return Err(mismatch);
+    }
+    if let Err(e) = json_file.validate() {
+        log::error!("Not accepting `{}` as a hand edit: {e:#}", path.display());
+        return Err(mismatch);
+    }
+    write_json(path, &json_file)?;
+    log::warn!("{mismatch:#}. Accepted it as a hand edit and refreshed its checksum");
+    Ok(json_file)
+}
+
+/// Move the file at `path` aside to `<file>.<what>-<timestamp>`
+fn set_aside(path: &Path, what: &str) -> Result<PathBuf> {
+    let timestamp = chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ");
+    let mut aside = path.as_os_str().to_owned();
+    aside.push(format!(".{what}-{timestamp}"));
+    let aside = PathBuf::from(aside);
+    std::fs::rename(path, &aside)
+        .map_err(crate::Err::Io)
+        .with_context(|| format!("Failed to move {what} `{}` aside", path.display()))?;
+    Ok(aside)
+}
+
+/// Replace the corrupt data file at `path` with the newest backup that loads and passes its checks.
+/// The corrupt file is kept next to it as `<file>.corrupt-<timestamp>`.
+fn recover(path: &Path, backups: &Backups, err: anyhow::Error) -> Result<JsonFile> {
+    log::error!("`{}` is corrupt: {err:#}", path.display());
+    let mut recovered = None;
+    for backup in backups.list()? {
+        match backups.load(&backup.name).and_then(|f| f.validate().map(|_| f)) {
+            Ok(json_file) => {
+                recovered = Some((backup.name, json_file));
+                break;
+            }
+            Err(e) => log::error!("Backup `{}` is not usable either: {e:#}", backup.name),
+        }
+    }
+    let Some((name, json_file)) = recovered else {
+        return Err(err).with_context(|| format!("`{}` is corrupt and there is no valid backup", path.display()));
+    };
+    let aside = set_aside(path, "corrupt")?;
+    write_json(path, &json_file)?;
+    log::error!(
+        "RECOVERED `{}` from backup `{name}` with {} entries, changes made after that backup are lost. \
+         The corrupt file was kept at `{}`",
+        path.display(),
+        json_file.persons().len(),
+        aside.display()
+    );
+    Ok(json_file)
+}
+
+impl PhonebookStore for JsonFileStore {
+    fn get(&self, id: PersonID) -> Result<Option<Person>> {
+        Ok(self.book.read().get_by_id(id))
+    }
+    fn get_by_name(&self, name: &str) -> Result<Option<Person>> {
+        Ok(self.book.read().get_by_name(name))
+    }
+    fn list(&self) -> Result<Vec<Person>> {
+        Ok(self.book.read().persons().to_vec())
+    }
+    fn add(&self, p: Person) -> Result<PersonID> {
+        // The journal records the entry with the id it was assigned, so replaying it reproduces the same book
+        self.commit(|book| {
+            let id = book.add_to_phonebook(p)?;
+            let person = book.get_by_id(id).expect("Entry was added right above");
+            Ok((id, JournalEntry::Add { person }))
+        })
+    }
+    fn update(&self, id: PersonID, p: Person) -> Result<()> {
+        self.commit(|book| {
+            let at = chrono::Utc::now();
+            book.update_at(id, p.clone(), at)?;
+            let revision = book.get_by_id(id).expect("Entry was updated right above").revision;
+            let entry = JournalEntry::Update {
+                id,
+                person: p,
+                revision,
+                at: Some(at),
+            };
+            Ok(((), entry))
+        })
+    }
+    fn expand(&self, id: PersonID) -> Result<Option<Expanded>> {
+        self.book.read().expand(id)
+    }
+    fn delete(&self, id: PersonID) -> Result<()> {
+        self.commit(|book| {
+            let at = Utc::now();
+            book.delete_at(id, at)?;
+            Ok(((), JournalEntry::Delete { id, at: Some(at) }))
+        })
+    }
+    fn trash(&self) -> Result<Vec<Person>> {
+        Ok(self.book.read().trash().to_vec())
+    }
+    fn restore(&self, id: PersonID) -> Result<()> {
+        self.commit(|book| {
+            let at = Utc::now();
+            book.restore_at(id, at)?;
+            let revision = book.get_by_id(id).expect("Entry was restored right above").revision;
+            Ok(((), JournalEntry::Restore { id, revision, at }))
+        })
+    }
+    fn purge(&self, before: DateTime<Utc>) -> Result<usize> {
+        self.commit(|book| Ok((book.purge(before), JournalEntry::Purge { before })))
+    }
+    fn schema(&self) -> Result<Schema> {
+        Ok(self.book.read().schema().clone())
+    }
+    fn set_schema(&self, schema: Schema) -> Result<()> {
+        self.commit(|book| {
+            let at = Utc::now();
+            book.set_schema_at(schema.clone(), at)?;
+            Ok(((), JournalEntry::Schema { schema, at }))
+        })
+    }
+    fn tagged(&self, tag: &str) -> Result<Vec<Person>> {
+        self.book.read().tagged(tag)
+    }
+    fn groups(&self) -> Result<Vec<Group>> {
+        Ok(self.book.read().groups())
+    }
+    fn tag(&self, tag: &str, ids: &[PersonID]) -> Result<usize> {
+        self.commit(|book| {
+            let at = chrono::Utc::now();
+            let changed = book.tag_at(tag, ids, at)?;
+            let entry = JournalEntry::Tag {
+                tag: crate::tag::normalize(tag)?,
+                ids: ids.to_vec(),
+                at: Some(at),
+            };
+            Ok((changed, entry))
+        })
+    }
+    fn untag(&self, tag: &str, ids: &[PersonID]) -> Result<usize> {
+        self.commit(|book| {
+            let at = chrono::Utc::now();
+            let changed = book.untag_at(tag, ids, at)?;
+            let entry = JournalEntry::Untag {
+                tag: crate::tag::normalize(tag)?,
+                ids: ids.to_vec(),
+                at: Some(at),
+            };
+            Ok((changed, entry))
+        })
+    }
+    fn organizations(&self) -> Result<Vec<Organization>> {
+        Ok(self.book.read().organizations().to_vec())
+    }
+    fn organization(&self, id: OrganizationID) -> Result<Option<Organization>> {
+        Ok(self.book.read().organization(id).cloned())
+    }
+    fn add_organization(&self, organization: Organization) -> Result<OrganizationID> {
+        self.commit(|book| {
+            let id = book.add_organization(organization)?;
+            let organization = book
+                .organization(id)
+                .expect("Organization was added right above")
+                .clone();
+            Ok((
+                id,
+                JournalEntry::Organization {
+                    organization,
+                    at: Utc::now(),
+                },
+            ))
+        })
+    }
+    fn update_organization(&self, id: OrganizationID, organization: Organization) -> Result<()> {
+        self.commit(|book| {
+            let at = Utc::now();
+            book.update_organization_at(id, organization, at)?;
+            let organization = book
+                .organization(id)
+                .expect("Organization was updated right above")
+                .clone();
+            Ok(((), JournalEntry::Organization { organization, at }))
+        })
+    }
+    fn delete_organization(&self, id: OrganizationID) -> Result<()> {
+        self.commit(|book| {
+            let at = Utc::now();
+            book.delete_organization_at(id, at)?;
+            Ok(((), JournalEntry::DeleteOrganization { id, at }))
+        })
+    }
+    fn members(&self, id: OrganizationID) -> Result<Vec<Person>> {
+        self.book.read().members(id)
+    }
+    fn move_to(
+        &self,
+        ids: &[PersonID],
+        organization: Option<OrganizationID>,
+        department: Option<&str>,
+    ) -> Result<usize> {
+        self.commit(|book| {
+            let at = Utc::now();
+            let changed = book.move_to_at(ids, organization, department, at)?;
+            // The department as the organization lists it, like it ended up on the entries
+            let department = department
+                .and_then(|d| organization.and_then(|id| book.organization(id))?.department(d))
+                .map(Into::into);
+            let entry = JournalEntry::Move {
+                ids: ids.to_vec(),
+                organization,
+                department,
+                at,
+            };
+            Ok((changed, entry))
+        })
+    }
+    fn replace_all(&self, mut json_file: JsonFile) -> Result<()> {
+        json_file.validate()?;
+        json_file.sort();
+        let mut book = self.book.write();
+        let mut journal = self.journal.lock();
+        // Pending journal entries describe the book being replaced, so fold them in before it gets backed up
+        if !journal.is_empty() {
+            self.compact(&mut journal, &book)?;
+        }
+        if let Some(backups) = &self.backups {
+            backups.snapshot(&self.path)?;
+        }
+        write_json(&self.path, &json_file)?;
+        *self.stamp.lock() = FileStamp::of(&self.path);
+        *book = json_file;
+        Ok(())
+    }
+    fn flush(&self) -> Result<()> {
+        let book = self.book.read();
+        let mut journal = self.journal.lock();
+        if !journal.is_empty() {
+            self.compact(&mut journal, &book)?;
+        }
+        Ok(())
+    }
+}
+
+/// One empty store of each kind, for the tests that hold them all to the same behaviour
+#[allow(unused)]
+fn stores(dir: &Path) -> Result<Vec<Box<dyn PhonebookStore>>> {
+    let path = dir.join("book.json");
+    write_json(&path, &JsonFile::default())?;
+    Ok(vec![
+        Box::new(MemoryStore::default()),
+        Box::new(JsonFileStore::open(&path, None, false)?),
+        Box::new(SqliteStore::open(&dir.join("book.db"), None)?),
+    ])
+}
+
+#[test]
+fn test_stores_behave_alike() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    let ids = |persons: Vec<Person>| persons.iter().map(|p| p.id).collect::<Vec<_>>();
+    for store in stores(dir)? {
+        let id = store.add(person!("Ada Lovelace", "39-44-5323523"))?;
+        assert!(store.add(person!("ada   lovelace", "1")).is_err());
+        assert!(store.add(person!("Lovelace, Ada", "1")).is_err());
+        assert_eq!(Some(id), store.get_by_name("Ada Lovelace")?.map(|p| p.id));
+        store.update(id, person!("", "000"))?;
+        assert_eq!("000", store.get(id)?.unwrap().numbers[0].number);
+        assert!(store.update(99, person!("", "000")).is_err());
+        // Nor can an edit take over the name of another entry
+        let dan = store.add(person!("Dan Abramov", "12-43-234345"))?;
+        let renamed = Person {
+            name: crate::Name::parse("Lovelace, Ada"),
+            ..Default::default()
+        };
+        assert!(store.update(dan, renamed).is_err());
+        assert_eq!(Some(dan), store.get_by_name("Dan Abramov")?.map(|p| p.id));
+        assert_eq!(vec![id, dan], ids(store.list()?));
+        store.delete(dan)?;
+        assert!(store.delete(dan).is_err());
+        assert_eq!(None, store.get(dan)?);
+        assert_eq!(vec![id], ids(store.list()?));
+        store.flush()?;
+    }
+    // The JSON backend folded its journal into the file on flush
+    assert!(!journal_path_for(&dir.join("book.json")).exists());
+    Ok(())
+}
+
+#[test]
+fn test_stores_replace_numbers_as_a_whole() -> Result<()> {
+    use crate::{PhoneLabel, PhoneNumber};
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    let number = |label, number: &str, primary| PhoneNumber {
+        label,
+        number: number.into(),
+        primary,
+        ..Default::default()
+    };
+    let with_numbers = |numbers| Person {
+        numbers,
+        ..Default::default()
+    };
+    for store in stores(dir)? {
+        // Kept in the order given, the first one is primary unless another one is
+        let id = store.add(Person {
+            numbers: vec![
+                number(PhoneLabel::Office, "020 7946 0958", false),
+                number(PhoneLabel::Mobile, "07700 900123", false),
+            ],
+            ..person!("Ada Lovelace", "1")
+        })?;
+        let numbers = |store: &dyn PhonebookStore| -> Result<Vec<(PhoneLabel, String, bool)>> {
+            let numbers = store.get(id)?.unwrap().numbers;
+            Ok(numbers.into_iter().map(|n| (n.label, n.number, n.primary)).collect())
+        };
+        let added = vec![
+            (PhoneLabel::Office, "020 7946 0958".to_owned(), true),
+            (PhoneLabel::Mobile, "07700 900123".to_owned(), false),
+        ];
+        assert_eq!(added, numbers(store.as_ref())?);
+
+        // An update without numbers leaves them alone
+        store.update(
+            id,
+            Person {
+                name: crate::Name::parse("Ada King"),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(added, numbers(store.as_ref())?);
+
+        // Numbers that differ only in formatting are the same number, an extension makes them different ones
+        let duplicate = vec![
+            number(PhoneLabel::Office, "+44 20 7946 0958", false),
+            number(PhoneLabel::Home, "+442079460958", false),
+        ];
+        assert!(store.update(id, with_numbers(duplicate)).is_err());
+        assert!(store
+            .update(id, with_numbers(vec![number(PhoneLabel::Fax, "n/a", false)]))
+            .is_err());
+        assert_eq!(added, numbers(store.as_ref())?);
+
+        // Only one of them can be primary
+        let primaries = vec![
+            number(PhoneLabel::Office, "020 7946 0958", true),
+            number(PhoneLabel::Mobile, "07700 900123", true),
+        ];
+        assert!(store.update(id, with_numbers(primaries)).is_err());
+
+        // A list given replaces the whole list, it is never merged into the old one
+        let mut extension = number(PhoneLabel::Office, "020 7946 0958", false);
+        extension.extension = Some("12".into());
+        store.update(
+            id,
+            with_numbers(vec![
+                number(PhoneLabel::Office, "020 7946 0958", false),
+                extension,
+                number(PhoneLabel::Mobile, "07700 900999", true),
+            ]),
+        )?;
+        let ada = store.get(id)?.unwrap();
+        assert_eq!(3, ada.numbers.len());
+        assert_eq!(Some("12"), ada.numbers[1].extension.as_deref());
+        assert_eq!(Some("07700 900999"), ada.primary_number().map(|n| n.number.as_str()));
+        assert!(!ada.numbers.iter().any(|n| n.number == "07700 900123"));
+
+        // Clients from before there were several numbers send one `number`, it becomes the only, primary one
+        let legacy = |json| serde_json::from_str::<Person>(json);
+        let upgraded = vec![(PhoneLabel::Other, "4413".to_owned(), true)];
+        store.update(id, legacy(r#"{"name": "", "number": "4413"}"#)?)?;
+        assert_eq!(upgraded, numbers(store.as_ref())?);
+        let mary = store.add(legacy(r#"{"name": "Mary Smith", "number": "39-23-6423122"}"#)?)?;
+        assert_eq!("39-23-6423122", store.get(mary)?.unwrap().numbers[0].number);
+        let both = r#"{"name": "", "number": "1", "numbers": [{"number": "2"}]}"#;
+        assert!(store.update(id, legacy(both)?).is_err());
+        assert_eq!(upgraded, numbers(store.as_ref())?);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_stores_keep_contact_details() -> Result<()> {
+    use crate::{Address, Email, Label, Website};
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    for store in stores(dir)? {
+        let id = store.add(person!("Ada Lovelace", "39-44-5323523"))?;
+        // Contact details are validated and stored along with the rest
+        let mut details = Person::default();
+        details.emails.push(Email {
+            address: "ada@".into(),
+            ..Default::default()
+        });
+        assert!(store.update(id, details.clone()).is_err());
+        details.emails[0].address = " ada@example.com".into();
+        details.addresses.push(Address {
+            label: Label::Work,
+            city: Some("London".into()),
+            ..Default::default()
+        });
+        details.urls.push(Website {
+            url: "https://example.com".into(),
+            ..Default::default()
+        });
+        store.update(id, details)?;
+        let ada = store.get(id)?.unwrap();
+        assert_eq!(
+            ("ada@example.com", Some("London"), "https://example.com/"),
+            (
+                ada.emails[0].address.as_str(),
+                ada.addresses[0].city.as_deref(),
+                ada.urls[0].url.as_str()
+            )
+        );
+        assert_eq!(1, ada.numbers.len());
+        // An explicit `[]` clears a list, one left out stays as it is
+        store.update(id, serde_json::from_str(r#"{"name": "", "emails": []}"#)?)?;
+        let ada = store.get(id)?.unwrap();
+        assert_eq!((0, 1, 1), (ada.emails.len(), ada.addresses.len(), ada.urls.len()));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_stores_tag_and_group_entries() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    for store in stores(dir)? {
+        let id = store.add(person!("Ada Lovelace", "39-44-5323523"))?;
+        // Tags are canonical on input and groups follow the tags
+        let dan = store.add(Person {
+            tags: vec!["Vendor".into(), "on call".into()],
+            ..person!("Dan Abramov", "12-43-234345")
+        })?;
+        assert_eq!(vec!["on-call", "vendor"], store.get(dan)?.unwrap().tags);
+        assert!(store.tag("on-call", &[id, 99]).is_err());
+        let revision = store.get(id)?.unwrap().revision;
+        assert_eq!(1, store.tag("on-call", &[id, dan])?);
+        assert_eq!(revision + 1, store.get(id)?.unwrap().revision);
+        assert_eq!(
+            vec![id, dan],
+            store.tagged("on-call")?.iter().map(|p| p.id).collect::<Vec<_>>()
+        );
+        assert_eq!(1, store.untag("vendor", &[id, dan])?);
+        assert_eq!(
+            vec![Group {
+                name: "on-call".into(),
+                members: 2
+            }],
+            store.groups()?
+        );
+        assert_eq!(1, store.tagged("on-call")?[0].numbers.len());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_stores_keep_timestamps_and_revisions() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    let mut saved = vec![];
+    for store in stores(dir)? {
+        // Metadata sent along with a new entry is replaced, not trusted
+        let before = Utc::now();
+        let id = store.add(Person {
+            created_at: Some(before - chrono::TimeDelta::days(400)),
+            revision: 40,
+            ..person!("Ada Lovelace", "39-44-5323523")
+        })?;
+        let dan = store.add(person!("Dan Abramov", "12-43-234345"))?;
+        let added = store.get(id)?.unwrap();
+        assert_eq!(1, added.revision);
+        assert!(added.created_at.is_some_and(|at| at >= before));
+        assert_eq!(added.created_at, added.updated_at);
+        assert_eq!(None, added.deleted_at);
+
+        // A change bumps the revision and the update time, never the creation time
+        let checkpoint = store.get(dan)?.unwrap().updated_at.unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        store.update(id, person!("", "000"))?;
+        let updated = store.get(id)?.unwrap();
+        assert_eq!(2, updated.revision);
+        assert_eq!(added.created_at, updated.created_at);
+        assert!(updated.updated_at > added.updated_at);
+        // Only what changed after the checkpoint shows up as modified since
+        let modified: Vec<_> = store
+            .list()?
+            .into_iter()
+            .filter(|p| p.modified_since(checkpoint))
+            .map(|p| p.id)
+            .collect();
+        assert_eq!(vec![id], modified);
+        assert!(!store.get(dan)?.unwrap().modified_since(checkpoint));
+
+        // Neither an edit that changes nothing nor stale metadata sent along counts as a change
+        store.update(
+            id,
+            Person {
+                revision: 1,
+                updated_at: Some(checkpoint),
+                ..person!("", "000")
+            },
+        )?;
+        assert_eq!(updated, store.get(id)?.unwrap());
+        store.flush()?;
+        saved.push(store.list()?);
+    }
+    // What was stamped is what gets saved, the time of each change included
+    let reopened: Vec<Box<dyn PhonebookStore>> = vec![
+        Box::new(JsonFileStore::open(&dir.join("book.json"), None, false)?),
+        Box::new(SqliteStore::open(&dir.join("book.db"), None)?),
+    ];
+    for (store, saved) in reopened.iter().zip(&saved[1..]) {
+        assert_eq!(*saved, store.list()?);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_stores_trash_restore_and_purge() -> Result<()> {
+    use crate::Err;
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    let kind = |result: Result<()>| match result {
+        Ok(()) => "ok".to_owned(),
+        Result::Err(e) => match e.downcast_ref::<Err>() {
+            Some(Err::NotFound(_)) => "not found".to_owned(),
+            Some(Err::PhonebookEntry(_)) => "rejected".to_owned(),
+            _ => format!("{e:#}"),
+        },
+    };
+    let ids = |persons: Vec<Person>| persons.iter().map(|p| p.id).collect::<Vec<_>>();
+    let pause = || std::thread::sleep(std::time::Duration::from_millis(2));
+    for store in stores(dir)? {
+        let ada = store.add(person!("Ada Lovelace", "39-44-5323523"))?;
+        let dan = store.add(person!("Dan Abramov", "12-43-234345"))?;
+        let mary = store.add(person!("Mary Smith", "3"))?;
+
+        // Deleted entries wait in the trash, in the order they were deleted
+        store.delete(dan)?;
+        pause();
+        let cutoff = Utc::now();
+        pause();
+        store.delete(ada)?;
+        assert_eq!(vec![dan, ada], ids(store.trash()?));
+        assert!(store.trash()?.iter().all(|p| p.deleted_at.is_some()));
+        assert_eq!("not found", kind(store.delete(ada)));
+        assert_eq!("not found", kind(store.restore(mary)));
+
+        // A restore never takes over the name of an entry added since
+        let other = store.add(person!("Ada Lovelace", "1"))?;
+        assert_eq!("rejected", kind(store.restore(ada)));
+        store.delete(other)?;
+        store.restore(ada)?;
+        let restored = store.get(ada)?.unwrap();
+        assert_eq!((None, 2), (restored.deleted_at, restored.revision));
+        assert_eq!("39-44-5323523", restored.numbers[0].number);
+        assert_eq!(vec![ada, mary], ids(store.list()?));
+        assert_eq!(vec![dan, other], ids(store.trash()?));
+        assert_eq!("not found", kind(store.restore(ada)));
+
+        // Purges only remove what was deleted before the cutoff, and what is purged is gone for good
+        assert_eq!(0, store.purge(cutoff - chrono::TimeDelta::days(1))?);
+        assert_eq!(1, store.purge(cutoff)?);
+        assert_eq!(vec![other], ids(store.trash()?));
+        assert_eq!("not found", kind(store.restore(dan)));
+        assert_eq!(None, store.get(dan)?);
+        // Ids in the trash stay reserved
+        assert!(store.add(person!("Dan Abramov", "12-43-234345"))? > other);
+        assert_eq!(1, store.purge(Utc::now())?);
+        assert!(store.trash()?.is_empty());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_stores_follow_the_schema() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    for store in stores(dir)? {
+        let id = store.add(person!("Ada Lovelace", "39-44-5323523"))?;
+        let dan = store.add(person!("Dan Abramov", "12-43-234345"))?;
+        // Custom fields follow the schema of the book, and a new schema is checked against every entry
+        let schema: Schema = serde_json::from_str(
+            r#"{"fields": [{"name": "pager", "type": "string"}, {"name": "cost_centre", "type": "enum", "values": ["CC-1"]}]}"#,
+        )?;
+        store.set_schema(schema.clone())?;
+        assert_eq!(schema, store.schema()?);
+        let fields = |json: &str| -> Result<Person> {
+            Ok(Person {
+                fields: serde_json::from_str(json)?,
+                ..Default::default()
+            })
+        };
+        assert!(store.update(id, fields(r#"{"shoe_size": "44"}"#)?).is_err());
+        store.update(id, fields(r#"{"pager": "555", "cost_centre": "cc-1"}"#)?)?;
+        assert_eq!(
+            Some(&crate::FieldValue::Text("CC-1".into())),
+            store.get(id)?.unwrap().fields.get("cost_centre")
+        );
+        let mut required = schema.clone();
+        required.fields[1].required = true;
+        assert!(store.set_schema(required.clone()).is_err());
+        store.update(dan, fields(r#"{"cost_centre": "CC-1"}"#)?)?;
+        store.set_schema(required)?;
+        assert!(store.add(person!("Mary Smith", "3")).is_err());
+        // Fields left out of the schema are dropped from the entries
+        let mut fewer = schema.clone();
+        fewer.fields.remove(0);
+        store.set_schema(fewer)?;
+        assert_eq!(1, store.get(id)?.unwrap().fields.len());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_stores_relate_entries() -> Result<()> {
+    use crate::{Relation, RelationKind};
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    for store in stores(dir)? {
+        let id = store.add(person!("Ada Lovelace", "39-44-5323523"))?;
+        let dan = store.add(person!("Dan Abramov", "12-43-234345"))?;
+        // Relationships point at existing entries, and deleting one side unlinks the other
+        let manager = |id| Person {
+            relations: vec![Relation {
+                kind: RelationKind::Manager,
+                id,
+            }],
+            ..Default::default()
+        };
+        assert!(store.update(id, manager(99)).is_err());
+        assert!(store.update(id, manager(id)).is_err());
+        store.update(id, manager(dan))?;
+        let expanded = store.expand(id)?.unwrap();
+        assert_eq!(
+            vec![(RelationKind::Manager, dan)],
+            expanded
+                .related
+                .iter()
+                .map(|r| (r.kind, r.person.id))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(None, store.expand(99)?);
+        // An explicit `[]` takes them all away
+        store.update(id, serde_json::from_str(r#"{"name": "", "relations": []}"#)?)?;
+        assert!(store.expand(id)?.unwrap().related.is_empty());
+        store.update(id, manager(dan))?;
+        store.delete(dan)?;
+        assert!(store.get(id)?.unwrap().relations.is_empty());
+        // Restoring the other side doesn't bring the relationship back
+        store.restore(dan)?;
+        assert!(store.get(id)?.unwrap().relations.is_empty());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_stores_move_people_between_organizations() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    for store in stores(dir)? {
+        let id = store.add(person!("Ada Lovelace", "39-44-5323523"))?;
+        let dan = store.add(person!("Dan Abramov", "12-43-234345"))?;
+        // People belong to organizations and move between them in bulk
+        let acme = store.add_organization(Organization {
+            name: "ACME Corp".into(),
+            departments: vec!["Sales".into()],
+            ..Default::default()
+        })?;
+        assert!(store
+            .add_organization(Organization {
+                name: "acme  corp".into(),
+                ..Default::default()
+            })
+            .is_err());
+        let legal = Person {
+            organization: Some(acme),
+            department: Some("Legal".into()),
+            ..Default::default()
+        };
+        assert!(store.update(id, legal).is_err());
+        assert!(store.move_to(&[id, 99], Some(acme), None).is_err());
+        assert_eq!(2, store.move_to(&[id, dan], Some(acme), Some("sales"))?);
+        assert_eq!(0, store.move_to(&[dan], Some(acme), Some("Sales"))?);
+        assert_eq!(Some("Sales"), store.get(dan)?.unwrap().department.as_deref());
+        assert_eq!(
+            vec![id, dan],
+            store.members(acme)?.iter().map(|p| p.id).collect::<Vec<_>>()
+        );
+        store.update_organization(
+            acme,
+            Organization {
+                name: "ACME Corp".into(),
+                main_number: Some("555 0100".into()),
+                ..Default::default()
+            },
+        )?;
+        let moved = store.get(id)?.unwrap();
+        assert_eq!((Some(acme), None), (moved.organization, moved.department));
+        assert_eq!(
+            Some("555 0100"),
+            store.organization(acme)?.unwrap().main_number.as_deref()
+        );
+        store.delete_organization(acme)?;
+        assert!(store.organizations()?.is_empty());
+        assert!(store.members(acme).is_err());
+        assert_eq!(None, store.get(dan)?.unwrap().organization);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_restoring_a_backup_replaces_the_whole_book() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    // A book with a schema, an organization with a member and an entry in the trash
+    let mut saved = JsonFile::default();
+    saved.set_schema(serde_json::from_str(
+        r#"{"fields": [{"name": "pager", "type": "string"}]}"#,
+    )?)?;
+    let acme = saved.add_organization(Organization {
+        name: "ACME Corp".into(),
+        departments: vec!["Sales".into()],
+        ..Default::default()
+    })?;
+    let ada = saved.add_to_phonebook(Person {
+        organization: Some(acme),
+        department: Some("Sales".into()),
+        fields: serde_json::from_str(r#"{"pager": "555"}"#)?,
+        ..person!("Ada Lovelace", "39-44-5323523")
+    })?;
+    let dan = saved.add_to_phonebook(person!("Dan Abramov", "12-43-234345"))?;
+    saved.delete(dan)?;
+    let source = dir.join("saved.json");
+    write_json(&source, &saved)?;
+    let backups = Backups::new(dir.join("backups"), 5);
+    backups.snapshot(&source)?;
+    let backup = backups.list()?.remove(0).name;
+
+    for store in stores(dir)? {
+        store.add(person!("Harry Potter", "4413"))?;
+        store.replace_all(backups.load(&backup)?)?;
+        assert_eq!(saved.persons(), store.list()?.as_slice());
+        assert_eq!(saved.trash(), store.trash()?.as_slice());
+        assert_eq!(saved.schema(), &store.schema()?);
+        assert_eq!(saved.organizations(), store.organizations()?.as_slice());
+        assert_eq!(vec![ada], store.members(acme)?.iter().map(|p| p.id).collect::<Vec<_>>());
+        // The id of the entry in the trash stays reserved
+        assert_eq!(dan + 1, store.add(person!("Harry Potter", "4413"))?);
+
+        // A backup that doesn't hold together is turned down as a whole
+        let mut orphaned = backups.load(&backup)?;
+        orphaned.organizations.clear();
+        assert!(store.replace_all(orphaned).is_err());
+        let mut unfit = backups.load(&backup)?;
+        unfit.schema = Schema::default();
+        assert!(store.replace_all(unfit).is_err());
+        assert_eq!(2, store.list()?.len());
+        assert_eq!(1, store.organizations()?.len());
+    }
+    // The JSON backend saved the restored book as a whole
+    let reopened = JsonFileStore::open(&dir.join("book.json"), None, false)?;
+    assert_eq!(saved.organizations(), reopened.organizations()?.as_slice());
+    assert_eq!(saved.schema(), &reopened.schema()?);
+    Ok(())
+}
+
+#[test]
+fn test_reload_external_edits() -> Result<()> {
+    use crate::read_json;
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    let path = dir.join("book.json");
+    let mut edited = JsonFile::default();
+    write_json(&path, &edited)?;
+    let store = JsonFileStore::open(&path, None, false)?;
+    assert_eq!(Reload::Unchanged, store.reload()?);
+
+    // A hand edit with nothing unsaved is picked up as is
+    edited.add_to_phonebook(person!("Ada Lovelace", "39-44-5323523"))?;
+    write_json(&path, &edited)?;
+    assert_eq!(Reload::Reloaded, store.reload()?);
+    assert_eq!(edited.persons(), store.list()?.as_slice());
+
+    // A hand edit racing an unsaved change keeps both
+    store.add(person!("Dan Abramov", "12-43-234345"))?;
+    edited.add_to_phonebook(person!("Harry Potter", "4413"))?;
+    write_json(&path, &edited)?;
+    let Reload::Conflict(aside) = store.reload()? else {
+        panic!("expected a conflict")
+    };
+    assert!(read_json(&aside)?.get_by_name("Harry Potter").is_some());
+    assert!(read_json(&path)?.get_by_name("Dan Abramov").is_some());
+    assert_eq!(Reload::Unchanged, store.reload()?);
+    Ok(())
+}
+
+#[test]
+fn test_checksum_mismatch_is_judged_alike_on_open_and_reload() -> Result<()> {
+    use crate::read_json;
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    let path = dir.join("book.json");
+    let flip = |from: &str, to: &str| -> Result<()> {
+        let text = std::fs::read_to_string(&path)?.replace(from, to);
+        std::fs::write(&path, text)?;
+        Ok(())
+    };
+    let store = JsonFileStore::open(&path, None, false)?;
+    store.add(person!("Ada Lovelace", "39-44-5323523"))?;
+    store.flush()?;
+
+    // Rejected on reload, just as it would be on open
+    flip("39-44-5323523", "39-44-5323524")?;
+    let Reload::Rejected(aside) = store.reload()? else {
+        panic!("expected the file to be rejected")
+    };
+    assert!(read_json(&aside).is_err());
+    assert!(read_json(&path)?.get_by_name("Ada Lovelace").unwrap().numbers[0].number == "39-44-5323523");
+    drop(store);
+    flip("39-44-5323523", "39-44-5323524")?;
+    assert!(JsonFileStore::open(&path, None, false).is_err());
+
+    // Accepted on open and on reload once hand edits are
+    let store = JsonFileStore::open(&path, None, true)?;
+    assert!(read_json(&path).is_ok());
+    flip("39-44-5323524", "39-44-5323525")?;
+    assert_eq!(Reload::Reloaded, store.reload()?);
+    assert!(read_json(&path)?.get_by_name("Ada Lovelace").unwrap().numbers[0].number == "39-44-5323525");
+    assert_eq!(store.list()?, read_json(&path)?.persons());
+    Ok(())
+}
+
+#[test]
+fn test_corrupt_file_falls_back_to_backup() -> Result<()> {
+    use crate::read_json;
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    let path = dir.join("book.json");
+    // Back up on every flush so that there is a backup holding Ada
+    let backups = Backups::new(dir.join("backups"), 5).every(std::time::Duration::ZERO);
+    let store = JsonFileStore::open(&path, Some(backups.clone()), false)?;
+    store.add(person!("Ada Lovelace", "39-44-5323523"))?;
+    store.flush()?;
+    store.add(person!("Dan Abramov", "12-43-234345"))?;
+    store.flush()?;
+    drop(store);
+
+    // A flipped digit still parses, only the checksum gives it away
+    let text = std::fs::read_to_string(&path)?.replace("12-43-234345", "12-43-234346");
+    std::fs::write(&path, text)?;
+    assert!(read_json(&path).is_err());
+    let store = JsonFileStore::open(&path, Some(backups), false)?;
+    assert!(store.get_by_name("Ada Lovelace")?.is_some());
+    assert!(read_json(&path).is_ok());
+    let kept_aside =
+        std::fs::read_dir(dir)?.any(|e| e.is_ok_and(|e| e.file_name().to_string_lossy().contains(".corrupt-")));
+    assert!(kept_aside);
+    Ok(())
+}
@@ -0,0 +1,1241 @@
+//! A SQLite backed `PhonebookStore` for books too large to keep in a `Vec<Person>` and rewrite on every change.
+//! SQLite is bundled with the binary so it builds offline. Every mutation runs in its own transaction
+//! and names, numbers, emails and tags are indexed. Numbers, tags, custom fields, relationships and the other contact
+//! details of an entry live in tables of their own, the custom field schema is kept in `meta`. Organizations have a
+//! table of their own too, entries reference them through `person.organization_id`.
+//! On first open an existing `mock.json`-style file can be imported once, trash, schema and organizations included.
+use super::PhonebookStore;
+use crate::{
+    organization, read_json, relation, tag, Address, Email, Err, Expanded, FieldValue, Group, JsonFile, Name,
+    Organization, OrganizationID, Person, PersonID, PhoneNumber, Relation, Schema, Website,
+};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use rusqlite::{params, Connection, OptionalExtension, Row, Transaction};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS person (
+        id       INTEGER PRIMARY KEY,
+        -- The display name, the parts follow
+        name     TEXT NOT NULL,
+        -- `Name::key`, which duplicate name checks compare
+        name_key TEXT NOT NULL,
+        prefix   TEXT NOT NULL DEFAULT '',
+        given    TEXT NOT NULL DEFAULT '',
+        middle   TEXT NOT NULL DEFAULT '',
+        family   TEXT NOT NULL DEFAULT '',
+        suffix   TEXT NOT NULL DEFAULT '',
+        nickname TEXT NOT NULL DEFAULT '',
+        created_at TEXT,
+        updated_at TEXT,
+        revision INTEGER NOT NULL DEFAULT 0,
+        organization_id INTEGER,
+        department TEXT
+    );
+    CREATE INDEX IF NOT EXISTS person_name_key ON person (name_key);
+    CREATE TABLE IF NOT EXISTS organization (
+        id          INTEGER PRIMARY KEY,
+        name        TEXT NOT NULL,
+        -- `Organization::key`, which duplicate name checks compare
+        name_key    TEXT NOT NULL,
+        main_number TEXT,
+        -- The address and the departments as JSON, they are only ever read along with the organization
+        address     TEXT,
+        departments TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS organization_name_key ON organization (name_key);
+    CREATE TABLE IF NOT EXISTS phone (
+        person_id INTEGER NOT NULL,
+        position  INTEGER NOT NULL,
+        label     TEXT NOT NULL,
+        number    TEXT NOT NULL,
+        extension TEXT,
+        is_primary INTEGER NOT NULL,
+        PRIMARY KEY (person_id, position)
+    );
+    CREATE INDEX IF NOT EXISTS phone_number ON phone (number);
+    CREATE TABLE IF NOT EXISTS email (
+        person_id INTEGER NOT NULL,
+        position  INTEGER NOT NULL,
+        label     TEXT NOT NULL,
+        address   TEXT NOT NULL,
+        PRIMARY KEY (person_id, position)
+    );
+    CREATE INDEX IF NOT EXISTS email_address ON email (address);
+    CREATE TABLE IF NOT EXISTS address (
+        person_id   INTEGER NOT NULL,
+        position    INTEGER NOT NULL,
+        label       TEXT NOT NULL,
+        street      TEXT,
+        city        TEXT,
+        region      TEXT,
+        postal_code TEXT,
+        country     TEXT,
+        PRIMARY KEY (person_id, position)
+    );
+    CREATE TABLE IF NOT EXISTS url (
+        person_id INTEGER NOT NULL,
+        position  INTEGER NOT NULL,
+        label     TEXT NOT NULL,
+        url       TEXT NOT NULL,
+        PRIMARY KEY (person_id, position)
+    );
+    CREATE TABLE IF NOT EXISTS tag (
+        person_id INTEGER NOT NULL,
+        -- Canonical, see `tag::normalize`
+        tag       TEXT NOT NULL,
+        PRIMARY KEY (person_id, tag)
+    );
+    CREATE INDEX IF NOT EXISTS tag_tag ON tag (tag);
+    CREATE TABLE IF NOT EXISTS field (
+        person_id INTEGER NOT NULL,
+        name      TEXT NOT NULL,
+        -- The `FieldValue` as JSON
+        value     TEXT NOT NULL,
+        PRIMARY KEY (person_id, name)
+    );
+    CREATE INDEX IF NOT EXISTS field_name ON field (name);
+    CREATE TABLE IF NOT EXISTS relation (
+        person_id  INTEGER NOT NULL,
+        -- `RelationKind::as_str`
+        kind       TEXT NOT NULL,
+        related_id INTEGER NOT NULL,
+        PRIMARY KEY (person_id, kind, related_id)
+    );
+    CREATE INDEX IF NOT EXISTS relation_related_id ON relation (related_id);
+    -- Deleted entries with all their details, until they are restored or purged
+    CREATE TABLE IF NOT EXISTS trash (
+        id         INTEGER PRIMARY KEY,
+        deleted_at TEXT NOT NULL,
+        -- The entry as JSON
+        person     TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS meta (
+        key   TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+";
+
+#[derive(Debug)]
+pub struct SqliteStore {
+    // rusqlite connections are Send but not Sync
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Open or create the database at `path`.
+    /// If `import_from` points at an existing JSON phonebook and this database never imported one,
+    /// its entries are copied over in a single transaction.
+    pub fn open(path: &Path, import_from: Option<&Path>) -> Result<Self> {
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .map_err(Err::Io)
+                .with_context(|| format!("Failed to create `{}`", dir.display()))?;
+        }
+        let mut conn = Connection::open(path)
+            .map_err(Err::Sqlite)
+            .with_context(|| format!("Failed to open sqlite database `{}`", path.display()))?;
+        // WAL lets readers carry on while a writer commits
+        conn.pragma_update(None, "journal_mode", "WAL").map_err(Err::Sqlite)?;
+        conn.execute_batch(SCHEMA)
+            .map_err(Err::Sqlite)
+            .with_context(|| "Failed to create the sqlite schema")?;
+        Self::migrate_numbers(&mut conn)?;
+        Self::migrate_names(&mut conn)?;
+        Self::migrate_metadata(&conn)?;
+        Self::migrate_organizations(&conn)?;
+        if let Some(json_path) = import_from.filter(|p| p.exists()) {
+            Self::import_once(&mut conn, json_path)?;
+        }
+        Ok(Self { conn: Mutex::new(conn) })
+    }
+
+    /// Databases created before entries had several numbers kept a single `number` column on `person`,
+    /// move it into `phone` the same way `migrate::v1_to_v2` does for JSON files
+    fn migrate_numbers(conn: &mut Connection) -> Result<()> {
+        if !has_column(conn, "person", "number")? {
+            return Ok(());
+        }
+        let tx = conn.transaction().map_err(Err::Sqlite)?;
+        let moved = tx
+            .execute(
+                "INSERT INTO phone (person_id, position, label, number, extension, is_primary)
+                 SELECT id, 0, 'other', TRIM(number), NULL, 1 FROM person WHERE TRIM(number) != ''",
+                [],
+            )
+            .map_err(Err::Sqlite)?;
+        tx.execute_batch("DROP INDEX IF EXISTS person_number; ALTER TABLE person DROP COLUMN number;")
+            .map_err(Err::Sqlite)
+            .with_context(|| "Failed to drop the old `number` column")?;
+        tx.commit().map_err(Err::Sqlite)?;
+        log::info!("Moved {moved} numbers into the sqlite `phone` table");
+        Ok(())
+    }
+
+    /// Databases created before names were structured only kept the name as entered,
+    /// split it into its parts and recompute the duplicate check key
+    fn migrate_names(conn: &mut Connection) -> Result<()> {
+        if has_column(conn, "person", "given")? {
+            return Ok(());
+        }
+        let tx = conn.transaction().map_err(Err::Sqlite)?;
+        for column in ["prefix", "given", "middle", "family", "suffix", "nickname"] {
+            tx.execute(
+                &format!("ALTER TABLE person ADD COLUMN {column} TEXT NOT NULL DEFAULT ''"),
+                [],
+            )
+            .map_err(Err::Sqlite)?;
+        }
+        let names = {
+            let mut stmt = tx.prepare("SELECT id, name FROM person").map_err(Err::Sqlite)?;
+            let names = stmt
+                .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))
+                .map_err(Err::Sqlite)?
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .map_err(Err::Sqlite)?;
+            names
+        };
+        for (id, name) in &names {
+            update_name(&tx, *id, &Name::parse(name))?;
+        }
+        tx.commit().map_err(Err::Sqlite)?;
+        log::info!("Split {} names into their parts", names.len());
+        Ok(())
+    }
+
+    /// Databases created before entries had timestamps and revisions get the columns, left empty like in JSON files
+    fn migrate_metadata(conn: &Connection) -> Result<()> {
+        if has_column(conn, "person", "revision")? {
+            return Ok(());
+        }
+        conn.execute_batch(
+            "ALTER TABLE person ADD COLUMN created_at TEXT;
+             ALTER TABLE person ADD COLUMN updated_at TEXT;
+             ALTER TABLE person ADD COLUMN revision INTEGER NOT NULL DEFAULT 0;",
+        )
+        .map_err(Err::Sqlite)
+        .with_context(|| "Failed to add the metadata columns")?;
+        log::info!("Added timestamps and revisions to the sqlite `person` table");
+        Ok(())
+    }
+
+    /// Databases created before organizations existed get the columns referencing them, left empty
+    fn migrate_organizations(conn: &Connection) -> Result<()> {
+        if !has_column(conn, "person", "organization_id")? {
+            conn.execute_batch(
+                "ALTER TABLE person ADD COLUMN organization_id INTEGER;
+                 ALTER TABLE person ADD COLUMN department TEXT;",
+            )
+            .map_err(Err::Sqlite)
+            .with_context(|| "Failed to add the organization columns")?;
+            log::info!("Added organizations to the sqlite `person` table");
+        }
+        // Not part of `SCHEMA`, the column doesn't exist yet when that runs on an older database
+        conn.execute_batch("CREATE INDEX IF NOT EXISTS person_organization_id ON person (organization_id);")
+            .map_err(Err::Sqlite)?;
+        Ok(())
+    }
+
+    fn import_once(conn: &mut Connection, json_path: &Path) -> Result<()> {
+        let tx = conn.transaction().map_err(Err::Sqlite)?;
+        let imported = tx
+            .query_row("SELECT value FROM meta WHERE key = 'imported_from'", [], |row| {
+                row.get::<_, String>(0)
+            })
+            .optional()
+            .map_err(Err::Sqlite)?;
+        if let Some(from) = imported {
+            log::debug!("sqlite database already imported `{from}`, skipping import");
+            return Ok(());
+        }
+        let json_file = read_json(json_path)?;
+        json_file
+            .validate()
+            .with_context(|| format!("Refusing to import invalid `{}`", json_path.display()))?;
+        insert_all(&tx, &json_file)?;
+        tx.execute(
+            "INSERT INTO meta (key, value) VALUES ('imported_from', ?1)",
+            params![json_path.display().to_string()],
+        )
+        .map_err(Err::Sqlite)?;
+        tx.commit().map_err(Err::Sqlite)?;
+        log::info!(
+            "Imported {} entries, {} deleted entries and {} organizations from `{}` into sqlite",
+            json_file.persons().len(),
+            json_file.trash().len(),
+            json_file.organizations().len(),
+            json_path.display()
+        );
+        Ok(())
+    }
+}
+
+fn to_sql_id(id: PersonID) -> Result<i64> {
+    i64::try_from(id)
+        .map_err(|_| Err::PhonebookEntry("id out of range".into()))
+        .with_context(|| format!("id {id} does not fit into a sqlite integer"))
+}
+
+/// Tables holding the contact details, tags, custom fields and relationships of an entry, keyed by `person_id`
+const DETAIL_TABLES: [&str; 7] = ["phone", "email", "address", "url", "tag", "field", "relation"];
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+        params![table, column],
+        |row| row.get(0),
+    )
+    .map_err(|e| Err::Sqlite(e).into())
+}
+
+/// Columns read by `person_from_row`
+const PERSON_COLUMNS: &str =
+    "id, prefix, given, middle, family, suffix, nickname, created_at, updated_at, revision, organization_id, department";
+
+/// The entry without its contact details, see `with_details`
+fn person_from_row(row: &Row) -> rusqlite::Result<Person> {
+    Ok(Person {
+        id: row.get::<_, i64>(0)? as PersonID,
+        name: Name {
+            prefix: row.get(1)?,
+            given: row.get(2)?,
+            middle: row.get(3)?,
+            family: row.get(4)?,
+            suffix: row.get(5)?,
+            nickname: row.get(6)?,
+        },
+        created_at: row.get(7)?,
+        updated_at: row.get(8)?,
+        revision: row.get::<_, i64>(9)? as u64,
+        organization: row.get::<_, Option<i64>>(10)?.map(|id| id as OrganizationID),
+        department: row.get(11)?,
+        ..Default::default()
+    })
+}
+
+/// Store the organization and department of the entry `id`
+fn update_organization_of(tx: &Transaction, id: i64, p: &Person) -> Result<()> {
+    tx.execute(
+        "UPDATE person SET organization_id = ?2, department = ?3 WHERE id = ?1",
+        params![id, p.organization.map(to_sql_id).transpose()?, p.department],
+    )
+    .map_err(Err::Sqlite)?;
+    Ok(())
+}
+
+/// Store `name` on the entry `id`, along with its display name and duplicate check key
+fn update_name(tx: &Transaction, id: i64, name: &Name) -> Result<()> {
+    tx.execute(
+        "UPDATE person SET name = ?2, name_key = ?3, prefix = ?4, given = ?5, middle = ?6, family = ?7, suffix = ?8,
+         nickname = ?9 WHERE id = ?1",
+        params![
+            id,
+            name.display_name(),
+            name.key(),
+            name.prefix,
+            name.given,
+            name.middle,
+            name.family,
+            name.suffix,
+            name.nickname
+        ],
+    )
+    .map_err(Err::Sqlite)?;
+    Ok(())
+}
+
+/// Record a change to the entry `id`, see `Person::stamp_updated`
+fn touch(tx: &Transaction, id: i64, at: DateTime<Utc>) -> Result<()> {
+    tx.execute(
+        "UPDATE person SET updated_at = ?2, revision = revision + 1 WHERE id = ?1",
+        params![id, at],
+    )
+    .map_err(Err::Sqlite)?;
+    Ok(())
+}
+
+fn number_from_row(row: &Row) -> rusqlite::Result<PhoneNumber> {
+    let label: String = row.get(0)?;
+    Ok(PhoneNumber {
+        // Only ever written from a `PhoneLabel`
+        label: label.parse().unwrap_or_default(),
+        number: row.get(1)?,
+        extension: row.get(2)?,
+        primary: row.get(3)?,
+    })
+}
+
+fn email_from_row(row: &Row) -> rusqlite::Result<Email> {
+    let label: String = row.get(0)?;
+    Ok(Email {
+        label: label.parse().unwrap_or_default(),
+        address: row.get(1)?,
+    })
+}
+
+fn address_from_row(row: &Row) -> rusqlite::Result<Address> {
+    let label: String = row.get(0)?;
+    Ok(Address {
+        label: label.parse().unwrap_or_default(),
+        street: row.get(1)?,
+        city: row.get(2)?,
+        region: row.get(3)?,
+        postal_code: row.get(4)?,
+        country: row.get(5)?,
+    })
+}
+
+fn website_from_row(row: &Row) -> rusqlite::Result<Website> {
+    let label: String = row.get(0)?;
+    Ok(Website {
+        label: label.parse().unwrap_or_default(),
+        url: row.get(1)?,
+    })
+}
+
+/// The JSON in column `index`, parsed
+fn json_column<T: serde::de::DeserializeOwned>(row: &Row, index: usize) -> rusqlite::Result<T> {
+    let json: String = row.get(index)?;
+    serde_json::from_str(&json)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(e)))
+}
+
+fn field_from_row(row: &Row) -> rusqlite::Result<(String, FieldValue)> {
+    Ok((row.get(0)?, json_column(row, 1)?))
+}
+
+/// Columns read by `organization_from_row`
+const ORGANIZATION_COLUMNS: &str = "id, name, main_number, address, departments";
+
+fn organization_from_row(row: &Row) -> rusqlite::Result<Organization> {
+    Ok(Organization {
+        id: row.get::<_, i64>(0)? as OrganizationID,
+        name: row.get(1)?,
+        main_number: row.get(2)?,
+        address: match row.get::<_, Option<String>>(3)? {
+            Some(_) => Some(json_column(row, 3)?),
+            None => None,
+        },
+        departments: json_column(row, 4)?,
+    })
+}
+
+fn relation_from_row(row: &Row) -> rusqlite::Result<Relation> {
+    let kind: String = row.get(0)?;
+    Ok(Relation {
+        // Only ever written from a `RelationKind`
+        kind: kind
+            .parse()
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e)))?,
+        id: row.get::<_, i64>(1)? as PersonID,
+    })
+}
+
+/// Every row of a detail table belonging to `id`, in the order they were given
+fn details<T>(conn: &Connection, sql: &str, id: i64, from_row: fn(&Row) -> rusqlite::Result<T>) -> Result<Vec<T>> {
+    let mut stmt = conn.prepare_cached(sql).map_err(Err::Sqlite)?;
+    let rows = stmt
+        .query_map(params![id], from_row)
+        .map_err(Err::Sqlite)?
+        .collect::<rusqlite::Result<Vec<T>>>()
+        .map_err(Err::Sqlite)?;
+    Ok(rows)
+}
+
+/// Fill in the contact details, tags, custom fields and relationships of `persons`
+fn with_details(conn: &Connection, mut persons: Vec<Person>) -> Result<Vec<Person>> {
+    for person in &mut persons {
+        let id = to_sql_id(person.id)?;
+        person.numbers = details(
+            conn,
+            "SELECT label, number, extension, is_primary FROM phone WHERE person_id = ?1 ORDER BY position",
+            id,
+            number_from_row,
+        )?;
+        person.emails = details(
+            conn,
+            "SELECT label, address FROM email WHERE person_id = ?1 ORDER BY position",
+            id,
+            email_from_row,
+        )?;
+        person.addresses = details(
+            conn,
+            "SELECT label, street, city, region, postal_code, country FROM address WHERE person_id = ?1 ORDER BY position",
+            id,
+            address_from_row,
+        )?;
+        person.urls = details(
+            conn,
+            "SELECT label, url FROM url WHERE person_id = ?1 ORDER BY position",
+            id,
+            website_from_row,
+        )?;
+        person.tags = details(
+            conn,
+            "SELECT tag FROM tag WHERE person_id = ?1 ORDER BY tag",
+            id,
+            |row| row.get(0),
+        )?;
+        person.fields = details(
+            conn,
+            "SELECT name, value FROM field WHERE person_id = ?1",
+            id,
+            field_from_row,
+        )?
+        .into_iter()
+        .collect();
+        // Kinds sort the same as text, which keeps the order of `Person::normalize`
+        person.relations = details(
+            conn,
+            "SELECT kind, related_id FROM relation WHERE person_id = ?1 ORDER BY kind, related_id",
+            id,
+            relation_from_row,
+        )?;
+    }
+    Ok(persons)
+}
+
+fn insert(tx: &Transaction, p: &Person) -> Result<()> {
+    let id = to_sql_id(p.id)?;
+    tx.execute(
+        "INSERT INTO person (id, name, name_key, created_at, updated_at, revision) VALUES (?1, '', '', ?2, ?3, ?4)",
+        params![id, p.created_at, p.updated_at, p.revision as i64],
+    )
+    .map_err(Err::Sqlite)?;
+    update_name(tx, id, &p.name)?;
+    update_organization_of(tx, id, p)?;
+    insert_details(tx, p)
+}
+
+/// Insert every part of `json_file` into a database that holds none of it.
+/// Both the import and `replace_all` go through here, whatever a `JsonFile` learns to hold must be added too.
+fn insert_all(tx: &Transaction, json_file: &JsonFile) -> Result<()> {
+    if !json_file.schema().is_empty() {
+        tx.execute(
+            "INSERT INTO meta (key, value) VALUES ('schema', ?1)",
+            params![serde_json::to_string(json_file.schema()).map_err(Err::Json)?],
+        )
+        .map_err(Err::Sqlite)?;
+    }
+    for organization in json_file.organizations() {
+        save_organization(tx, &mut organization.clone())?;
+    }
+    for person in json_file.persons() {
+        insert(tx, person)?;
+    }
+    for person in json_file.trash() {
+        save_trash(tx, person)?;
+    }
+    Ok(())
+}
+
+fn insert_details(tx: &Transaction, p: &Person) -> Result<()> {
+    let id = to_sql_id(p.id)?;
+    for (position, n) in p.numbers.iter().enumerate() {
+        tx.execute(
+            "INSERT INTO phone (person_id, position, label, number, extension, is_primary)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![id, position as i64, n.label.as_str(), n.number, n.extension, n.primary],
+        )
+        .map_err(Err::Sqlite)?;
+    }
+    for (position, e) in p.emails.iter().enumerate() {
+        tx.execute(
+            "INSERT INTO email (person_id, position, label, address) VALUES (?1, ?2, ?3, ?4)",
+            params![id, position as i64, e.label.as_str(), e.address],
+        )
+        .map_err(Err::Sqlite)?;
+    }
+    for (position, a) in p.addresses.iter().enumerate() {
+        tx.execute(
+            "INSERT INTO address (person_id, position, label, street, city, region, postal_code, country)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                id,
+                position as i64,
+                a.label.as_str(),
+                a.street,
+                a.city,
+                a.region,
+                a.postal_code,
+                a.country
+            ],
+        )
+        .map_err(Err::Sqlite)?;
+    }
+    for (position, w) in p.urls.iter().enumerate() {
+        tx.execute(
+            "INSERT INTO url (person_id, position, label, url) VALUES (?1, ?2, ?3, ?4)",
+            params![id, position as i64, w.label.as_str(), w.url],
+        )
+        .map_err(Err::Sqlite)?;
+    }
+    for t in &p.tags {
+        tx.execute("INSERT INTO tag (person_id, tag) VALUES (?1, ?2)", params![id, t])
+            .map_err(Err::Sqlite)?;
+    }
+    for r in &p.relations {
+        tx.execute(
+            "INSERT INTO relation (person_id, kind, related_id) VALUES (?1, ?2, ?3)",
+            params![id, r.kind.as_str(), to_sql_id(r.id)?],
+        )
+        .map_err(Err::Sqlite)?;
+    }
+    insert_fields(tx, id, &p.fields)
+}
+
+fn insert_fields(tx: &Transaction, id: i64, fields: &BTreeMap<String, FieldValue>) -> Result<()> {
+    for (name, value) in fields {
+        tx.execute(
+            "INSERT INTO field (person_id, name, value) VALUES (?1, ?2, ?3)",
+            params![id, name, serde_json::to_string(value).map_err(Err::Json)?],
+        )
+        .map_err(Err::Sqlite)?;
+    }
+    Ok(())
+}
+
+/// The custom field schema, empty until one is set
+fn schema(conn: &Connection) -> Result<Schema> {
+    let json = conn
+        .query_row("SELECT value FROM meta WHERE key = 'schema'", [], |row| {
+            row.get::<_, String>(0)
+        })
+        .optional()
+        .map_err(Err::Sqlite)?;
+    match json {
+        Some(json) => Ok(serde_json::from_str(&json).map_err(Err::Json)?),
+        None => Ok(Schema::default()),
+    }
+}
+
+/// Every entry in the trash, in the order they were deleted
+fn load_trash(conn: &Connection) -> Result<Vec<Person>> {
+    let mut stmt = conn
+        .prepare_cached("SELECT person FROM trash ORDER BY deleted_at, id")
+        .map_err(Err::Sqlite)?;
+    let rows = stmt
+        .query_map([], |row| row.get::<_, String>(0))
+        .map_err(Err::Sqlite)?
+        .collect::<rusqlite::Result<Vec<String>>>()
+        .map_err(Err::Sqlite)?;
+    rows.iter()
+        .map(|json| serde_json::from_str(json).map_err(|e| Err::Json(e).into()))
+        .collect()
+}
+
+/// Put `person` into the trash, replacing an earlier deletion of the same id
+fn save_trash(tx: &Transaction, person: &Person) -> Result<()> {
+    tx.execute(
+        "INSERT OR REPLACE INTO trash (id, deleted_at, person) VALUES (?1, ?2, ?3)",
+        params![
+            to_sql_id(person.id)?,
+            person.deleted_at,
+            serde_json::to_string(person).map_err(Err::Json)?
+        ],
+    )
+    .map_err(Err::Sqlite)?;
+    Ok(())
+}
+
+fn delete_details(tx: &Transaction, id: PersonID) -> Result<()> {
+    for table in DETAIL_TABLES {
+        tx.execute(
+            &format!("DELETE FROM {table} WHERE person_id = ?1"),
+            params![to_sql_id(id)?],
+        )
+        .map_err(Err::Sqlite)?;
+    }
+    Ok(())
+}
+
+fn get_organization(conn: &Connection, id: OrganizationID) -> Result<Option<Organization>> {
+    let Ok(id) = to_sql_id(id) else {
+        return Ok(None);
+    };
+    conn.query_row(
+        &format!("SELECT {ORGANIZATION_COLUMNS} FROM organization WHERE id = ?1"),
+        params![id],
+        organization_from_row,
+    )
+    .optional()
+    .map_err(|e| Err::Sqlite(e).into())
+}
+
+/// The organization `id` or an `Err::NotFound`
+fn require_organization(conn: &Connection, id: OrganizationID) -> Result<Organization> {
+    get_organization(conn, id)?
+        .ok_or_else(|| Err::NotFound(format!("organization {id}")))
+        .with_context(|| format!("Organization {id} does not exist"))
+}
+
+/// The organization `person` references, if it exists
+fn organization_of(conn: &Connection, person: &Person) -> Result<Option<Organization>> {
+    match person.organization {
+        Some(id) => get_organization(conn, id),
+        None => Ok(None),
+    }
+}
+
+/// Store `organization` under its id, after the checks of `JsonFile::add_organization`
+fn save_organization(tx: &Transaction, organization: &mut Organization) -> Result<()> {
+    organization.normalize()?;
+    let id = to_sql_id(organization.id)?;
+    let taken: bool = tx
+        .query_row(
+            "SELECT COUNT(*) > 0 FROM organization WHERE name_key = ?1 AND id != ?2",
+            params![organization.key(), id],
+            |row| row.get(0),
+        )
+        .map_err(Err::Sqlite)?;
+    if taken {
+        return Err(Err::PhonebookEntry("Duplicate name".into())).with_context(|| {
+            format!(
+                "Organization {} already exists, names must be unique",
+                organization.name
+            )
+        });
+    }
+    let address = match &organization.address {
+        Some(address) => Some(serde_json::to_string(address).map_err(Err::Json)?),
+        None => None,
+    };
+    tx.execute(
+        "INSERT OR REPLACE INTO organization (id, name, name_key, main_number, address, departments)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            id,
+            organization.name,
+            organization.key(),
+            organization.main_number,
+            address,
+            serde_json::to_string(&organization.departments).map_err(Err::Json)?
+        ],
+    )
+    .map_err(Err::Sqlite)?;
+    Ok(())
+}
+
+/// See `JsonFile::refresh_members`
+fn refresh_members(tx: &Transaction, id: OrganizationID, at: DateTime<Utc>) -> Result<()> {
+    let organization = get_organization(tx, id)?;
+    for mut person in select(tx, "organization_id = ?1", params![to_sql_id(id)?])? {
+        if organization::forget_missing(&mut person, organization.as_ref()) {
+            let person_id = to_sql_id(person.id)?;
+            update_organization_of(tx, person_id, &person)?;
+            touch(tx, person_id, at)?;
+        }
+    }
+    for mut person in load_trash(tx)? {
+        if person.organization == Some(id) && organization::forget_missing(&mut person, organization.as_ref()) {
+            save_trash(tx, &person)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether the entry `id` is in the phonebook, the trash doesn't count
+fn exists(conn: &Connection, id: PersonID) -> Result<bool> {
+    // Out of range ids can't be in the table anyway
+    let Ok(id) = to_sql_id(id) else {
+        return Ok(false);
+    };
+    conn.query_row("SELECT COUNT(*) > 0 FROM person WHERE id = ?1", params![id], |row| {
+        row.get(0)
+    })
+    .map_err(|e| Err::Sqlite(e).into())
+}
+
+/// Drop the relationships pointing at `id`, see `JsonFile::unlink`
+fn unlink(tx: &Transaction, id: PersonID, at: DateTime<Utc>) -> Result<()> {
+    let id = to_sql_id(id)?;
+    let linked = {
+        let mut stmt = tx
+            .prepare_cached("SELECT DISTINCT person_id FROM relation WHERE related_id = ?1")
+            .map_err(Err::Sqlite)?;
+        let linked = stmt
+            .query_map(params![id], |row| row.get::<_, i64>(0))
+            .map_err(Err::Sqlite)?
+            .collect::<rusqlite::Result<Vec<i64>>>()
+            .map_err(Err::Sqlite)?;
+        linked
+    };
+    tx.execute("DELETE FROM relation WHERE related_id = ?1", params![id])
+        .map_err(Err::Sqlite)?;
+    for person_id in linked {
+        touch(tx, person_id, at)?;
+    }
+    Ok(())
+}
+
+fn get(conn: &Connection, id: PersonID) -> Result<Option<Person>> {
+    let person = conn
+        .query_row(
+            &format!("SELECT {PERSON_COLUMNS} FROM person WHERE id = ?1"),
+            params![to_sql_id(id)?],
+            person_from_row,
+        )
+        .optional()
+        .map_err(Err::Sqlite)?;
+    Ok(with_details(conn, person.into_iter().collect())?.pop())
+}
+
+/// Every entry matching the `WHERE` clause `filter`, sorted by id and with their details
+fn select(conn: &Connection, filter: &str, params: impl rusqlite::Params) -> Result<Vec<Person>> {
+    let mut stmt = conn
+        .prepare_cached(&format!(
+            "SELECT {PERSON_COLUMNS} FROM person WHERE {filter} ORDER BY id"
+        ))
+        .map_err(Err::Sqlite)?;
+    let persons = stmt
+        .query_map(params, person_from_row)
+        .map_err(Err::Sqlite)?
+        .collect::<rusqlite::Result<Vec<Person>>>()
+        .map_err(Err::Sqlite)?;
+    with_details(conn, persons)
+}
+
+/// The sqlite counterpart of `JsonFile::check_if_name_exists`
+fn find_by_name(conn: &Connection, name: &Name) -> Result<Option<Person>> {
+    let found = conn
+        .query_row(
+            &format!("SELECT {PERSON_COLUMNS} FROM person WHERE name_key = ?1 ORDER BY id LIMIT 1"),
+            params![name.key()],
+            person_from_row,
+        )
+        .optional()
+        .map_err(Err::Sqlite)?;
+    Ok(with_details(conn, found.into_iter().collect())?.pop())
+}
+
+impl PhonebookStore for SqliteStore {
+    fn get(&self, id: PersonID) -> Result<Option<Person>> {
+        get(&self.conn.lock(), id)
+    }
+    fn get_by_name(&self, name: &str) -> Result<Option<Person>> {
+        find_by_name(&self.conn.lock(), &Name::parse(name))
+    }
+    fn list(&self) -> Result<Vec<Person>> {
+        select(&self.conn.lock(), "1", [])
+    }
+    fn add(&self, mut p: Person) -> Result<PersonID> {
+        p.normalize()?;
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction().map_err(Err::Sqlite)?;
+        // Same rules as `JsonFile::add_to_phonebook`
+        if p.id != 0 && get(&tx, p.id)?.is_some() {
+            log::warn!("Person with id {} already exists in the phonebook", p.id);
+            return Err(Err::PhonebookEntry("Person ID already exists".into()))
+                .with_context(|| format!("Person with id {} already exists, please do not provide an id", p.id));
+        }
+        if find_by_name(&tx, &p.name)?.is_some() {
+            log::warn!("Name {} already exists in the phonebook. Names must be unique", &p.name);
+            return Err(Err::PhonebookEntry("Duplicate name".into()))
+                .with_context(|| format!("Person with name {} already exists, Names must be unique", p.name));
+        }
+        // Ids in the trash stay reserved, see `JsonFile::generate_id`
+        let max: i64 = tx
+            .query_row(
+                "SELECT COALESCE(MAX(id), 0) FROM (SELECT id FROM person UNION ALL SELECT id FROM trash)",
+                [],
+                |row| row.get(0),
+            )
+            .map_err(Err::Sqlite)?;
+        schema(&tx)?.check(&mut p)?;
+        relation::check(&p, |id| exists(&tx, id))?;
+        let organization = organization_of(&tx, &p)?;
+        organization::check(&mut p, organization.as_ref())?;
+        p.id = max as PersonID + 1;
+        p.stamp_created(Utc::now());
+        insert(&tx, &p)?;
+        tx.commit().map_err(Err::Sqlite)?;
+        Ok(p.id)
+    }
+    fn update(&self, id: PersonID, p: Person) -> Result<()> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction().map_err(Err::Sqlite)?;
+        let mut entry = get(&tx, id)?
+            .ok_or(Err::PhonebookEntry("id does not exist".into()))
+            .with_context(|| {
+                log::info!("id: {id} does not exist in the phonebook");
+                "id does not exist in phonebook"
+            })?;
+        let before = entry.clone();
+        entry.merge(p);
+        entry.normalize()?;
+        // Same rules as `JsonFile::update`
+        if find_by_name(&tx, &entry.name)?.is_some_and(|other| other.id != id) {
+            log::warn!(
+                "Name {} already exists in the phonebook. Names must be unique",
+                &entry.name
+            );
+            return Err(Err::PhonebookEntry("Duplicate name".into()))
+                .with_context(|| format!("Person with name {} already exists, Names must be unique", entry.name));
+        }
+        schema(&tx)?.check(&mut entry)?;
+        relation::check(&entry, |id| exists(&tx, id))?;
+        let organization = organization_of(&tx, &entry)?;
+        organization::check(&mut entry, organization.as_ref())?;
+        if entry == before {
+            return Ok(());
+        }
+        touch(&tx, to_sql_id(id)?, Utc::now())?;
+        update_name(&tx, to_sql_id(id)?, &entry.name)?;
+        update_organization_of(&tx, to_sql_id(id)?, &entry)?;
+        delete_details(&tx, id)?;
+        insert_details(&tx, &entry)?;
+        tx.commit().map_err(Err::Sqlite)?;
+        Ok(())
+    }
+    fn expand(&self, id: PersonID) -> Result<Option<Expanded>> {
+        let conn = self.conn.lock();
+        let Some(person) = get(&conn, id)? else {
+            return Ok(None);
+        };
+        relation::expand(person, |id| get(&conn, id)).map(Some)
+    }
+    fn delete(&self, id: PersonID) -> Result<()> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction().map_err(Err::Sqlite)?;
+        let Some(mut person) = get(&tx, id)? else {
+            log::info!("DELETE: id #{id} doesn't exist");
+            return Err(Err::NotFound(format!("id {id}")))
+                .with_context(|| format!("id {id} does not exist in phonebook"));
+        };
+        let at = Utc::now();
+        person.deleted_at = Some(at);
+        save_trash(&tx, &person)?;
+        delete_details(&tx, id)?;
+        tx.execute("DELETE FROM person WHERE id = ?1", params![to_sql_id(id)?])
+            .map_err(Err::Sqlite)?;
+        unlink(&tx, id, at)?;
+        tx.commit().map_err(Err::Sqlite)?;
+        Ok(())
+    }
+    fn trash(&self) -> Result<Vec<Person>> {
+        load_trash(&self.conn.lock())
+    }
+    fn restore(&self, id: PersonID) -> Result<()> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction().map_err(Err::Sqlite)?;
+        let json: String = tx
+            .query_row(
+                "SELECT person FROM trash WHERE id = ?1",
+                params![to_sql_id(id)?],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(Err::Sqlite)?
+            .ok_or_else(|| Err::NotFound(format!("id {id}")))
+            .with_context(|| format!("id {id} is not in the trash"))?;
+        let mut person: Person = serde_json::from_str(&json).map_err(Err::Json)?;
+        // Same rules as `JsonFile::restore`
+        if get(&tx, id)?.is_some() {
+            return Err(Err::PhonebookEntry("Person ID already exists".into()))
+                .with_context(|| format!("Can't restore id {id}, another entry has taken it"));
+        }
+        if find_by_name(&tx, &person.name)?.is_some() {
+            return Err(Err::PhonebookEntry("Duplicate name".into()))
+                .with_context(|| format!("Can't restore {}, an entry with that name was added since", person.name));
+        }
+        schema(&tx)?.check(&mut person)?;
+        let mut relations = Vec::with_capacity(person.relations.len());
+        for relation in person.relations.drain(..) {
+            if exists(&tx, relation.id)? {
+                relations.push(relation);
+            }
+        }
+        person.relations = relations;
+        let organization = organization_of(&tx, &person)?;
+        organization::forget_missing(&mut person, organization.as_ref());
+        person.deleted_at = None;
+        person.stamp_updated(Utc::now());
+        insert(&tx, &person)?;
+        tx.execute("DELETE FROM trash WHERE id = ?1", params![to_sql_id(id)?])
+            .map_err(Err::Sqlite)?;
+        tx.commit().map_err(Err::Sqlite)?;
+        Ok(())
+    }
+    fn purge(&self, before: DateTime<Utc>) -> Result<usize> {
+        // Timestamps are all stored in UTC and the same format, so they compare as text
+        let purged = self
+            .conn
+            .lock()
+            .execute("DELETE FROM trash WHERE deleted_at < ?1", params![before])
+            .map_err(Err::Sqlite)?;
+        Ok(purged)
+    }
+    fn schema(&self) -> Result<Schema> {
+        schema(&self.conn.lock())
+    }
+    fn set_schema(&self, new: Schema) -> Result<()> {
+        new.validate()?;
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction().map_err(Err::Sqlite)?;
+        // Same rules as `JsonFile::set_schema`
+        let at = Utc::now();
+        for mut person in select(&tx, "1", [])? {
+            let before = person.clone();
+            new.drop_unknown(&mut person);
+            new.check(&mut person)
+                .with_context(|| format!("Entry {} doesn't fit the new schema", person.id))?;
+            if person != before {
+                let id = to_sql_id(person.id)?;
+                tx.execute("DELETE FROM field WHERE person_id = ?1", params![id])
+                    .map_err(Err::Sqlite)?;
+                insert_fields(&tx, id, &person.fields)?;
+                touch(&tx, id, at)?;
+            }
+        }
+        for mut person in load_trash(&tx)? {
+            let count = person.fields.len();
+            new.drop_unknown(&mut person);
+            if person.fields.len() != count {
+                save_trash(&tx, &person)?;
+            }
+        }
+        tx.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES ('schema', ?1)",
+            params![serde_json::to_string(&new).map_err(Err::Json)?],
+        )
+        .map_err(Err::Sqlite)?;
+        tx.commit().map_err(Err::Sqlite)?;
+        Ok(())
+    }
+    fn tagged(&self, tag: &str) -> Result<Vec<Person>> {
+        let tag = tag::normalize(tag)?;
+        select(
+            &self.conn.lock(),
+            "id IN (SELECT person_id FROM tag WHERE tag = ?1)",
+            params![tag],
+        )
+    }
+    fn groups(&self) -> Result<Vec<Group>> {
+        let conn = self.conn.lock();
+        let mut stmt = conn
+            .prepare_cached("SELECT tag, COUNT(*) FROM tag GROUP BY tag ORDER BY tag")
+            .map_err(Err::Sqlite)?;
+        let groups = stmt
+            .query_map([], |row| {
+                Ok(Group {
+                    name: row.get(0)?,
+                    members: row.get::<_, i64>(1)? as usize,
+                })
+            })
+            .map_err(Err::Sqlite)?
+            .collect::<rusqlite::Result<Vec<Group>>>()
+            .map_err(Err::Sqlite)?;
+        Ok(groups)
+    }
+    fn tag(&self, tag: &str, ids: &[PersonID]) -> Result<usize> {
+        let tag = tag::normalize(tag)?;
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction().map_err(Err::Sqlite)?;
+        let (mut changed, at) = (0, Utc::now());
+        for &id in ids {
+            // Same rules as `JsonFile::tag`, dropping the transaction rolls back the ids tagged so far
+            if !exists(&tx, id)? {
+                return Err(Err::NotFound(format!("id {id}")))
+                    .with_context(|| format!("Can't tag id {id} as {tag}, it does not exist in the phonebook"));
+            }
+            let inserted = tx
+                .execute(
+                    "INSERT OR IGNORE INTO tag (person_id, tag) VALUES (?1, ?2)",
+                    params![to_sql_id(id)?, tag],
+                )
+                .map_err(Err::Sqlite)?;
+            if inserted > 0 {
+                touch(&tx, to_sql_id(id)?, at)?;
+                changed += 1;
+            }
+        }
+        tx.commit().map_err(Err::Sqlite)?;
+        Ok(changed)
+    }
+    fn untag(&self, tag: &str, ids: &[PersonID]) -> Result<usize> {
+        let tag = tag::normalize(tag)?;
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction().map_err(Err::Sqlite)?;
+        let (mut changed, at) = (0, Utc::now());
+        for &id in ids {
+            // Out of range ids can't be in the table anyway
+            let Ok(id) = to_sql_id(id) else { continue };
+            let deleted = tx
+                .execute("DELETE FROM tag WHERE person_id = ?1 AND tag = ?2", params![id, tag])
+                .map_err(Err::Sqlite)?;
+            if deleted > 0 {
+                touch(&tx, id, at)?;
+                changed += 1;
+            }
+        }
+        tx.commit().map_err(Err::Sqlite)?;
+        Ok(changed)
+    }
+    fn organizations(&self) -> Result<Vec<Organization>> {
+        let conn = self.conn.lock();
+        let mut stmt = conn
+            .prepare_cached(&format!("SELECT {ORGANIZATION_COLUMNS} FROM organization ORDER BY id"))
+            .map_err(Err::Sqlite)?;
+        let organizations = stmt
+            .query_map([], organization_from_row)
+            .map_err(Err::Sqlite)?
+            .collect::<rusqlite::Result<Vec<Organization>>>()
+            .map_err(Err::Sqlite)?;
+        Ok(organizations)
+    }
+    fn organization(&self, id: OrganizationID) -> Result<Option<Organization>> {
+        get_organization(&self.conn.lock(), id)
+    }
+    fn add_organization(&self, mut organization: Organization) -> Result<OrganizationID> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction().map_err(Err::Sqlite)?;
+        let max: i64 = tx
+            .query_row("SELECT COALESCE(MAX(id), 0) FROM organization", [], |row| row.get(0))
+            .map_err(Err::Sqlite)?;
+        organization.id = max as OrganizationID + 1;
+        save_organization(&tx, &mut organization)?;
+        tx.commit().map_err(Err::Sqlite)?;
+        Ok(organization.id)
+    }
+    fn update_organization(&self, id: OrganizationID, mut organization: Organization) -> Result<()> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction().map_err(Err::Sqlite)?;
+        require_organization(&tx, id)?;
+        organization.id = id;
+        save_organization(&tx, &mut organization)?;
+        refresh_members(&tx, id, Utc::now())?;
+        tx.commit().map_err(Err::Sqlite)?;
+        Ok(())
+    }
+    fn delete_organization(&self, id: OrganizationID) -> Result<()> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction().map_err(Err::Sqlite)?;
+        require_organization(&tx, id)?;
+        tx.execute("DELETE FROM organization WHERE id = ?1", params![to_sql_id(id)?])
+            .map_err(Err::Sqlite)?;
+        refresh_members(&tx, id, Utc::now())?;
+        tx.commit().map_err(Err::Sqlite)?;
+        Ok(())
+    }
+    fn members(&self, id: OrganizationID) -> Result<Vec<Person>> {
+        let conn = self.conn.lock();
+        require_organization(&conn, id)?;
+        select(&conn, "organization_id = ?1", params![to_sql_id(id)?])
+    }
+    fn move_to(
+        &self,
+        ids: &[PersonID],
+        organization: Option<OrganizationID>,
+        department: Option<&str>,
+    ) -> Result<usize> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction().map_err(Err::Sqlite)?;
+        // Same rules as `JsonFile::move_to`
+        for &id in ids {
+            if !exists(&tx, id)? {
+                return Err(Err::NotFound(format!("id {id}")))
+                    .with_context(|| format!("Can't move id {id}, it does not exist in the phonebook"));
+            }
+        }
+        let mut target = Person {
+            organization,
+            department: department.map(Into::into),
+            ..Default::default()
+        };
+        let organization = organization_of(&tx, &target)?;
+        organization::check(&mut target, organization.as_ref())?;
+        let (mut changed, at) = (0, Utc::now());
+        for &id in ids {
+            let person = get(&tx, id)?.expect("Checked right above");
+            if (person.organization, &person.department) != (target.organization, &target.department) {
+                update_organization_of(&tx, to_sql_id(id)?, &target)?;
+                touch(&tx, to_sql_id(id)?, at)?;
+                changed += 1;
+            }
+        }
+        tx.commit().map_err(Err::Sqlite)?;
+        Ok(changed)
+    }
+    fn replace_all(&self, json_file: JsonFile) -> Result<()> {
+        json_file.validate()?;
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction().map_err(Err::Sqlite)?;
+        for table in DETAIL_TABLES.iter().chain(&["person", "organization", "trash"]) {
+            tx.execute(&format!("DELETE FROM {table}"), []).map_err(Err::Sqlite)?;
+        }
+        tx.execute("DELETE FROM meta WHERE key = 'schema'", [])
+            .map_err(Err::Sqlite)?;
+        insert_all(&tx, &json_file)?;
+        tx.commit().map_err(Err::Sqlite)?;
+        Ok(())
+    }
+    fn flush(&self) -> Result<()> {
+        // Fold the WAL back into the main database file
+        self.conn
+            .lock()
+            .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+            .map_err(Err::Sqlite)?;
+        Ok(())
+    }
+    fn close(&self) -> Result<()> {
+        self.flush()?;
+        let mut conn = self.conn.lock();
+        // Stands in until the store is dropped, so the file, its `-wal` and its `-shm` are released right away
+        let empty = Connection::open_in_memory().map_err(Err::Sqlite)?;
+        std::mem::replace(&mut *conn, empty)
+            .close()
+            .map_err(|(_, e)| Err::Sqlite(e))
+            .with_context(|| "Failed to close the sqlite database")?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_sqlite_imports_json_once() -> Result<()> {
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    let (json_path, db_path) = (dir.join("book.json"), dir.join("book.db"));
+    let mut json_file = crate::JsonFile::default();
+    json_file.add_to_phonebook(person!("Ada Lovelace", "39-44-5323523"))?;
+    json_file.add_to_phonebook(person!("Dan Abramov", "12-43-234345"))?;
+    // Everything else the book holds comes along too
+    json_file.set_schema(serde_json::from_str(
+        r#"{"fields": [{"name": "pager", "type": "string"}]}"#,
+    )?)?;
+    let acme = json_file.add_organization(Organization {
+        name: "ACME Corp".into(),
+        ..Default::default()
+    })?;
+    json_file.move_to(&[2], Some(acme), None)?;
+    let mary = json_file.add_to_phonebook(person!("Mary Smith", "3"))?;
+    json_file.delete(mary)?;
+    crate::write_json(&json_path, &json_file)?;
+
+    let store = SqliteStore::open(&db_path, Some(&json_path))?;
+    assert_eq!(json_file.persons(), store.list()?.as_slice());
+    assert_eq!(json_file.trash(), store.trash()?.as_slice());
+    assert_eq!(json_file.schema(), &store.schema()?);
+    assert_eq!(json_file.organizations(), store.organizations()?.as_slice());
+    store.delete(1)?;
+    drop(store);
+    // Reopening must not resurrect the deleted entry
+    let store = SqliteStore::open(&db_path, Some(&json_path))?;
+    assert_eq!(1, store.list()?.len());
+    assert_eq!(4, store.add(person!("Harry Potter", "4413"))?);
+
+    // Databases from before `phone` existed get their numbers moved over
+    let old_path = dir.join("old.db");
+    Connection::open(&old_path)?.execute_batch(
+        "CREATE TABLE person (id INTEGER PRIMARY KEY, name TEXT NOT NULL, name_key TEXT NOT NULL, number TEXT NOT NULL);
+         INSERT INTO person VALUES (7, 'Ada Lovelace', 'ada', '39-44-5323523');",
+    )?;
+    let store = SqliteStore::open(&old_path, None)?;
+    assert_eq!(
+        Some(person!("Ada Lovelace", "39-44-5323523")),
+        store.get(7)?.map(|p| Person { id: 0, ..p })
+    );
+    Ok(())
+}
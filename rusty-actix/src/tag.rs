@@ -0,0 +1,89 @@
+//! Tags such as `vendor`, `on-call` or `family` and the groups they make up.
+//! A group is simply every entry carrying the same tag, so groups need no storage of their own and can't
+//! drift out of sync with the entries: adding members tags them, removing members untags them and a group
+//! whose last member left is gone.
+use crate::{Err, Person};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Longest tag accepted, in characters
+pub const MAX_TAG_LEN: usize = 64;
+
+/// A group as listed by `GET /groups`
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Group {
+    pub name: String,
+    /// Number of entries carrying the tag
+    pub members: usize,
+}
+
+/// The canonical form of `tag`: trimmed, lowercased and with inner whitespace turned into `-`,
+/// so `On Call` and `on-call` are the same tag. Only letters, digits, `-`, `_` and `.` are allowed.
+pub fn normalize(tag: &str) -> Result<String> {
+    let normalized = tag.split_whitespace().collect::<Vec<_>>().join("-").to_lowercase();
+    let valid = !normalized.is_empty()
+        && normalized.chars().count() <= MAX_TAG_LEN
+        && normalized
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if valid {
+        return Ok(normalized);
+    }
+    Err(Err::PhonebookEntry("Invalid tag".into()))
+        .with_context(|| format!("`{tag}` is not a tag, use up to {MAX_TAG_LEN} letters, digits, `-`, `_` or `.`"))
+}
+
+/// Every tag used by `persons` along with how many of them carry it, sorted by name
+pub fn groups<'a>(persons: impl IntoIterator<Item = &'a Person>) -> Vec<Group> {
+    let mut counts = BTreeMap::<&str, usize>::new();
+    for tag in persons.into_iter().flat_map(|p| &p.tags) {
+        *counts.entry(tag).or_default() += 1;
+    }
+    counts
+        .into_iter()
+        .map(|(name, members)| Group {
+            name: name.to_owned(),
+            members,
+        })
+        .collect()
+}
+
+#[test]
+fn test_tags_and_groups() -> Result<()> {
+    assert_eq!("on-call", normalize("  On  Call ")?);
+    assert_eq!("team_a.v2", normalize("team_a.v2")?);
+    for bad in ["", "   ", "on/call", "#vendor", &"x".repeat(MAX_TAG_LEN + 1)] {
+        assert!(normalize(bad).is_err(), "{bad} passed");
+    }
+
+    let mut book = crate::JsonFile::default();
+    let ada = book.add_to_phonebook(person!("Ada Lovelace", "39-44-5323523"))?;
+    let dan = book.add_to_phonebook(person!("Dan Abramov", "12-43-234345"))?;
+    assert_eq!(2, book.tag("On Call", &[ada, dan])?);
+    // Tagging twice changes nothing, an unknown id fails the whole call
+    assert_eq!(0, book.tag("on-call", &[ada])?);
+    assert!(book.tag("vendor", &[ada, 99]).is_err());
+    assert!(book.tagged("vendor")?.is_empty());
+    book.tag("vendor", &[dan])?;
+    assert_eq!(vec!["on-call", "vendor"], book.get_by_id(dan).unwrap().tags);
+    assert_eq!(
+        vec![
+            Group {
+                name: "on-call".into(),
+                members: 2
+            },
+            Group {
+                name: "vendor".into(),
+                members: 1
+            }
+        ],
+        groups(book.persons())
+    );
+    assert_eq!(1, book.untag("on-call", &[dan, 99])?);
+    assert_eq!(
+        vec![ada],
+        book.tagged("ON CALL")?.iter().map(|p| p.id).collect::<Vec<_>>()
+    );
+    Ok(())
+}
@@ -0,0 +1,77 @@
+//! Hot reload of the data file when it gets edited outside of the server.
+//! The parent directory is watched rather than the file itself since every save, ours included,
+//! replaces the file through a rename. Our own saves are recognized and ignored by `JsonFileStore::reload`.
+//! The watcher only holds a weak reference to the store and stops once the store is dropped, e.g. when its book is deleted.
+use crate::store::{JsonFileStore, Reload};
+use crate::Err;
+use anyhow::{Context, Result};
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::path::Path;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Editors tend to produce a burst of events for a single save, wait this long for the burst to settle
+const SETTLE: Duration = Duration::from_millis(200);
+
+/// Watch the data file of `store` on a background thread and reload it whenever it changes on disk
+pub fn watch(store: &Arc<JsonFileStore>) -> Result<std::thread::JoinHandle<()>> {
+    let path = store.path().to_path_buf();
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => Path::new(".").to_path_buf(),
+    };
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)
+        .map_err(|e| Err::Io(std::io::Error::other(e)))
+        .with_context(|| "Failed to create a file watcher")?;
+    watcher
+        .watch(&dir, RecursiveMode::NonRecursive)
+        .map_err(|e| Err::Io(std::io::Error::other(e)))
+        .with_context(|| format!("Failed to watch `{}`", dir.display()))?;
+    log::info!("Watching `{}` for external edits", path.display());
+
+    let store = Arc::downgrade(store);
+    let file_name = path.file_name().map(ToOwned::to_owned);
+    let touches_file = move |event: &notify::Result<notify::Event>| match event {
+        Ok(event) => {
+            matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+                && event.paths.iter().any(|p| p.file_name() == file_name.as_deref())
+        }
+        Err(e) => {
+            log::warn!("File watcher error: {e}");
+            false
+        }
+    };
+    let handle = std::thread::Builder::new()
+        .name("phonebook-watcher".into())
+        .spawn(move || {
+            // The watcher stops as soon as it is dropped, so this thread owns it
+            let _watcher = watcher;
+            while let Ok(event) = rx.recv() {
+                if !touches_file(&event) {
+                    continue;
+                }
+                std::thread::sleep(SETTLE);
+                while rx.try_recv().is_ok() {}
+                let Some(store) = store.upgrade() else {
+                    log::debug!("Stopped watching `{}`", path.display());
+                    return;
+                };
+                match store.reload() {
+                    Ok(Reload::Unchanged) => {}
+                    Ok(Reload::Reloaded) => log::info!("Picked up external changes to `{}`", path.display()),
+                    Ok(Reload::Conflict(aside)) => {
+                        log::error!("Conflicting external edit of `{}` kept at `{}`", path.display(), aside.display())
+                    }
+                    Ok(Reload::Rejected(aside)) => {
+                        log::error!("Rejected change to `{}` kept at `{}`", path.display(), aside.display())
+                    }
+                    Err(e) => log::error!("Failed to reload `{}`: {e:?}", path.display()),
+                }
+            }
+        })
+        .map_err(Err::Io)
+        .with_context(|| "Failed to spawn the file watcher thread")?;
+    Ok(handle)
+}
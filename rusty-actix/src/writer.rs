@@ -0,0 +1,132 @@
+//! A background task that coalesces saves.
+//! Mutations only mark the store dirty, the writer waits until no new change arrived for `window`
+//! and then flushes the store once for the whole burst. A bulk import firing hundreds of POSTs
+//! in a row thus results in a single rewrite of the data file instead of hundreds.
+use crate::store::PhonebookStore;
+use anyhow::{anyhow, Result};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+/// Debounce window used when nothing else is configured
+pub const DEFAULT_WRITE_WINDOW: Duration = Duration::from_millis(500);
+
+enum Msg {
+    Dirty,
+    Flush(oneshot::Sender<Result<()>>),
+}
+
+/// Handle to the background writer, cheap to share between handlers
+#[derive(Debug, Clone)]
+pub struct Writer {
+    tx: mpsc::UnboundedSender<Msg>,
+}
+
+impl Writer {
+    /// Spawn the writer on the current tokio runtime.
+    /// A burst is flushed once it has been quiet for `window`, or at the latest after `10 * window`
+    /// so that a steady stream of changes still reaches the disk.
+    pub fn spawn(store: Arc<dyn PhonebookStore>, window: Duration) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(run(store, window, rx));
+        Self { tx }
+    }
+
+    /// Note that the store has unsaved changes
+    pub fn mark_dirty(&self) {
+        if self.tx.send(Msg::Dirty).is_err() {
+            log::error!("Background writer is gone, changes stay unsaved until the next flush");
+        }
+    }
+
+    /// Flush right away, without waiting for the debounce window, e.g. on shutdown
+    pub async fn flush(&self) -> Result<()> {
+        let (done, wait) = oneshot::channel();
+        self.tx
+            .send(Msg::Flush(done))
+            .map_err(|_| anyhow!("Background writer is gone"))?;
+        wait.await.map_err(|_| anyhow!("Background writer dropped the flush request"))?
+    }
+}
+
+async fn run(store: Arc<dyn PhonebookStore>, window: Duration, mut rx: mpsc::UnboundedReceiver<Msg>) {
+    let mut dirty_since: Option<Instant> = None;
+    loop {
+        let msg = match dirty_since {
+            None => rx.recv().await,
+            Some(since) => {
+                let deadline = (Instant::now() + window).min(since + window * 10);
+                match tokio::time::timeout_at(deadline, rx.recv()).await {
+                    Ok(msg) => msg,
+                    // The burst is over
+                    Err(_elapsed) => {
+                        if let Err(e) = flush(&store).await {
+                            log::error!("Background save failed: {e:?}");
+                        }
+                        dirty_since = None;
+                        continue;
+                    }
+                }
+            }
+        };
+        match msg {
+            Some(Msg::Dirty) => {
+                dirty_since.get_or_insert_with(Instant::now);
+            }
+            Some(Msg::Flush(done)) => {
+                let _ = done.send(flush(&store).await);
+                dirty_since = None;
+            }
+            // Every handle is gone, save whatever is left and stop
+            None => {
+                if dirty_since.is_some() {
+                    if let Err(e) = flush(&store).await {
+                        log::error!("Final background save failed: {e:?}");
+                    }
+                }
+                return;
+            }
+        }
+    }
+}
+
+async fn flush(store: &Arc<dyn PhonebookStore>) -> Result<()> {
+    let store = Arc::clone(store);
+    tokio::task::spawn_blocking(move || store.flush()).await?
+}
+
+// The clock only moves when every task is idle, so the test can't outrun the window or the other way round
+#[tokio::test(start_paused = true)]
+async fn test_writer_coalesces_bursts() -> Result<()> {
+    use crate::{JsonFile, JsonFileStore, Person};
+    let tmp = tempfile::tempdir()?;
+    let dir = tmp.path();
+    let path = dir.join("book.json");
+    crate::write_json(&path, &JsonFile::default())?;
+    let store: Arc<dyn PhonebookStore> = Arc::new(JsonFileStore::open(&path, None, false)?);
+    let window = Duration::from_millis(50);
+    let writer = Writer::spawn(Arc::clone(&store), window);
+
+    let start = Instant::now();
+    for i in 0..20 {
+        store.add(person!(format!("Person {i}"), "4413"))?;
+        writer.mark_dirty();
+    }
+    // Still inside the window, nothing was written yet
+    tokio::time::sleep(window / 2).await;
+    assert!(crate::read_json(&path)?.persons().is_empty());
+    // The save itself runs on a blocking thread, outside of the paused clock
+    while crate::read_json(&path)?.persons().is_empty() {
+        assert!(start.elapsed() < window * 1000, "the burst was never saved");
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    assert!(start.elapsed() >= window);
+    assert_eq!(20, crate::read_json(&path)?.persons().len());
+
+    store.add(person!("Ada Lovelace", "39-44-5323523"))?;
+    writer.mark_dirty();
+    writer.flush().await?;
+    assert_eq!(21, crate::read_json(&path)?.persons().len());
+    Ok(())
+}